@@ -19,7 +19,7 @@ pub fn start_cli() -> (String, usize) {
 
 /// Creates the required CLI parser.
 fn create_matchers() -> ArgMatches<'static> {
-    return App::new("Penetrator")
+    App::new("Penetrator")
         .version("0.1.0")
         .author("Jörg S, Julian Z")
         .about("A simple website penetrator / performance tester")
@@ -27,7 +27,7 @@ fn create_matchers() -> ArgMatches<'static> {
             .required(true)
             .long("url")
             .value_name("URL")
-            .validator(|value| valid_url(value))
+            .validator(valid_url)
             .help("The url the penetrator will send requests to")
             .takes_value(true))
         .arg(Arg::with_name("num")
@@ -35,10 +35,10 @@ fn create_matchers() -> ArgMatches<'static> {
             .long("number_of_requests")
             .default_value("10")
             .value_name("NUM")
-            .validator(|value| valid_num(value))
+            .validator(valid_num)
             .help("The number of requests to be sent")
             .takes_value(true))
-        .get_matches();
+        .get_matches()
 }
 
 
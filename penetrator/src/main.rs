@@ -45,7 +45,7 @@ fn generate_result(request_results: Vec<Result<(u16, u128), reqwest::Error>>, du
         .into_iter()
         .filter(|r| {
             let res = r.as_ref().unwrap_or(&(0, 0)).0;
-            r.is_ok() && 200 <= res && 300 > res
+            r.is_ok() && (200..300).contains(&res)
         })
         .map(|r| r.unwrap_or((0, 0)))
         .collect();
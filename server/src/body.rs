@@ -0,0 +1,380 @@
+//! Module containing the response body, which refers to the served files instead of copying them
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, ErrorKind, IoSlice, Write};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use crate::connection::Connection;
+
+/// The most slices written with a single call of writev
+const MAX_SLICES: usize = 64;
+
+/// The body of a response
+///
+/// Files loaded into memory are shared with every response sending them, files on disk are sent
+/// by the kernel via sendfile. So sending a file neither copies nor reads it into the response.
+#[derive(Debug, Clone)]
+pub enum Body {
+    /// bytes belonging to this response only, e.g. a generated page
+    Owned(Vec<u8>),
+    /// a part of bytes shared with the loaded files
+    Shared(Arc<[u8]>, Range<usize>),
+    /// a part of a file on disk
+    File(Arc<File>, Range<u64>),
+    /// bodies sent one after another, e.g. the parts of multipart/byteranges
+    Parts(Vec<Body>),
+    /// chunks produced while the response is sent, e.g. a large generated export
+    Stream(Chunks),
+}
+
+/// The producer of a streamed body, whose length is unknown until the last chunk was produced
+///
+/// Clones share the producer, so every chunk is sent only once.
+#[derive(Clone)]
+pub struct Chunks {
+    source: Arc<Mutex<ChunkSource>>,
+}
+
+struct ChunkSource {
+    chunks: Box<dyn Iterator<Item = Vec<u8>> + Send>,
+    /// whether each chunk is framed with its size, as in the chunked transfer coding
+    chunked: bool,
+    finished: bool,
+}
+
+impl Chunks {
+    /// Streams the chunks, framed with the chunked transfer coding or sent as they are until the connection closes
+    pub fn new(chunks: impl Iterator<Item = Vec<u8>> + Send + 'static, chunked: bool) -> Self {
+        let source = ChunkSource { chunks: Box::new(chunks), chunked, finished: false };
+        Self { source: Arc::new(Mutex::new(source)) }
+    }
+
+    /// Produces the next chunk as it is sent, the last chunk of the chunked transfer coding ends the body
+    fn next_framed(&self) -> Option<Vec<u8>> {
+        let mut source = match self.source.lock() {
+            Ok(source) => source,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        if source.finished {
+            return None;
+        }
+        // an empty chunk would mark the end of the body, so empty chunks are left out
+        match source.chunks.find(|chunk| !chunk.is_empty()) {
+            Some(chunk) if source.chunked => {
+                let mut framed = format!("{:x}\r\n", chunk.len()).into_bytes();
+                framed.extend_from_slice(&chunk);
+                framed.extend_from_slice(b"\r\n");
+                Some(framed)
+            }
+            Some(chunk) => Some(chunk),
+            None => {
+                source.finished = true;
+                if source.chunked { Some(b"0\r\n\r\n".to_vec()) } else { None }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Chunks {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("Chunks")
+    }
+}
+
+/// A piece of the response, which is either in memory or in a file
+enum Segment<'a> {
+    Bytes(&'a [u8]),
+    File(&'a File, Range<u64>),
+}
+
+impl Body {
+    /// Creates a body sharing the bytes with all its clones
+    pub fn shared(bytes: Vec<u8>) -> Self {
+        let length = bytes.len();
+        Body::Shared(Arc::from(bytes), 0..length)
+    }
+
+    /// Creates a body sending the first length bytes of the file
+    pub fn file(file: File, length: u64) -> Self {
+        Body::File(Arc::new(file), 0..length)
+    }
+
+    /// The length of the body in bytes, streams are not counted as their length is unknown
+    pub fn len(&self) -> usize {
+        match self {
+            Body::Owned(bytes) => bytes.len(),
+            Body::Shared(_, range) => range.len(),
+            Body::File(_, range) => (range.end - range.start) as usize,
+            Body::Parts(parts) => parts.iter().map(Body::len).sum(),
+            Body::Stream(_) => 0,
+        }
+    }
+
+    /// Whether the body is produced while it is sent, so its length is unknown upfront
+    pub fn is_stream(&self) -> bool {
+        matches!(self, Body::Stream(_))
+    }
+
+    /// Produces the next chunk of a streamed body, framed as it is sent
+    ///
+    /// Streams are sent after the head, all other bodies have no chunks.
+    pub fn next_chunk(&self) -> Option<Vec<u8>> {
+        match self {
+            Body::Stream(chunks) => chunks.next_framed(),
+            _ => None
+        }
+    }
+
+    /// The bytes of the body, unless a part of it is still on disk
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Owned(bytes) => Some(bytes),
+            Body::Shared(bytes, range) => Some(&bytes[range.clone()]),
+            Body::File(..) | Body::Parts(_) | Body::Stream(_) => None,
+        }
+    }
+
+    /// Copies the bytes of the body into a vector, reading the parts on disk
+    ///
+    /// Streamed bodies are produced completely.
+    pub fn to_vec(&self) -> io::Result<Vec<u8>> {
+        use std::os::unix::fs::FileExt;
+        let mut content = Vec::with_capacity(self.len());
+        for segment in self.segments() {
+            match segment {
+                Segment::Bytes(bytes) => content.extend_from_slice(bytes),
+                Segment::File(file, range) => {
+                    let start = content.len();
+                    content.resize(start + (range.end - range.start) as usize, 0);
+                    file.read_exact_at(&mut content[start..], range.start)?;
+                }
+            }
+        }
+        while let Some(chunk) = self.next_chunk() {
+            content.extend_from_slice(&chunk);
+        }
+        Ok(content)
+    }
+
+    /// The part of the body within the range, referring to the same bytes or file
+    pub fn slice(&self, range: Range<usize>) -> Body {
+        match self {
+            Body::Owned(bytes) => Body::Owned(bytes[range].to_vec()),
+            Body::Shared(bytes, shared) => Body::Shared(Arc::clone(bytes), shared.start + range.start..shared.start + range.end),
+            Body::File(file, part) => Body::File(Arc::clone(file), part.start + range.start as u64..part.start + range.end as u64),
+            Body::Parts(parts) => {
+                let mut start = 0;
+                let mut sliced = Vec::new();
+                for part in parts {
+                    let end = start + part.len();
+                    if start < range.end && range.start < end {
+                        sliced.push(part.slice(range.start.max(start) - start..range.end.min(end) - start));
+                    }
+                    start = end;
+                }
+                Body::Parts(sliced)
+            }
+            Body::Stream(_) => self.clone(),
+        }
+    }
+
+    /// The pieces of the body in the order they are sent
+    fn segments(&self) -> Vec<Segment<'_>> {
+        match self {
+            Body::File(file, range) => vec![Segment::File(file, range.clone())],
+            Body::Parts(parts) => parts.iter().flat_map(Body::segments).collect(),
+            Body::Stream(_) => Vec::new(),
+            body => vec![Segment::Bytes(body.bytes().unwrap_or_default())],
+        }
+    }
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::Owned(Vec::new())
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Owned(bytes)
+    }
+}
+
+/// Bodies are equal if they refer to the same bytes, parts of files only equal the same parts of the same file
+impl PartialEq for Body {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Body::File(file, range), Body::File(other_file, other_range)) => Arc::ptr_eq(file, other_file) && range == other_range,
+            (Body::Parts(parts), Body::Parts(other_parts)) => parts == other_parts,
+            (Body::Stream(chunks), Body::Stream(other_chunks)) => Arc::ptr_eq(&chunks.source, &other_chunks.source),
+            _ => self.bytes().is_some() && self.bytes() == other.bytes()
+        }
+    }
+}
+
+impl Eq for Body {}
+
+/// Writes the head followed by the body completely into the blocking stream
+///
+/// Streamed bodies are produced chunk by chunk while they are written.
+pub fn write_all(stream: &mut Connection, head: &[u8], body: &Body) -> io::Result<()> {
+    let length = head.len() + body.len();
+    let mut written = 0;
+    while written < length {
+        match write_from(stream, head, body, written) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(bytes_written) => written += bytes_written,
+            Err(error) if error.kind() == ErrorKind::Interrupted => {}
+            Err(error) => return Err(error)
+        }
+    }
+    while let Some(chunk) = body.next_chunk() {
+        stream.write_all(&chunk)?;
+    }
+    stream.flush()
+}
+
+/// Writes as much of the head followed by the body as the stream accepts, starting at the offset
+///
+/// The bytes in memory are gathered into a single writev, the parts of files are sent with sendfile.
+/// Encrypted connections can not use sendfile, the parts of files are copied through them instead.
+/// Returns how many bytes were written, so nonblocking streams can continue at the new offset.
+pub fn write_from(stream: &mut Connection, head: &[u8], body: &Body, offset: usize) -> io::Result<usize> {
+    let mut skipped = 0;
+    let mut slices = Vec::new();
+    for segment in std::iter::once(Segment::Bytes(head)).chain(body.segments()) {
+        let length = match &segment {
+            Segment::Bytes(bytes) => bytes.len(),
+            Segment::File(_, range) => (range.end - range.start) as usize,
+        };
+        if skipped + length <= offset {
+            skipped += length;
+            continue;
+        }
+        let start = offset.saturating_sub(skipped);
+        match segment {
+            Segment::Bytes(bytes) => slices.push(IoSlice::new(&bytes[start..])),
+            Segment::File(file, range) if slices.is_empty() => {
+                return send_file(stream, file, range.start + start as u64, length - start);
+            }
+            Segment::File(..) => break
+        }
+        skipped += length;
+        if slices.len() == MAX_SLICES {
+            break;
+        }
+    }
+    stream.write_vectored(&slices)
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn sendfile(
+        out_fd: i32, // the socket to write to
+        in_fd: i32, // the file to read from
+        offset: *mut i64, // the position in the file, advanced by the bytes sent
+        count: usize, // the most bytes to send
+    ) -> isize;
+}
+
+/// Sends the part of the file into the stream without copying it into user space
+#[cfg(target_os = "linux")]
+fn send_file(stream: &mut Connection, file: &File, offset: u64, length: usize) -> io::Result<usize> {
+    use std::os::unix::io::AsRawFd;
+    if let Connection::Tls(_) = stream {
+        return copy_file(stream, file, offset, length);
+    }
+    let mut offset = offset as i64;
+    let sent = unsafe { sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut offset, length) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+/// Sends the part of the file into the stream, reading it in chunks where sendfile differs
+#[cfg(not(target_os = "linux"))]
+fn send_file(stream: &mut Connection, file: &File, offset: u64, length: usize) -> io::Result<usize> {
+    copy_file(stream, file, offset, length)
+}
+
+/// Reads the next chunk of the part of the file and writes it into the stream
+fn copy_file(stream: &mut Connection, file: &File, offset: u64, length: usize) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    let mut chunk = vec![0; length.min(64 * 1024)];
+    let read = file.read_at(&mut chunk, offset)?;
+    if read == 0 {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    stream.write(&chunk[..read])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+
+    fn temp_file(name: &str, content: &[u8]) -> File {
+        let path = std::env::temp_dir().join(format!("webserver_body_test_{}_{}", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        let file = File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file
+    }
+
+    #[test]
+    fn slice_test() {
+        let shared = Body::shared(b"Hello World".to_vec());
+        assert_eq!(shared.slice(6..11).bytes(), Some(&b"World"[..]));
+        assert_eq!(shared.slice(6..11).slice(1..3).bytes(), Some(&b"or"[..]));
+        let parts = Body::Parts(vec![Body::from(b"ab".to_vec()), shared.slice(0..5), Body::from(b"cd".to_vec())]);
+        assert_eq!(parts.len(), 9);
+        assert_eq!(parts.slice(1..8).to_vec().unwrap(), b"bHelloc");
+    }
+
+    #[test]
+    fn file_test() {
+        let file = Body::file(temp_file("file", b"Hello World"), 11);
+        assert_eq!(file.len(), 11);
+        assert_eq!(file.bytes(), None);
+        assert_eq!(file.slice(6..11).to_vec().unwrap(), b"World");
+        assert_eq!(file.slice(0..5), file.slice(0..5));
+        assert_ne!(file.slice(0..5), Body::from(b"Hello".to_vec()));
+    }
+
+    #[test]
+    fn stream_test() {
+        let chunks = vec![b"Hello".to_vec(), Vec::new(), b" World".to_vec()];
+        let chunked = Body::Stream(Chunks::new(chunks.clone().into_iter(), true));
+        assert!(chunked.is_stream());
+        assert_eq!(chunked.len(), 0);
+        assert_eq!(chunked.clone().to_vec().unwrap(), b"5\r\nHello\r\n6\r\n World\r\n0\r\n\r\n");
+        assert_eq!(chunked.next_chunk(), None);
+
+        let plain = Body::Stream(Chunks::new(chunks.into_iter(), false));
+        assert_eq!(plain.to_vec().unwrap(), b"Hello World");
+    }
+
+    #[test]
+    fn write_all_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = Connection::Plain(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        let (mut received, _) = listener.accept().unwrap();
+
+        let content = b"0123456789".repeat(10_000);
+        let body = Body::Parts(vec![
+            Body::shared(b"Hello ".to_vec()),
+            Body::file(temp_file("write", &content), content.len() as u64).slice(5..100_000),
+            Body::from(b" World".to_vec()),
+        ]);
+        let sender = std::thread::spawn(move || write_all(&mut stream, b"head\r\n", &body));
+
+        let mut data = Vec::new();
+        received.read_to_end(&mut data).unwrap();
+        sender.join().unwrap().unwrap();
+        assert_eq!(data, [&b"head\r\nHello "[..], &content[5..], b" World"].concat());
+    }
+}
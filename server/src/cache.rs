@@ -0,0 +1,104 @@
+//! Module caching values up to a total size, evicting the least recently used ones
+
+use std::collections::{BTreeMap, HashMap};
+
+/// A cache bounded by the summed up size of its values
+///
+/// Each access stamps the entry with a counter, the entry with the lowest stamp is evicted first.
+#[derive(Debug)]
+pub struct LruCache<V> {
+    capacity: usize,
+    used: usize,
+    stamp: u64,
+    /// the value, its size and the stamp of its last access
+    entries: HashMap<String, (V, usize, u64)>,
+    /// the keys ordered by the stamp of their last access
+    order: BTreeMap<u64, String>,
+}
+
+impl<V: Clone> LruCache<V> {
+    /// Creates an empty cache holding values up to the capacity in bytes
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, used: 0, stamp: 0, entries: HashMap::new(), order: BTreeMap::new() }
+    }
+
+    /// Returns a copy of the value and marks it as recently used
+    pub fn get(&mut self, key: &str) -> Option<V> {
+        let stamp = self.next_stamp();
+        let (value, _, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        self.order.insert(stamp, key.to_string());
+        *last_used = stamp;
+        Some(value.clone())
+    }
+
+    /// Stores the value, evicting the least recently used values until it fits
+    ///
+    /// Values larger than the whole cache are not stored.
+    pub fn insert(&mut self, key: String, value: V, size: usize) {
+        self.remove(&key);
+        if size > self.capacity {
+            return;
+        }
+        while self.used + size > self.capacity {
+            match self.order.iter().next().map(|(_, key)| key.clone()) {
+                Some(oldest) => self.remove(&oldest),
+                None => break
+            }
+        }
+        let stamp = self.next_stamp();
+        self.order.insert(stamp, key.clone());
+        self.entries.insert(key, (value, size, stamp));
+        self.used += size;
+    }
+
+    /// Removes the value if it is cached
+    pub fn remove(&mut self, key: &str) {
+        if let Some((_, size, stamp)) = self.entries.remove(key) {
+            self.order.remove(&stamp);
+            self.used -= size;
+        }
+    }
+
+    fn next_stamp(&mut self) -> u64 {
+        self.stamp += 1;
+        self.stamp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evict_least_recently_used_test() {
+        let mut cache = LruCache::new(10);
+        cache.insert("a".to_string(), 1, 4);
+        cache.insert("b".to_string(), 2, 4);
+        assert_eq!(cache.get("a"), Some(1));
+        cache.insert("c".to_string(), 3, 4);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(1));
+        assert_eq!(cache.get("c"), Some(3));
+        assert_eq!(cache.used, 8);
+    }
+
+    #[test]
+    fn insert_test() {
+        let mut cache = LruCache::new(10);
+        cache.insert("a".to_string(), 1, 4);
+        cache.insert("a".to_string(), 2, 6);
+        assert_eq!(cache.get("a"), Some(2));
+        assert_eq!(cache.used, 6);
+
+        cache.insert("large".to_string(), 3, 11);
+        assert_eq!(cache.get("large"), None);
+        assert_eq!(cache.get("a"), Some(2));
+
+        cache.insert("full".to_string(), 4, 10);
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.used, 10);
+        cache.remove("full");
+        assert_eq!(cache.used, 0);
+    }
+}
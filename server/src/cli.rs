@@ -1,15 +1,47 @@
-extern crate clap;
-
-/// Module containing the CLI for the webservers
+//! Module containing the CLI for the webservers
 
+extern crate clap;
 
 use clap::{Arg, App, ArgMatches};
+use std::time::Duration;
+use crate::compression::CompressionMode;
+use crate::request::{KeepAlive, RequestLimits};
+use crate::threaded::server::{PoolConfig, PoolKind, QueuePolicy};
 
-/// Starts the CLI and returns:
-/// - the address for the server to listen on
-/// - the directory the server should serve
-/// - the number of threads the thread pool should have
-pub fn start_cli() -> (String, i32, String, i32, String) {
+/// The configuration of the webservers provided by the user
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// the address for the server to listen on
+    pub ip: String,
+    pub port: i32,
+    /// the directory the server should serve
+    pub dir: String,
+    /// the number of threads the thread pool should have and the bounds of its queue
+    pub pool: PoolConfig,
+    /// the type of the server to run
+    pub type_: String,
+    /// the limits a request has to stay within
+    pub limits: RequestLimits,
+    /// the settings for persistent connections
+    pub keep_alive: KeepAlive,
+    /// whether directories without an index file are listed
+    pub directory_listing: bool,
+    /// the file in mime.types format replacing the builtin media types
+    pub mime_types: Option<String>,
+    /// when and how the responses are compressed
+    pub compression: CompressionMode,
+    /// how often the served directory is checked for changes, if it is watched
+    pub watch: Option<Duration>,
+    /// the size of the cache in bytes, if the files are read from disk on demand instead of being loaded at startup
+    pub disk_cache: Option<usize>,
+    /// the PEM files of the certificate chain and its private key, if the connections are encrypted with TLS
+    pub tls: Option<(String, String)>,
+    /// how long the accepted requests have to finish after SIGINT or SIGTERM
+    pub shutdown_timeout: Duration,
+}
+
+/// Starts the CLI and returns the configuration provided by the user
+pub fn start_cli() -> Config {
     let cli = create_matchers();
 
     let ip = cli.value_of("ip").unwrap();
@@ -17,18 +49,64 @@ pub fn start_cli() -> (String, i32, String, i32, String) {
     let dir = cli.value_of("dir").unwrap();
     let threads = cli.value_of("threads").unwrap().parse::<u32>().unwrap();
     let type_ = cli.value_of("type").unwrap();
+    let max_body_size = cli.value_of("max_body_size").unwrap().parse::<usize>().unwrap();
+    let max_header_size = cli.value_of("max_header_size").unwrap().parse::<usize>().unwrap();
+    let read_timeout = cli.value_of("read_timeout").unwrap().parse::<u64>().unwrap();
+    let keep_alive_timeout = cli.value_of("keep_alive_timeout").unwrap().parse::<u64>().unwrap();
+    let max_requests = cli.value_of("max_requests").unwrap().parse::<usize>().unwrap();
+    let directory_listing = cli.is_present("directory_listing");
+    let mime_types = cli.value_of("mime_types").map(str::to_string);
+    let compression = CompressionMode::parse(cli.value_of("compression").unwrap()).unwrap();
+    let watch_interval = cli.value_of("watch_interval").unwrap().parse::<u64>().unwrap();
+    let watch = if cli.is_present("watch") { Some(Duration::from_millis(watch_interval)) } else { None };
+    let cache_size = cli.value_of("cache_size").unwrap().parse::<usize>().unwrap();
+    let disk_cache = if cli.value_of("storage").unwrap() == "disk" { Some(cache_size) } else { None };
+    let tls = cli.value_of("tls_cert").zip(cli.value_of("tls_key"))
+        .map(|(cert, key)| (cert.to_string(), key.to_string()));
+    let shutdown_timeout = cli.value_of("shutdown_timeout").unwrap().parse::<u64>().unwrap();
+    let pool_kind = PoolKind::parse(cli.value_of("pool").unwrap()).unwrap();
+    let queue_capacity = cli.value_of("queue_capacity").unwrap().parse::<usize>().unwrap();
+    let queue_policy = QueuePolicy::parse(cli.value_of("queue_policy").unwrap()).unwrap();
 
-    (ip.to_string(),
-     port as i32,
-     dir.to_string(),
-     threads as i32,
-     type_.to_string())
+    Config {
+        ip: ip.to_string(),
+        port: port as i32,
+        dir: dir.to_string(),
+        pool: PoolConfig {
+            size: threads as usize,
+            kind: pool_kind,
+            queue_capacity: if queue_capacity > 0 { Some(queue_capacity) } else { None },
+            queue_policy,
+        },
+        type_: type_.to_string(),
+        limits: RequestLimits {
+            max_body_size,
+            max_header_size,
+            read_timeout: Duration::from_millis(read_timeout),
+        },
+        keep_alive: KeepAlive {
+            timeout: Duration::from_millis(keep_alive_timeout),
+            max_requests,
+        },
+        directory_listing,
+        mime_types,
+        compression,
+        watch,
+        disk_cache,
+        tls,
+        shutdown_timeout: Duration::from_millis(shutdown_timeout),
+    }
 }
 
 
 /// Creates the required CLI parser.
 fn create_matchers() -> ArgMatches<'static> {
-    return App::new("Webserver")
+    create_app().get_matches()
+}
+
+/// Describes the arguments of the CLI
+fn create_app() -> App<'static, 'static> {
+    App::new("Webserver")
         .version("0.1.0")
         .author("Jörg S, Julian Z")
         .about("A simple but fast server in rust")
@@ -38,7 +116,7 @@ fn create_matchers() -> ArgMatches<'static> {
             .long("port")
             .default_value("9000")
             .value_name("PORT")
-            .validator(|value| valid_port(value))
+            .validator(valid_port)
             .help("The port the server will listen on")
             .takes_value(true))
         .arg(Arg::with_name("threads")
@@ -47,15 +125,39 @@ fn create_matchers() -> ArgMatches<'static> {
             .long("threads")
             .default_value("8")
             .value_name("THREADS")
-            .validator(|value| valid_threads(value))
+            .validator(valid_threads)
             .help("The amount of threads to handle the requests")
             .takes_value(true))
+        .arg(Arg::with_name("pool")
+            .required(true)
+            .long("pool")
+            .default_value("shared")
+            .validator(valid_pool)
+            .value_name("KIND")
+            .help("How the threads of the threaded server take the connections [shared|stealing]. Shared uses one queue behind a lock, stealing a deque per thread with work stealing")
+            .takes_value(true))
+        .arg(Arg::with_name("queue_capacity")
+            .required(true)
+            .long("queue_capacity")
+            .default_value("0")
+            .validator(valid_size)
+            .value_name("NUM")
+            .help("The amount of connections waiting for a thread of the threaded server, 0 does not limit the queue")
+            .takes_value(true))
+        .arg(Arg::with_name("queue_policy")
+            .required(true)
+            .long("queue_policy")
+            .default_value("block")
+            .validator(valid_queue_policy)
+            .value_name("POLICY")
+            .help("What happens to new connections while the queue is full [block|reject|drop_oldest]. Block stops accepting, reject answers the new and drop_oldest the oldest waiting connection with 503")
+            .takes_value(true))
         .arg(Arg::with_name("ip")
             .short("ip")
             .required(true)
             .long("ip_address")
             .default_value("127.0.0.1")
-            .validator(|value| valid_ip(value))
+            .validator(valid_ip)
             .value_name("IP")
             .help("The IPv4 the server will listen on")
             .takes_value(true))
@@ -72,11 +174,120 @@ fn create_matchers() -> ArgMatches<'static> {
             .required(true)
             .long("server_type")
             .default_value("all")
-            .validator(|value| valid_type(value))
+            .validator(valid_type)
             .value_name("TYPE")
-            .help("The type of the server [threaded|event_loop|single_thread|all]. Event loop is only supported on BSD systems, and eventually linux.")
+            .help("The type of the server [threaded|event_loop|single_thread|all]. Event loop is only supported on BSD and linux systems.")
+            .takes_value(true))
+        .arg(Arg::with_name("max_body_size")
+            .short("b")
+            .required(true)
+            .long("max_body_size")
+            .default_value("1048576")
+            .validator(valid_size)
+            .value_name("BYTES")
+            .help("The maximum size of a request body in bytes, larger requests are rejected with 413")
+            .takes_value(true))
+        .arg(Arg::with_name("max_header_size")
+            .required(true)
+            .long("max_header_size")
+            .default_value("8192")
+            .validator(valid_size)
+            .value_name("BYTES")
+            .help("The maximum size of the request line and headers in bytes, larger requests are rejected with 431")
+            .takes_value(true))
+        .arg(Arg::with_name("read_timeout")
+            .required(true)
+            .long("read_timeout")
+            .default_value("5000")
+            .validator(valid_timeout)
+            .value_name("MILLIS")
+            .help("The time in milliseconds a request has to arrive completely, slower requests are rejected with 408")
+            .takes_value(true))
+        .arg(Arg::with_name("keep_alive_timeout")
+            .required(true)
+            .long("keep_alive_timeout")
+            .default_value("5000")
+            .validator(valid_millis)
+            .value_name("MILLIS")
+            .help("The time in milliseconds an idle connection is kept open for further requests, 0 disables keep-alive")
+            .takes_value(true))
+        .arg(Arg::with_name("max_requests")
+            .required(true)
+            .long("max_requests_per_connection")
+            .default_value("100")
+            .validator(valid_max_requests)
+            .value_name("NUM")
+            .help("The amount of requests served over one connection before it is closed")
+            .takes_value(true))
+        .arg(Arg::with_name("directory_listing")
+            .short("l")
+            .long("directory_listing")
+            .help("Lists the contents of directories without an index.html"))
+        .arg(Arg::with_name("mime_types")
+            .long("mime_types")
+            .value_name("FILE")
+            .help("A file in mime.types format mapping extensions to media types, replacing the builtin ones")
+            .takes_value(true))
+        .arg(Arg::with_name("compression")
+            .short("c")
+            .required(true)
+            .long("compression")
+            .default_value("off")
+            .validator(valid_compression)
+            .value_name("MODE")
+            .help("How responses are compressed [off|on_the_fly|precompress|siblings]. Precompress compresses the static files at startup, siblings serves their .gz and .br files")
+            .takes_value(true))
+        .arg(Arg::with_name("watch")
+            .short("w")
+            .long("watch")
+            .help("Reloads the served directory when files in it change, without restarting the server"))
+        .arg(Arg::with_name("watch_interval")
+            .required(true)
+            .long("watch_interval")
+            .default_value("1000")
+            .validator(valid_timeout)
+            .value_name("MILLIS")
+            .help("The time in milliseconds between two checks of the watched directory")
+            .takes_value(true))
+        .arg(Arg::with_name("storage")
+            .short("s")
+            .required(true)
+            .long("storage")
+            .default_value("memory")
+            .validator(valid_storage)
+            .value_name("STORAGE")
+            .help("Where the served files are kept [memory|disk]. Memory loads all files at startup, disk reads them on demand")
+            .takes_value(true))
+        .arg(Arg::with_name("cache_size")
+            .required(true)
+            .long("cache_size")
+            .default_value("67108864")
+            .validator(valid_size)
+            .value_name("BYTES")
+            .help("The size of the cache keeping the recently served files in memory, when serving from disk")
+            .takes_value(true))
+        .arg(Arg::with_name("tls_cert")
+            .long("tls_cert")
+            .alias("tls-cert")
+            .requires("tls_key")
+            .value_name("FILE")
+            .help("The certificate chain in PEM format, the servers speak HTTPS instead of HTTP with it")
+            .takes_value(true))
+        .arg(Arg::with_name("tls_key")
+            .long("tls_key")
+            .alias("tls-key")
+            .requires("tls_cert")
+            .value_name("FILE")
+            .help("The private key of the certificate in PEM format")
+            .takes_value(true))
+        .arg(Arg::with_name("shutdown_timeout")
+            .required(true)
+            .long("shutdown_timeout")
+            .default_value("5000")
+            .validator(valid_millis)
+            .value_name("MILLIS")
+            .help("The time in milliseconds the accepted requests have to finish after SIGINT or SIGTERM, before the servers exit anyway")
             .takes_value(true))
-        .get_matches();
 }
 
 
@@ -97,13 +308,16 @@ fn valid_type(s: String) -> Result<(), String> {
             match std::env::consts::OS {
                 "macos" => {}
                 "freebsd" => {}
+                "openbsd" => {}
+                "netbsd" => {}
+                "dragonfly" => {}
                 "linux" => {}
-                _ => return Err("Event loop is only supported on BSD systems, and eventually linux.".to_string())
+                _ => return Err("Event loop is only supported on BSD and linux systems.".to_string())
             }
         }
         _ => return Err("Please select a server type [threaded|event_loop|single_thread]".to_string())
     }
-    return Ok(());
+    Ok(())
 }
 
 /// Validate the correctness of the user provided amount of threads
@@ -114,6 +328,70 @@ fn valid_threads(string: String) -> Result<(), String> {
     }
 }
 
+/// Validate the correctness of the user provided size in bytes
+fn valid_size(string: String) -> Result<(), String> {
+    match string.parse::<usize>() {
+        Ok(_) => { Ok(()) }
+        _ => { Err("Please provide a valid size in bytes".to_string()) }
+    }
+}
+
+/// Validate the correctness of the user provided timeout in milliseconds
+fn valid_timeout(string: String) -> Result<(), String> {
+    match string.parse::<u64>() {
+        Ok(num) if num > 0 => { Ok(()) }
+        _ => { Err("Please provide a valid timeout in milliseconds (>0)".to_string()) }
+    }
+}
+
+/// Validate the correctness of the user provided duration in milliseconds, which may be 0
+fn valid_millis(string: String) -> Result<(), String> {
+    match string.parse::<u64>() {
+        Ok(_) => { Ok(()) }
+        _ => { Err("Please provide a valid time in milliseconds (>=0)".to_string()) }
+    }
+}
+
+/// Validate the correctness of the user provided amount of requests per connection
+fn valid_max_requests(string: String) -> Result<(), String> {
+    match string.parse::<usize>() {
+        Ok(num) if num >= 1 => { Ok(()) }
+        _ => { Err("Please provide a valid amount of requests per connection (>=1)".to_string()) }
+    }
+}
+
+/// Validate the correctness of the user provided compression mode
+fn valid_compression(string: String) -> Result<(), String> {
+    match CompressionMode::parse(&string) {
+        Some(_) => { Ok(()) }
+        None => { Err("Please select a compression mode [off|on_the_fly|precompress|siblings]".to_string()) }
+    }
+}
+
+/// Validate the correctness of the user provided kind of the thread pool
+fn valid_pool(string: String) -> Result<(), String> {
+    match PoolKind::parse(&string) {
+        Some(_) => { Ok(()) }
+        None => { Err("Please select a thread pool [shared|stealing]".to_string()) }
+    }
+}
+
+/// Validate the correctness of the user provided policy of the queue
+fn valid_queue_policy(string: String) -> Result<(), String> {
+    match QueuePolicy::parse(&string) {
+        Some(_) => { Ok(()) }
+        None => { Err("Please select a queue policy [block|reject|drop_oldest]".to_string()) }
+    }
+}
+
+/// Validate the correctness of the user provided storage of the served files
+fn valid_storage(string: String) -> Result<(), String> {
+    match &string[..] {
+        "memory" | "disk" => { Ok(()) }
+        _ => { Err("Please select a storage [memory|disk]".to_string()) }
+    }
+}
+
 /// Validate the correctness of the user provided ip
 fn valid_ip(ip: String) -> Result<(), String> {
     let blocks = ip[..].split('.')
@@ -154,7 +432,7 @@ mod tests {
         assert_ne!(valid_threads("test".to_string()), Ok(()));
         assert_ne!(valid_threads("0".to_string()), Ok(()));
         assert_ne!(valid_threads("1".to_string()), Ok(()));
-        assert_ne!(valid_threads("2".to_string()), Ok(()));
+        assert_eq!(valid_threads("2".to_string()), Ok(()));
         assert_eq!(valid_threads("3".to_string()), Ok(()));
         assert_eq!(valid_threads("8".to_string()), Ok(()));
         assert_eq!(valid_threads("1000".to_string()), Ok(()));
@@ -171,4 +449,85 @@ mod tests {
         assert_eq!(valid_ip("192.178.168.17".to_string()), Ok(()));
         assert_ne!(valid_ip("1000000000".to_string()), Ok(()));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn valid_size_test() {
+        assert_ne!(valid_size("".to_string()), Ok(()));
+        assert_ne!(valid_size("test".to_string()), Ok(()));
+        assert_ne!(valid_size("-1".to_string()), Ok(()));
+        assert_eq!(valid_size("0".to_string()), Ok(()));
+        assert_eq!(valid_size("1048576".to_string()), Ok(()));
+    }
+
+    #[test]
+    fn valid_timeout_test() {
+        assert_ne!(valid_timeout("".to_string()), Ok(()));
+        assert_ne!(valid_timeout("test".to_string()), Ok(()));
+        assert_ne!(valid_timeout("0".to_string()), Ok(()));
+        assert_eq!(valid_timeout("1".to_string()), Ok(()));
+        assert_eq!(valid_timeout("5000".to_string()), Ok(()));
+    }
+
+    #[test]
+    fn valid_millis_test() {
+        assert_ne!(valid_millis("".to_string()), Ok(()));
+        assert_ne!(valid_millis("abc".to_string()), Ok(()));
+        assert_ne!(valid_millis("-1".to_string()), Ok(()));
+        assert_eq!(valid_millis("0".to_string()), Ok(()));
+        assert_eq!(valid_millis("5000".to_string()), Ok(()));
+    }
+
+    #[test]
+    fn valid_max_requests_test() {
+        assert_ne!(valid_max_requests("".to_string()), Ok(()));
+        assert_ne!(valid_max_requests("test".to_string()), Ok(()));
+        assert_ne!(valid_max_requests("0".to_string()), Ok(()));
+        assert_eq!(valid_max_requests("1".to_string()), Ok(()));
+        assert_eq!(valid_max_requests("100".to_string()), Ok(()));
+    }
+
+    #[test]
+    fn valid_compression_test() {
+        assert_ne!(valid_compression("".to_string()), Ok(()));
+        assert_ne!(valid_compression("gzip".to_string()), Ok(()));
+        assert_eq!(valid_compression("off".to_string()), Ok(()));
+        assert_eq!(valid_compression("on_the_fly".to_string()), Ok(()));
+        assert_eq!(valid_compression("precompress".to_string()), Ok(()));
+        assert_eq!(valid_compression("siblings".to_string()), Ok(()));
+    }
+
+    #[test]
+    fn valid_pool_test() {
+        assert_ne!(valid_pool("".to_string()), Ok(()));
+        assert_ne!(valid_pool("lock_free".to_string()), Ok(()));
+        assert_eq!(valid_pool("shared".to_string()), Ok(()));
+        assert_eq!(valid_pool("stealing".to_string()), Ok(()));
+    }
+
+    #[test]
+    fn valid_queue_policy_test() {
+        assert_ne!(valid_queue_policy("".to_string()), Ok(()));
+        assert_ne!(valid_queue_policy("drop".to_string()), Ok(()));
+        assert_eq!(valid_queue_policy("block".to_string()), Ok(()));
+        assert_eq!(valid_queue_policy("reject".to_string()), Ok(()));
+        assert_eq!(valid_queue_policy("drop_oldest".to_string()), Ok(()));
+    }
+
+    #[test]
+    fn valid_storage_test() {
+        assert_ne!(valid_storage("".to_string()), Ok(()));
+        assert_ne!(valid_storage("ssd".to_string()), Ok(()));
+        assert_eq!(valid_storage("memory".to_string()), Ok(()));
+        assert_eq!(valid_storage("disk".to_string()), Ok(()));
+    }
+
+    #[test]
+    fn tls_args_test() {
+        for (cert, key) in [("--tls_cert", "--tls_key"), ("--tls-cert", "--tls-key")] {
+            let matches = create_app().get_matches_from_safe(vec!["webserver", cert, "cert.pem", key, "key.pem"]).unwrap();
+            assert_eq!(matches.value_of("tls_cert"), Some("cert.pem"));
+            assert_eq!(matches.value_of("tls_key"), Some("key.pem"));
+        }
+        assert!(create_app().get_matches_from_safe(vec!["webserver", "--tls-cert", "cert.pem"]).is_err());
+    }
+}
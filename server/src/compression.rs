@@ -0,0 +1,190 @@
+//! Module compressing the response bodies according to the Accept-Encoding of the client
+
+use std::io::{self, Write};
+use flate2::Compression;
+use flate2::write::{DeflateEncoder, GzEncoder};
+
+/// Bodies smaller than this are sent as they are, as compressing them does not pay off
+const MIN_COMPRESSED_SIZE: usize = 256;
+
+/// When and how the servers compress their responses
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CompressionMode {
+    /// nothing is compressed
+    Off,
+    /// the responses are compressed for every request
+    OnTheFly,
+    /// the static files are compressed at startup, all other responses on the fly
+    Precompress,
+    /// the static files are sent with their .gz and .br siblings from the served directory, all other responses on the fly
+    Siblings,
+}
+
+impl CompressionMode {
+    /// Reads the mode from its name on the command line
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(CompressionMode::Off),
+            "on_the_fly" => Some(CompressionMode::OnTheFly),
+            "precompress" => Some(CompressionMode::Precompress),
+            "siblings" => Some(CompressionMode::Siblings),
+            _ => None
+        }
+    }
+}
+
+/// The content codings the servers support, in the order they are preferred
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+/// All supported content codings, the preferred first
+pub const ENCODINGS: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+impl Encoding {
+    /// The name of the coding in the Accept-Encoding and Content-Encoding headers
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// The extension of files precompressed with the coding
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some("br"),
+            Encoding::Gzip => Some("gz"),
+            Encoding::Deflate => None,
+        }
+    }
+
+    /// Compresses the content, the best level takes longer and is meant for compressing at startup
+    pub fn compress(&self, content: &[u8], best: bool) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let quality = if best { 11 } else { 5 };
+                let mut compressed = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, quality, 22);
+                    writer.write_all(content)?;
+                }
+                Ok(compressed)
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), level(best));
+                encoder.write_all(content)?;
+                encoder.finish()
+            }
+            Encoding::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), level(best));
+                encoder.write_all(content)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// The compression level of gzip and deflate
+fn level(best: bool) -> Compression {
+    if best { Compression::best() } else { Compression::default() }
+}
+
+/// Chooses the coding the client accepts with the highest quality among the available ones
+///
+/// E.g. gzip;q=0.8, br, *;q=0.1
+/// Codings with the same quality are chosen in the order they are available.
+/// Returns None if the body should be sent as it is.
+pub fn negotiate(accept_encoding: &str, available: &[Encoding]) -> Option<Encoding> {
+    let accepted: Vec<(&str, f32)> = accept_encoding.split(',')
+        .filter_map(|coding| {
+            let mut parameters = coding.split(';').map(str::trim);
+            let name = parameters.next().filter(|name| !name.is_empty())?;
+            let quality = parameters
+                .find_map(|parameter| parameter.strip_prefix("q=").or_else(|| parameter.strip_prefix("Q=")))
+                .map_or(Some(1.0), |quality| quality.parse::<f32>().ok())?;
+            Some((name, quality))
+        })
+        .collect();
+    let quality_of = |name: &str| accepted.iter()
+        .find(|(accepted_name, _)| accepted_name.eq_ignore_ascii_case(name))
+        .or_else(|| accepted.iter().find(|(accepted_name, _)| *accepted_name == "*"))
+        .map_or(0.0, |(_, quality)| *quality);
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in available {
+        let quality = quality_of(encoding.as_str());
+        if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((*encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether bodies of the content type get smaller when compressed, which images and videos do not
+pub fn is_compressible(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    media_type.starts_with("text/") || matches!(media_type,
+        "application/json" | "application/manifest+json" | "application/xml" | "application/wasm"
+        | "image/svg+xml" | "image/x-icon" | "image/bmp")
+}
+
+/// Whether the body is large enough to be worth compressing
+pub fn is_worth_compressing(body: &[u8]) -> bool {
+    body.len() >= MIN_COMPRESSED_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use flate2::read::{DeflateDecoder, GzDecoder};
+
+    #[test]
+    fn negotiate_test() {
+        assert_eq!(negotiate("gzip, deflate, br", &ENCODINGS), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip, deflate", &ENCODINGS), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0.5, gzip;q=0.8", &ENCODINGS), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate, br;q=0", &ENCODINGS), Some(Encoding::Deflate));
+        assert_eq!(negotiate("*;q=0.1", &ENCODINGS), Some(Encoding::Brotli));
+        assert_eq!(negotiate("*, br;q=0", &ENCODINGS), Some(Encoding::Gzip));
+        assert_eq!(negotiate("GZIP", &ENCODINGS), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br", &[Encoding::Gzip]), None);
+        assert_eq!(negotiate("identity", &ENCODINGS), None);
+        assert_eq!(negotiate("gzip;q=x", &ENCODINGS), None);
+        assert_eq!(negotiate("", &ENCODINGS), None);
+    }
+
+    #[test]
+    fn compress_test() {
+        let content = "Hello World ".repeat(100).into_bytes();
+
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&Encoding::Gzip.compress(&content, false).unwrap()[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, content);
+
+        let mut decompressed = Vec::new();
+        DeflateDecoder::new(&Encoding::Deflate.compress(&content, true).unwrap()[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, content);
+
+        let compressed = Encoding::Brotli.compress(&content, false).unwrap();
+        assert!(compressed.len() < content.len());
+        let mut decompressed = Vec::new();
+        brotli::Decompressor::new(&compressed[..], 4096).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, content);
+    }
+
+    #[test]
+    fn is_compressible_test() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/json"));
+        assert!(is_compressible("image/svg+xml; charset=utf-8"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("video/mp4"));
+        assert!(!is_compressible("application/octet-stream"));
+    }
+}
@@ -0,0 +1,240 @@
+//! Module wrapping the accepted sockets, which are either plain TCP or encrypted with TLS
+
+use std::io::{self, IoSlice, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+
+/// The certificate and key the servers encrypt their connections with, shared by all connections
+pub type TlsConfig = Arc<ServerConfig>;
+
+/// Loads the certificate chain and the private key from their PEM files
+///
+/// Self-signed certificates work as well, the servers do not check them.
+pub fn load_tls_config(cert: &Path, key: &Path) -> Result<TlsConfig, String> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|error| format!("Could not read the certificates in {}: {}", cert.display(), error))?;
+    if certs.is_empty() {
+        return Err(format!("There is no certificate in {}", cert.display()));
+    }
+    let private_key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|error| format!("Could not read the private key in {}: {}", key.display(), error))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, private_key)
+        .map_err(|error| format!("Could not use the certificate {}: {}", cert.display(), error))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec(), b"http/1.0".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// An accepted connection, read from and written to like the socket itself
+///
+/// Encrypted connections do their handshake while the first request is read.
+#[derive(Debug)]
+pub enum Connection {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Connection {
+    /// Wraps the accepted socket, encrypting it if the servers have a certificate
+    pub fn new(stream: TcpStream, tls: Option<&TlsConfig>) -> io::Result<Self> {
+        match tls {
+            Some(config) => {
+                let connection = ServerConnection::new(config.clone()).map_err(io::Error::other)?;
+                Ok(Connection::Tls(Box::new(StreamOwned::new(connection, stream))))
+            }
+            None => Ok(Connection::Plain(stream))
+        }
+    }
+
+    /// The underlying socket
+    pub fn socket(&self) -> &TcpStream {
+        match self {
+            Connection::Plain(stream) => stream,
+            Connection::Tls(stream) => &stream.sock,
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket().set_read_timeout(timeout)
+    }
+
+    /// Whether encrypted data is still waiting to be written into the socket
+    pub fn wants_write(&self) -> bool {
+        match self {
+            Connection::Plain(_) => false,
+            Connection::Tls(stream) => stream.conn.wants_write(),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write_vectored(bufs),
+            Connection::Tls(stream) => stream.write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Connection {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket().as_raw_fd()
+    }
+}
+
+/// How long closing an encrypted connection waits for a client that does not read
+const CLOSE_NOTIFY_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Tells the client that the encrypted connection ends, so it does not suspect a truncated response
+///
+/// This is best-effort, a client that stopped reading does not block the closing thread.
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Connection::Tls(stream) = self {
+            let _ = stream.sock.set_write_timeout(Some(CLOSE_NOTIFY_TIMEOUT));
+            stream.conn.send_close_notify();
+            while stream.conn.wants_write() {
+                match stream.conn.write_tls(&mut stream.sock) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use rustls::pki_types::ServerName;
+
+    /// Writes a self-signed certificate for localhost and its key, returning their paths
+    fn self_signed(name: &str) -> (PathBuf, PathBuf) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert = dir.join(format!("webserver_tls_test_{}_{}.crt", name, std::process::id()));
+        let key = dir.join(format!("webserver_tls_test_{}_{}.key", name, std::process::id()));
+        fs::write(&cert, certified.cert.pem()).unwrap();
+        fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+        (cert, key)
+    }
+
+    #[test]
+    fn load_tls_config_test() {
+        let (cert, key) = self_signed("load");
+        let config = load_tls_config(&cert, &key).unwrap();
+        assert_eq!(config.alpn_protocols, vec![b"http/1.1".to_vec(), b"http/1.0".to_vec()]);
+        assert!(load_tls_config(&key, &key).is_err());
+        assert!(load_tls_config(&cert, &cert).is_err());
+        assert!(load_tls_config(Path::new("/missing.crt"), &key).is_err());
+        fs::remove_file(cert).unwrap();
+        fs::remove_file(key).unwrap();
+    }
+
+    #[test]
+    fn encrypted_connection_test() {
+        let (cert, key) = self_signed("connection");
+        let config = load_tls_config(&cert, &key).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_file(&cert).unwrap()).unwrap();
+        let client_config = Arc::new(ClientConfig::builder().with_root_certificates(roots).with_no_client_auth());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let connection = ClientConnection::new(client_config, ServerName::try_from("localhost").unwrap()).unwrap();
+            let mut stream = StreamOwned::new(connection, TcpStream::connect(address).unwrap());
+            stream.write_all(b"ping").unwrap();
+            let mut answer = Vec::new();
+            stream.read_to_end(&mut answer).unwrap();
+            answer
+        });
+
+        let mut connection = Connection::new(listener.accept().unwrap().0, Some(&config)).unwrap();
+        let mut ping = [0; 4];
+        connection.read_exact(&mut ping).unwrap();
+        assert_eq!(&ping, b"ping");
+        connection.write_all(b"pong").unwrap();
+        connection.flush().unwrap();
+        assert!(!connection.wants_write());
+        drop(connection);
+        assert_eq!(client.join().unwrap(), b"pong");
+        fs::remove_file(cert).unwrap();
+        fs::remove_file(key).unwrap();
+    }
+
+    #[test]
+    fn close_stalled_connection_test() {
+        let (cert, key) = self_signed("stalled");
+        let config = load_tls_config(&cert, &key).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_file(&cert).unwrap()).unwrap();
+        let client_config = Arc::new(ClientConfig::builder().with_root_certificates(roots).with_no_client_auth());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (finish, finished) = std::sync::mpsc::channel::<()>();
+        let client = std::thread::spawn(move || {
+            let connection = ClientConnection::new(client_config, ServerName::try_from("localhost").unwrap()).unwrap();
+            let mut stream = StreamOwned::new(connection, TcpStream::connect(address).unwrap());
+            stream.write_all(b"ping").unwrap();
+            // the client stops reading, until the server closed the connection
+            let _ = finished.recv();
+        });
+
+        let mut connection = Connection::new(listener.accept().unwrap().0, Some(&config)).unwrap();
+        let mut ping = [0; 4];
+        connection.read_exact(&mut ping).unwrap();
+
+        // fill the socket until nothing more can be sent
+        connection.socket().set_nonblocking(true).unwrap();
+        while connection.write(&[0; 16384]).is_ok() {}
+        assert!(connection.wants_write());
+        connection.socket().set_nonblocking(false).unwrap();
+
+        let start = std::time::Instant::now();
+        drop(connection);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        drop(finish);
+        client.join().unwrap();
+        fs::remove_file(cert).unwrap();
+        fs::remove_file(key).unwrap();
+    }
+}
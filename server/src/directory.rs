@@ -0,0 +1,138 @@
+//! Module resolving requested directories to their index file or a generated listing
+
+use crate::file::{ServerFiles, StaticFile};
+use crate::uri::percent_encode;
+
+/// The file served for a directory, if it contains one
+const INDEX_FILE: &str = "index.html";
+
+/// What a requested path of the static files stands for
+#[derive(Debug, Eq, PartialEq)]
+pub enum Resource {
+    /// a file, with the path it is stored under, which is the index file for directories
+    File(String, StaticFile),
+    /// a directory requested without its trailing slash, redirected to the location with it
+    Redirect(String),
+    /// the generated html page listing the contents of a directory
+    Listing(Vec<u8>),
+    Missing,
+}
+
+/// Resolves the normalised path to the file or directory it stands for
+///
+/// Directories are only served if they contain an index file, or if the directory listing is enabled.
+/// The query is kept when redirecting.
+pub fn resolve(path: &str, query: Option<&str>, files: &ServerFiles) -> Resource {
+    if let Some(file) = files.file(path) {
+        return Resource::File(path.to_string(), file);
+    }
+
+    let dir = if path.ends_with('/') { path.to_string() } else { format!("{}/", path) };
+    let index = format!("{}{}", dir, INDEX_FILE);
+    let index_file = files.file(&index);
+    let has_listing = files.directory_listing && files.is_directory(&dir);
+    if index_file.is_none() && !has_listing {
+        return Resource::Missing;
+    }
+
+    if !path.ends_with('/') {
+        let location = match query {
+            Some(query) => format!("{}?{}", percent_encode(&dir), query),
+            None => percent_encode(&dir)
+        };
+        return Resource::Redirect(location);
+    }
+
+    if let Some(file) = index_file {
+        return Resource::File(index, file);
+    }
+    match build_listing(&dir, files) {
+        Some(html) => Resource::Listing(html.into_bytes()),
+        None => Resource::Missing
+    }
+}
+
+/// Builds the html page listing the directory dynamically, subdirectories first
+///
+/// Returns None if the templates are missing.
+fn build_listing(dir: &str, files: &ServerFiles) -> Option<String> {
+    let mut html = files.dynamic_files.get("/directory_listing.html")?.to_string();
+    let list_entry = files.dynamic_files.get("/directory_listing_entry.html")?;
+
+    let (directories, file_names) = files.directory_entries(dir);
+
+    let parent = if dir == "/" { None } else { Some("../".to_string()) };
+    let mut entries = String::new();
+    parent.into_iter().chain(directories).chain(file_names).for_each(|name| {
+        let mut entry = list_entry.to_string();
+        entry = entry.replace("{{Link}}", &escape_html(&percent_encode(&name)));
+        entry = entry.replace("{{Name}}", &escape_html(&name));
+        entries = format!("{}\n{}", entries, entry);
+    });
+
+    html = html.replace("{{Path}}", &escape_html(dir));
+    html = html.replace("{{directory_entries}}", &entries);
+    Some(html)
+}
+
+/// Escapes the characters with a meaning in html, so file names are displayed as they are
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+
+    fn server_files(directory_listing: bool) -> ServerFiles {
+        let static_files: HashMap<String, StaticFile> = vec!["/hello.html", "/docs/index.html", "/img/a b.png", "/img/icons/x.ico"]
+            .into_iter()
+            .map(|path| (path.to_string(), file(path)))
+            .collect();
+        let dynamic_files: HashMap<String, String> = vec![
+            ("/directory_listing.html", "{{Path}}:{{directory_entries}}"),
+            ("/directory_listing_entry.html", "{{Link}}|{{Name}}"),
+        ].into_iter().map(|(path, content)| (path.to_string(), content.to_string())).collect();
+        ServerFiles { static_files: Arc::new(RwLock::new(Arc::new(static_files))), dynamic_files: Arc::new(dynamic_files), directory_listing, compression: crate::compression::CompressionMode::Off, disk_files: None }
+    }
+
+    fn file(path: &str) -> StaticFile {
+        StaticFile::new(path.as_bytes().to_vec(), None)
+    }
+
+    #[test]
+    fn resolve_file_test() {
+        let files = server_files(false);
+        assert_eq!(resolve("/hello.html", None, &files), Resource::File("/hello.html".to_string(), file("/hello.html")));
+        assert_eq!(resolve("/missing.html", None, &files), Resource::Missing);
+    }
+
+    #[test]
+    fn resolve_index_test() {
+        let files = server_files(false);
+        assert_eq!(resolve("/docs/", None, &files), Resource::File("/docs/index.html".to_string(), file("/docs/index.html")));
+        assert_eq!(resolve("/docs", Some("a=1"), &files), Resource::Redirect("/docs/?a=1".to_string()));
+        assert_eq!(resolve("/img/", None, &files), Resource::Missing);
+        assert_eq!(resolve("/", None, &files), Resource::Missing);
+    }
+
+    #[test]
+    fn resolve_listing_test() {
+        let files = server_files(true);
+        assert_eq!(resolve("/img", None, &files), Resource::Redirect("/img/".to_string()));
+        assert_eq!(resolve("/img/", None, &files), Resource::Listing(b"/img/:\n../|../\nicons/|icons/\na%20b.png|a b.png".to_vec()));
+        assert_eq!(resolve("/", None, &files), Resource::Listing(b"/:\ndocs/|docs/\nimg/|img/\nhello.html|hello.html".to_vec()));
+        assert_eq!(resolve("/nothing/", None, &files), Resource::Missing);
+    }
+
+    #[test]
+    fn escape_html_test() {
+        assert_eq!(escape_html("<a href=\"x\">'&'</a>"), "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;");
+    }
+}
@@ -0,0 +1,229 @@
+//! Module serving the files of the served directory from disk, keeping the recently used ones in memory
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use crate::body::Body;
+use crate::cache::LruCache;
+use crate::compression::{CompressionMode, ENCODINGS};
+use crate::file::{precompress_file, StaticFile};
+
+/// The state of a file on disk, the cached copy is outdated once it differs
+type FileState = (u64, Option<SystemTime>);
+
+/// The served directory, whose files are read when they are requested
+///
+/// Read files are cached up to the size of the cache. Before a cached file is served,
+/// its state on disk is compared, so changed files are read again without a restart.
+/// Files larger than the cache are never read, they are sent from disk with sendfile.
+#[derive(Debug)]
+pub struct DiskFiles {
+    root: PathBuf,
+    compression: CompressionMode,
+    cache_size: usize,
+    cache: Mutex<LruCache<(StaticFile, FileState)>>,
+}
+
+impl DiskFiles {
+    /// Serves the directory with a cache of the size in bytes
+    pub fn new(root: &Path, cache_size: usize, compression: CompressionMode) -> Result<Self, String> {
+        if !root.is_dir() {
+            return Err(format!("Could not serve {} from disk: not a directory", root.display()));
+        }
+        Ok(Self { root: root.to_path_buf(), compression, cache_size, cache: Mutex::new(LruCache::new(cache_size)) })
+    }
+
+    /// The file stored under the normalised path, from the cache if it did not change on disk
+    pub fn file(&self, path: &str) -> Option<StaticFile> {
+        let disk_path = self.disk_path(path);
+        let metadata = match fs::metadata(&disk_path).ok().filter(|metadata| metadata.is_file()) {
+            Some(metadata) => metadata,
+            None => {
+                self.lock_cache().remove(path);
+                return None;
+            }
+        };
+        let state = (metadata.len(), metadata.modified().ok());
+        if metadata.len() > self.cache_size as u64 {
+            return self.open_file(&disk_path, state);
+        }
+
+        if let Some((file, cached_state)) = self.lock_cache().get(path) {
+            if cached_state == state {
+                return Some(file);
+            }
+        }
+
+        let file = match self.read_file(path, &disk_path, state.1) {
+            Ok(file) => file,
+            Err(error) => {
+                println!("{}", error);
+                self.lock_cache().remove(path);
+                return None;
+            }
+        };
+        let size = file.content.len() + file.encodings.values().map(Body::len).sum::<usize>();
+        self.lock_cache().insert(path.to_string(), (file.clone(), state), size);
+        Some(file)
+    }
+
+    /// Whether the directory exists on disk
+    pub fn is_directory(&self, dir: &str) -> bool {
+        self.disk_path(dir).is_dir()
+    }
+
+    /// The names of the subdirectories, with a trailing slash, and the names of the files in the directory
+    pub fn directory_entries(&self, dir: &str) -> (BTreeSet<String>, BTreeSet<String>) {
+        let mut directories = BTreeSet::new();
+        let mut file_names = BTreeSet::new();
+        let entries = match fs::read_dir(self.disk_path(dir)) {
+            Ok(entries) => entries,
+            Err(_) => return (directories, file_names)
+        };
+        for entry in entries.flatten() {
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue
+            };
+            if entry.path().is_dir() {
+                directories.insert(format!("{}/", name));
+            } else {
+                file_names.insert(name);
+            }
+        }
+        (directories, file_names)
+    }
+
+    /// Reads the file and compresses it ahead of time, or reads its compressed siblings, if the mode asks to
+    fn read_file(&self, path: &str, disk_path: &Path, modified: Option<SystemTime>) -> Result<StaticFile, String> {
+        let content = fs::read(disk_path)
+            .map_err(|error| format!("Could not read {}: {}", disk_path.display(), error))?;
+        let mut file = StaticFile::new(content, modified);
+        match self.compression {
+            CompressionMode::Precompress => precompress_file(path, &mut file)?,
+            CompressionMode::Siblings => {
+                for encoding in ENCODINGS {
+                    let sibling = match encoding.extension() {
+                        Some(extension) => PathBuf::from(format!("{}.{}", disk_path.display(), extension)),
+                        None => continue
+                    };
+                    if let Ok(content) = fs::read(sibling) {
+                        file.encodings.insert(encoding, Body::shared(content));
+                    }
+                }
+            }
+            CompressionMode::Off | CompressionMode::OnTheFly => {}
+        }
+        Ok(file)
+    }
+
+    /// Opens the file to be sent from disk, together with its compressed siblings if the mode asks to
+    fn open_file(&self, disk_path: &Path, (length, modified): FileState) -> Option<StaticFile> {
+        let file = match fs::File::open(disk_path) {
+            Ok(file) => file,
+            Err(error) => {
+                println!("Could not open {}: {}", disk_path.display(), error);
+                return None;
+            }
+        };
+        let mut file = StaticFile::from_disk(file, length, modified);
+        if self.compression == CompressionMode::Siblings {
+            for encoding in ENCODINGS {
+                let sibling = match encoding.extension() {
+                    Some(extension) => PathBuf::from(format!("{}.{}", disk_path.display(), extension)),
+                    None => continue
+                };
+                if let (Ok(sibling), Ok(metadata)) = (fs::File::open(&sibling), fs::metadata(&sibling)) {
+                    file.encodings.insert(encoding, Body::file(sibling, metadata.len()));
+                }
+            }
+        }
+        Some(file)
+    }
+
+    /// The location of the normalised path on disk, which can not leave the served directory
+    fn disk_path(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+
+    /// Locks the cache, also if a thread panicked while holding it
+    fn lock_cache(&self) -> std::sync::MutexGuard<'_, LruCache<(StaticFile, FileState)>> {
+        match self.cache.lock() {
+            Ok(cache) => cache,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Encoding;
+
+    fn served_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("webserver_disk_test_{}_{}", name, std::process::id()));
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::write(dir.join("index.html"), "index").unwrap();
+        fs::write(dir.join("docs/a.txt"), "a").unwrap();
+        dir
+    }
+
+    #[test]
+    fn file_test() {
+        let dir = served_dir("file");
+        let disk_files = DiskFiles::new(&dir, 1024, CompressionMode::OnTheFly).unwrap();
+        assert_eq!(disk_files.file("/index.html").unwrap().content.to_vec().unwrap(), b"index");
+        assert_eq!(disk_files.file("/docs/a.txt").unwrap().content.to_vec().unwrap(), b"a");
+        assert_eq!(disk_files.file("/docs/"), None);
+        assert_eq!(disk_files.file("/missing.html"), None);
+        assert!(disk_files.lock_cache().get("/docs/a.txt").is_some());
+
+        fs::write(dir.join("index.html"), "changed").unwrap();
+        assert_eq!(disk_files.file("/index.html").unwrap().content.to_vec().unwrap(), b"changed");
+        fs::remove_file(dir.join("index.html")).unwrap();
+        assert_eq!(disk_files.file("/index.html"), None);
+        assert!(disk_files.lock_cache().get("/index.html").is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn siblings_test() {
+        let dir = served_dir("siblings");
+        fs::write(dir.join("index.html.gz"), "gzipped").unwrap();
+        let disk_files = DiskFiles::new(&dir, 1024, CompressionMode::Siblings).unwrap();
+        let file = disk_files.file("/index.html").unwrap();
+        assert_eq!(file.encodings[&Encoding::Gzip].to_vec().unwrap(), b"gzipped");
+        assert_eq!(file.available_encodings(), vec![Encoding::Gzip]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn large_file_test() {
+        let dir = served_dir("large");
+        fs::write(dir.join("large.txt"), "larger than the cache").unwrap();
+        fs::write(dir.join("large.txt.br"), "brotli").unwrap();
+        let disk_files = DiskFiles::new(&dir, 8, CompressionMode::Siblings).unwrap();
+        let file = disk_files.file("/large.txt").unwrap();
+        assert_eq!(file.content.bytes(), None);
+        assert_eq!(file.content.to_vec().unwrap(), b"larger than the cache");
+        assert_eq!(file.encodings[&Encoding::Brotli].to_vec().unwrap(), b"brotli");
+        assert!(disk_files.lock_cache().get("/large.txt").is_none());
+        assert_eq!(disk_files.file("/large.txt").unwrap().etag, file.etag);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn directory_test() {
+        let dir = served_dir("directory");
+        let disk_files = DiskFiles::new(&dir, 1024, CompressionMode::Off).unwrap();
+        assert!(disk_files.is_directory("/docs/"));
+        assert!(!disk_files.is_directory("/index.html/"));
+        let (directories, file_names) = disk_files.directory_entries("/");
+        assert_eq!(directories.into_iter().collect::<Vec<_>>(), vec!["docs/"]);
+        assert_eq!(file_names.into_iter().collect::<Vec<_>>(), vec!["index.html"]);
+        assert!(DiskFiles::new(&dir.join("index.html"), 1024, CompressionMode::Off).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,133 +1,179 @@
-use std::net::{TcpStream, TcpListener};
+use std::net::TcpListener;
+use crate::connection::Connection;
 use std::os::unix::io::AsRawFd;
-use crate::event_loop::unsafe_c::{Timespec, create_kqueue, put_kevent_in_kqueue, poll_kevents_from_q, create_k_read_event, create_k_write_event, KeventInternal};
-use crate::{Buffer, StaticFiles};
+use crate::event_loop::unsafe_c::{Timeout, InternalEvent, create_queue, close_queue, put_event_in_queue, poll_events_from_q, create_read_event, create_write_event};
+use crate::file::ServerFiles;
+use crate::reader::RequestBuffer;
+use crate::body::Body;
 
-/// The Queue holding events and a reference to the kqueue
+/// The Queue holding events and a reference to the OS event queue (kqueue or epoll)
 pub struct Queue<T> where T: GeneralEvent {
     pub events: Vec<T>,
-    pub wait_timeout: Timespec,
-    pub dir: StaticFiles,
+    pub wait_timeout: Timeout,
+    pub files: ServerFiles,
     pub fd: i32,
 }
 
 impl<T> Queue<T> where T: GeneralEvent {
-    /// Creates a new k queue
-    pub fn new(dir: StaticFiles) -> Result<Queue<T>, String> {
+    /// Creates a new OS event queue
+    pub fn new(files: ServerFiles) -> Result<Queue<T>, String> {
         Ok(Self {
             events: vec![],
-            wait_timeout: Timespec::zero(),
-            fd: create_kqueue()?,
-            dir,
+            wait_timeout: Timeout::zero(),
+            fd: create_queue()?,
+            files,
         })
     }
 
-    /// Adds a given element into the kqueue
+    /// Adds a given element into the OS event queue
     pub fn add(&mut self, event: T) -> Result<(), T> {
-        self.events.push(event);
-        let kevent = self.events.last().unwrap().get_kevent();
-        let worked = put_kevent_in_kqueue(self.fd, &kevent, &self.wait_timeout);
+        let worked = put_event_in_queue(self.fd, event.get_internal_event(), &self.wait_timeout);
         if let Err(err) = worked {
             println!("{}", err);
-            return Err(self.events.remove(self.events.len() - 1));
+            return Err(event);
         }
+        self.events.push(event);
         Ok(())
     }
 
-    /// Polls the q and retrieves the ready events
+    /// Polls the q and retrieves the ready events, without waiting for events to become ready
     pub fn poll(&mut self) -> Result<Vec<T>, String> {
-        let finished_events = loop {
-            let results = poll_kevents_from_q(self.fd, &self.wait_timeout)?;
-            if results.len() > 0 {
-                break results;
-            }
-        };
+        let finished_events = poll_events_from_q(self.fd, &self.wait_timeout)?;
 
-        let mut indexes = Vec::with_capacity(8);
+        let mut events = Vec::with_capacity(8);
         for event in finished_events {
             let index =
                 self.events
                     .iter()
-                    .position(|ev| ev.get_ident() == event.ident);
+                    .position(|ev| ev.get_ident() == event.ident());
             if let Some(idx) = index {
-                indexes.push(idx)
+                events.push(self.events.remove(idx))
             }
         }
+        Ok(events)
+    }
 
-        let mut events = Vec::with_capacity(8);
-        for index in indexes {
-            events.push(self.events.remove(index))
+    /// Removes the events matching the predicate from the q, without waiting for them to become ready
+    pub fn take_where<P>(&mut self, predicate: P) -> Vec<T> where P: Fn(&T) -> bool {
+        let mut taken = Vec::new();
+        let mut index = 0;
+        while index < self.events.len() {
+            if predicate(&self.events[index]) {
+                taken.push(self.events.remove(index));
+            } else {
+                index += 1;
+            }
         }
-        Ok(events)
+        taken
+    }
+}
+
+impl<T> Drop for Queue<T> where T: GeneralEvent {
+    /// Closes the OS event queue
+    fn drop(&mut self) {
+        close_queue(self.fd);
     }
 }
 
-/// The Event containing the request data, stream object and the kevent
-pub struct Event {
-    //todo change to request or sth like that
-    pub data: Buffer,
-    pub stream: TcpStream,
+/// The Event waiting for the request data to arrive, containing the stream object and the OS event
+pub struct ReadEvent {
+    pub buffer: RequestBuffer,
+    pub stream: Connection,
+    // the internal C representation of the Event
+    pub internal_event: InternalEvent,
+}
+
+/// The Event containing the response data, stream object and the OS event
+///
+/// The cursor marks how much of the data has already been written into the stream.
+/// The buffer of a persistent connection is kept, to read its next request afterwards.
+pub struct WriteEvent {
+    /// the status line and headers of the response
+    pub data: Vec<u8>,
+    /// the body sent after the head, without copying it into the data
+    pub body: Body,
+    /// the bytes of the head and body written so far
+    pub written: usize,
+    pub buffer: Option<RequestBuffer>,
+    pub stream: Connection,
     // the internal C representation of the Event
-    pub kevent: KeventInternal,
+    pub internal_event: InternalEvent,
 }
 
-/// The Event containing the request data, connection object and the kevent
+/// The Event containing the connection object and the OS event
 pub struct ListenerEvent {
-    //todo change to request or sth like that
-    pub data: Buffer,
     pub listener: TcpListener,
     // the internal C representation of the Event
-    pub kevent: KeventInternal,
+    pub internal_event: InternalEvent,
 }
 
-/// Trait which defines general Event functions used in Event and ListenerEvent
+/// Trait which defines general Event functions used in ReadEvent, WriteEvent and ListenerEvent
 pub trait GeneralEvent {
     fn get_ident(&self) -> u64;
-    fn get_kevent(&self) -> &KeventInternal;
+    fn get_internal_event(&self) -> &InternalEvent;
 }
 
-impl Event {
-    pub(crate) fn new_read(stream: TcpStream, data: Buffer) -> Self {
+impl ReadEvent {
+    pub(crate) fn new(stream: Connection, buffer: RequestBuffer) -> Self {
         Self {
-            data,
-            kevent: create_k_read_event(stream.as_raw_fd() as u64),
+            buffer,
+            internal_event: create_read_event(stream.as_raw_fd() as u64),
             stream,
         }
     }
-    pub(crate) fn new_write(stream: TcpStream, data: Buffer) -> Self {
+}
+
+impl WriteEvent {
+    pub(crate) fn new(stream: Connection, data: Vec<u8>, body: Body, buffer: Option<RequestBuffer>) -> Self {
         Self {
             data,
-            kevent: create_k_write_event(stream.as_raw_fd() as u64),
+            body,
+            written: 0,
+            buffer,
+            internal_event: create_write_event(stream.as_raw_fd() as u64),
             stream,
         }
     }
+
+    /// Whether all of the data has been written, encrypted connections also have to flush their records
+    pub fn is_finished(&self) -> bool {
+        self.written >= self.data.len() + self.body.len() && !self.stream.wants_write()
+    }
 }
 
-impl GeneralEvent for Event {
+impl GeneralEvent for ReadEvent {
     fn get_ident(&self) -> u64 {
-        self.kevent.ident
+        self.internal_event.ident()
     }
-    fn get_kevent(&self) -> &KeventInternal {
-        &self.kevent
+    fn get_internal_event(&self) -> &InternalEvent {
+        &self.internal_event
+    }
+}
+
+impl GeneralEvent for WriteEvent {
+    fn get_ident(&self) -> u64 {
+        self.internal_event.ident()
+    }
+    fn get_internal_event(&self) -> &InternalEvent {
+        &self.internal_event
     }
 }
 
 impl GeneralEvent for ListenerEvent {
     fn get_ident(&self) -> u64 {
-        self.kevent.ident
+        self.internal_event.ident()
     }
 
-    fn get_kevent(&self) -> &KeventInternal {
-        &self.kevent
+    fn get_internal_event(&self) -> &InternalEvent {
+        &self.internal_event
     }
 }
 
 
 impl ListenerEvent {
-    pub(crate) fn new(listener: TcpListener, data: Buffer) -> Self {
+    pub(crate) fn new(listener: TcpListener) -> Self {
         Self {
-            data,
-            kevent: create_k_read_event(listener.as_raw_fd() as u64),
+            internal_event: create_read_event(listener.as_raw_fd() as u64),
             listener,
         }
     }
@@ -1,8 +1,14 @@
 use crate::response::{Response, create_response};
-use crate::event_loop::ffi::{Queue, Event, ListenerEvent};
+use crate::event_loop::ffi::{Queue, ReadEvent, WriteEvent, ListenerEvent};
 use std::net::{TcpListener};
-use std::io::{Read, Write};
-use crate::{Buffer, StaticFiles};
+use std::io::{ErrorKind, Write};
+use crate::body::write_from;
+use crate::connection::{Connection, TlsConfig};
+use crate::file::ServerFiles;
+use crate::request::{KeepAlive, RequestLimits};
+use crate::reader::{ReadError, RequestBuffer};
+use crate::shutdown;
+use std::time::Instant;
 
 mod ffi;
 mod unsafe_c;
@@ -17,10 +23,14 @@ mod unsafe_c;
 /// It is always actively looking for work, but when work arrives, but would block,
 /// it continues on other work.
 /// In a future update it will even wait passively, if no work is available.
-pub fn start_server(ip: String, port: i32, dir: StaticFiles) {
+/// Persistent connections go back into the reading queue after their response was written.
+/// With a TLS config the handshake is done nonblocking as well, while the first request is read.
+/// On shutdown the listener and the connections waiting for requests are closed,
+/// the responses being written are finished until the deadline.
+pub fn start_server(ip: String, port: i32, files: ServerFiles, limits: RequestLimits, keep_alive: KeepAlive, tls: Option<TlsConfig>) {
     let address = format!("{}:{}", ip, port);
 
-    let (mut incoming_q, mut reading_q, mut writing_q) = match create_qs(dir) {
+    let (mut incoming_q, mut reading_q, mut writing_q) = match create_qs(files) {
         Ok(qs) => qs,
         Err(error) => {
             println!("{}", error);
@@ -31,47 +41,63 @@ pub fn start_server(ip: String, port: i32, dir: StaticFiles) {
 
     let listener = match TcpListener::bind(address) {
         Ok(listener) => listener,
-        Err(err) => panic!(err)
+        Err(err) => panic!("{}", err)
     };
 
-    match listener.set_nonblocking(true) {
-        Err(err) => panic!(err),
-        _ => {}
+    if let Err(err) = listener.set_nonblocking(true) {
+        panic!("{}", err)
     };
 
-    let listener_event = ListenerEvent::new(listener, [0; 2048]);
+    let listener_event = ListenerEvent::new(listener);
     let worked = incoming_q.add(listener_event);
-    if let Err(_) = worked {
+    if worked.is_err() {
         println!("Could not accept connection.")
     }
 
-    loop {
-        if reading_q.events.len() > 0 {
-            handle_reading(&mut reading_q, &mut writing_q)
+    while !shutdown::is_requested() {
+        if !reading_q.events.is_empty() {
+            handle_reading(&mut reading_q, &mut writing_q, keep_alive)
         }
-        if writing_q.events.len() > 0 {
-            handle_writing(&mut writing_q)
+        if !writing_q.events.is_empty() {
+            handle_writing(&mut writing_q, &mut reading_q, keep_alive)
         }
-        if incoming_q.events.len() > 0 {
-            handle_incoming(&mut incoming_q, &mut reading_q)
+        if !incoming_q.events.is_empty() {
+            handle_incoming(&mut incoming_q, &mut reading_q, limits, tls.as_ref())
         }
     }
+
+    drop(incoming_q);
+    reading_q.events.clear();
+    let deadline = shutdown::deadline();
+    while !writing_q.events.is_empty() && Instant::now() < deadline {
+        handle_writing(&mut writing_q, &mut reading_q, keep_alive)
+    }
+    if !writing_q.events.is_empty() {
+        println!("{} responses were not written in time", writing_q.events.len());
+    }
 }
 
+/// The incoming, reading and writing queues of the event loop
+type Queues = (Queue<ListenerEvent>, Queue<ReadEvent>, Queue<WriteEvent>);
+
 /// Creating the queues to handle the requests:
 ///
 /// Creates the incoming, writing and reading queues.
 /// When an error occurs, the server shuts down.
-fn create_qs(dir: StaticFiles) -> Result<(Queue<ListenerEvent>, Queue<Event>, Queue<Event>), String> {
-    let incoming_q = Queue::new(dir.clone())?;
-    let reading_q = Queue::new(dir.clone())?;
-    let writing_q = Queue::new(dir)?;
+fn create_qs(files: ServerFiles) -> Result<Queues, String> {
+    let incoming_q = Queue::new(files.clone())?;
+    let reading_q = Queue::new(files.clone())?;
+    let writing_q = Queue::new(files)?;
 
-    return Ok((incoming_q, reading_q, writing_q));
+    Ok((incoming_q, reading_q, writing_q))
 }
 
 /// Handle the writing into the socket nonblocking
-fn handle_writing(writing_q: &mut Queue<Event>) {
+///
+/// Writes as much of the responses as the sockets accept. Responses that are not written
+/// completely go back into the writing queue with their cursor, until everything is flushed.
+/// Afterwards persistent connections wait for their next request in the reading queue.
+fn handle_writing(writing_q: &mut Queue<WriteEvent>, reading_q: &mut Queue<ReadEvent>, keep_alive: KeepAlive) {
     let ready_writing_events = match writing_q.poll() {
         Ok(events) => events,
         Err(_) => {
@@ -80,19 +106,58 @@ fn handle_writing(writing_q: &mut Queue<Event>) {
         }
     };
     for mut event in ready_writing_events {
-        let bytes_written = match event.stream.write(&mut event.data) {
-            Err(_) => 0,
-            Ok(bytes_read) => bytes_read,
-        };
-        if bytes_written != event.data.len() {
-            println!("Not all written: buf: {}, written: {}", event.data.len(), bytes_written);
-            //todo back in queue with the rest of the work
+        if write_available(&mut event).is_err() {
+            println!("Connection error. Could not write response.");
+            continue;
+        }
+        if !event.is_finished() {
+            if writing_q.add(event).is_err() {
+                println!("Could not continue writing response.");
+            }
+            continue;
+        }
+        if let Some(mut buffer) = event.buffer {
+            buffer.wait_for_next(keep_alive.timeout);
+            // pipelined requests may already be buffered completely, so they are answered right away
+            answer_buffered(ReadEvent::new(event.stream, buffer), reading_q, writing_q, keep_alive);
         }
     }
 }
 
+/// Writes the remaining data into the socket, until it is finished or the socket would block
+///
+/// The chunks of streamed bodies are produced one at a time, once the previous one was written.
+/// Encrypted connections flush the records they still hold afterwards.
+fn write_available(event: &mut WriteEvent) -> std::io::Result<()> {
+    loop {
+        while event.written < event.data.len() + event.body.len() {
+            match write_from(&mut event.stream, &event.data, &event.body, event.written) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(bytes_written) => event.written += bytes_written,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                Err(error) => return Err(error)
+            }
+        }
+        match event.body.next_chunk() {
+            Some(chunk) => {
+                event.data = chunk;
+                event.written = 0;
+            }
+            None => break
+        }
+    }
+    match event.stream.flush() {
+        Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(()),
+        flushed => flushed
+    }
+}
+
 /// Handle the reading from the socket nonblocking
-fn handle_reading(reading_q: &mut Queue<Event>, writing_q: &mut Queue<Event>) {
+///
+/// Reads everything available from the ready sockets. Requests that are still incomplete
+/// go back into the reading queue, until they are complete or their read timeout expired.
+fn handle_reading(reading_q: &mut Queue<ReadEvent>, writing_q: &mut Queue<WriteEvent>, keep_alive: KeepAlive) {
     let ready_reading_events = match reading_q.poll() {
         Ok(events) => events,
         Err(_) => {
@@ -101,26 +166,76 @@ fn handle_reading(reading_q: &mut Queue<Event>, writing_q: &mut Queue<Event>) {
         }
     };
     for mut reading_event in ready_reading_events {
-        if let Err(_) = reading_event.stream.read(&mut reading_event.data) {
-            let response = Response::default_bad_request().make_sendable();
-            let event = Event::new_write(reading_event.stream, from_slice(&response[..]));
-            let worked = writing_q.add(event);
-            if let Err(_) = worked {
-                println!("Error while sending response.")
+        if let Err(error) = read_available(&mut reading_event) {
+            answer_read_error(reading_event, error, writing_q);
+            continue;
+        }
+        answer_buffered(reading_event, reading_q, writing_q, keep_alive);
+    }
+
+    for expired_event in reading_q.take_where(|event| event.buffer.is_expired()) {
+        let error = expired_event.buffer.timeout_error();
+        answer_read_error(expired_event, error, writing_q);
+    }
+}
+
+/// Answers the next request in the buffer, or waits in the reading queue for the rest of it
+fn answer_buffered(mut reading_event: ReadEvent, reading_q: &mut Queue<ReadEvent>, writing_q: &mut Queue<WriteEvent>, keep_alive: KeepAlive) {
+    let request = match reading_event.buffer.next_request() {
+        Ok(Some(request)) => request,
+        Ok(None) => {
+            if reading_q.add(reading_event).is_err() {
+                println!("Could not continue reading request.");
             }
             return;
-        };
-        let response = create_response(reading_event.data, reading_q.dir.clone());
-        let event = Event::new_write(reading_event.stream, from_slice(&response[..]));
-        let worked = writing_q.add(event);
-        if let Err(_) = worked {
-            println!("Error while sending response")
+        }
+        Err(error) => {
+            answer_read_error(reading_event, ReadError::Invalid(error), writing_q);
+            return;
+        }
+    };
+    let mut response = create_response(&request, &reading_q.files);
+    response.keep_alive = keep_alive.allows(&request, reading_event.buffer.requests_taken()) && !shutdown::is_requested();
+    let data = response.make_head_sendable();
+    let buffer = if response.keep_alive { Some(reading_event.buffer) } else { None };
+
+    let event = WriteEvent::new(reading_event.stream, data, response.body, buffer);
+    if writing_q.add(event).is_err() {
+        println!("Error while sending response")
+    }
+}
+
+/// Reads all bytes currently available on the socket into the request buffer
+fn read_available(event: &mut ReadEvent) -> Result<(), ReadError> {
+    loop {
+        match event.buffer.fill_from(&mut event.stream) {
+            Ok(0) => return Err(ReadError::Closed),
+            Ok(_) => {}
+            Err(error) if error.kind() == ErrorKind::WouldBlock => return Ok(()),
+            Err(error) if error.kind() == ErrorKind::Interrupted => {}
+            Err(_) => return Err(ReadError::Closed)
         }
     }
 }
 
+/// Sends the error response for a request that could not be read, or drops a closed connection
+fn answer_read_error(event: ReadEvent, error: ReadError, writing_q: &mut Queue<WriteEvent>) {
+    let error = match error {
+        ReadError::Invalid(error) => error,
+        ReadError::Closed => return
+    };
+    let mut response = Response::default_invalid_request(&error);
+    let data = response.make_head_sendable();
+    let event = WriteEvent::new(event.stream, data, response.body, None);
+    if writing_q.add(event).is_err() {
+        println!("Error while sending response.")
+    }
+}
+
 /// Handle the incoming connection nonblocking
-fn handle_incoming(incoming_q: &mut Queue<ListenerEvent>, reading_q: &mut Queue<Event>) {
+///
+/// Accepts all pending connections and puts the listener back into the incoming queue afterwards.
+fn handle_incoming(incoming_q: &mut Queue<ListenerEvent>, reading_q: &mut Queue<ReadEvent>, limits: RequestLimits, tls: Option<&TlsConfig>) {
     let ready_listening_events = match incoming_q.poll() {
         Ok(events) => events,
         Err(_) => {
@@ -129,36 +244,106 @@ fn handle_incoming(incoming_q: &mut Queue<ListenerEvent>, reading_q: &mut Queue<
         }
     };
     for listen_event in ready_listening_events {
-        match listen_event.listener.accept() {
-            Ok((stream, _)) => {
-                let read_event = Event::new_read(stream, [0; 2048]);
-                let worked = reading_q.add(read_event);
-                if let Err(_) = worked {
-                    println!("Could not accept connection.");
-                    return;
+        loop {
+            match listen_event.listener.accept() {
+                Ok((stream, _)) => {
+                    let connection = match stream.set_nonblocking(true).and_then(|_| Connection::new(stream, tls)) {
+                        Ok(connection) => connection,
+                        Err(_) => {
+                            println!("Could not accept connection.");
+                            continue;
+                        }
+                    };
+                    let read_event = ReadEvent::new(connection, RequestBuffer::new(limits));
+                    if reading_q.add(read_event).is_err() {
+                        println!("Could not accept connection.");
+                    }
                 }
-                let worked = incoming_q.add(listen_event);
-                if let Err(_) = worked {
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => {
                     println!("Could not accept connection.");
-                    return;
+                    break;
                 }
-            }
-            Err(_) => { println!("Could not accept connection."); }
-        };
+            };
+        }
+        if incoming_q.add(listen_event).is_err() {
+            println!("Could not listen for connections anymore.");
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+    use crate::test_client::{check_connection_loop, server_files, LIMITS};
+
+    const KEEP_ALIVE: KeepAlive = KeepAlive { timeout: Duration::from_secs(5), max_requests: 100 };
+
+    /// Connects a client to a loopback listener, returning both ends with the server end nonblocking
+    fn connect() -> (TcpStream, Connection) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+        (client, Connection::new(server, None).unwrap())
+    }
+
+    /// Handles the writing queue until it is empty
+    fn write_all_events(writing_q: &mut Queue<WriteEvent>, reading_q: &mut Queue<ReadEvent>) {
+        let start = Instant::now();
+        while !writing_q.events.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(10), "the responses were not written in time");
+            handle_writing(writing_q, reading_q, KEEP_ALIVE);
+        }
+    }
+
+    #[test]
+    fn write_available_partial_test() {
+        let (mut writing_q, mut reading_q) = (Queue::new(server_files()).unwrap(), Queue::new(server_files()).unwrap());
+        let (mut client, connection) = connect();
+        let head = b"HTTP/1.1 200 OK\r\n\r\n".to_vec();
+        let body: Vec<u8> = (0..16 * 1024 * 1024).map(|index| (index % 251) as u8).collect();
+        writing_q.add(WriteEvent::new(connection, head.clone(), body.clone().into(), None)).ok().unwrap();
 
-/// Converts a byte slice into a fixed length portion
-fn from_slice(bytes: &[u8]) -> Buffer {
-    let vec: Vec<u8> = bytes.to_vec();
-    let mut result = [0; 2048];
-    if bytes.len() > 2048 { println!("todo to small buffer"); }
-    for i in 0..2048 {
-        result[i] = match vec.get(i) {
-            Some(val) => *val,
-            None => 0,
+        // the client does not read yet, so the socket only takes a part of the response
+        let start = Instant::now();
+        while writing_q.events[0].written == 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            handle_writing(&mut writing_q, &mut reading_q, KEEP_ALIVE);
         }
+        assert_eq!(writing_q.events.len(), 1);
+        assert!(!writing_q.events[0].is_finished());
+        assert!(writing_q.events[0].written < head.len() + body.len());
+
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).unwrap();
+            received
+        });
+        write_all_events(&mut writing_q, &mut reading_q);
+        let received = reader.join().unwrap();
+        assert_eq!(&received[..head.len()], &head[..]);
+        assert!(received[head.len()..] == body[..]);
+    }
+
+    /// Runs the event loop for the single connection, until it was closed
+    fn serve(connection: Connection, keep_alive: KeepAlive) {
+        connection.socket().set_nonblocking(true).unwrap();
+        let (mut reading_q, mut writing_q) = (Queue::new(server_files()).unwrap(), Queue::new(server_files()).unwrap());
+        reading_q.add(ReadEvent::new(connection, RequestBuffer::new(LIMITS))).ok().unwrap();
+        while !reading_q.events.is_empty() || !writing_q.events.is_empty() {
+            handle_reading(&mut reading_q, &mut writing_q, keep_alive);
+            handle_writing(&mut writing_q, &mut reading_q, keep_alive);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn connection_loop_test() {
+        check_connection_loop(serve);
     }
-    result
 }
@@ -0,0 +1,185 @@
+use std::io::Error as IoError;
+
+
+// see man epoll on linux
+// the events are added as one shot events, after they fired once
+// epoll disables them, but they stay registered for the fd.
+// re adding them therefore has to modify the existing registration
+
+const EPOLLIN: u32 = 0x001;
+const EPOLLOUT: u32 = 0x004;
+const EPOLLONESHOT: u32 = 1 << 30;
+const EPOLL_CTL_ADD: i32 = 1;
+const EPOLL_CTL_MOD: i32 = 3;
+const EPOLL_CLOEXEC: i32 = 0o2000000;
+const EEXIST: i32 = 17;
+
+#[link(name = "c")]
+extern "C" {
+    // call of the C function, returning the fd
+    fn epoll_create1(flags: i32) -> i32;
+
+    fn epoll_ctl(
+        epfd: i32, // the file descriptor of the q
+        op: i32, // add, modify or delete the registration of the fd
+        fd: i32, // the file descriptor that should be watched
+        event: *mut EpollEvent, // the events the fd should be watched for
+    ) -> i32;
+
+    fn epoll_wait(
+        epfd: i32, // the file descriptor of the q
+        events: *mut EpollEvent, // pointer to array of the finished EpollEvents
+        maxevents: i32, // size of the events array
+        timeout: i32, // timeout in milliseconds, 0 returns immediately
+    ) -> i32;
+
+    fn close(d: i32) -> i32;
+}
+
+/// The event type the epoll backend works with
+pub type InternalEvent = EpollEvent;
+
+pub fn create_queue() -> Result<i32, String> {
+    let file_desc = unsafe { epoll_create1(EPOLL_CLOEXEC) };
+    if file_desc < 0 {
+        return Err(String::from("Error creating new epoll q"));
+    }
+    Ok(file_desc)
+}
+
+pub fn close_queue(fd: i32) {
+    unsafe { close(fd) };
+}
+
+
+fn create_epoll_event(events: u32, fd: u64) -> EpollEvent {
+    EpollEvent {
+        events: events | EPOLLONESHOT,
+        data: fd,
+    }
+}
+
+pub fn create_read_event(fd: u64) -> EpollEvent {
+    create_epoll_event(EPOLLIN, fd)
+}
+
+pub fn create_write_event(fd: u64) -> EpollEvent {
+    create_epoll_event(EPOLLOUT, fd)
+}
+
+
+pub fn put_event_in_queue(fd: i32, event: &EpollEvent, _timeout: &Timeout) -> Result<(), String> {
+    let mut event = *event;
+    let watched_fd = event.data as i32;
+    let mut worked = unsafe { epoll_ctl(fd, EPOLL_CTL_ADD, watched_fd, &mut event) };
+    if worked < 0 && IoError::last_os_error().raw_os_error() == Some(EEXIST) {
+        // the fd is still registered from a fired one shot event, so rearm it
+        worked = unsafe { epoll_ctl(fd, EPOLL_CTL_MOD, watched_fd, &mut event) };
+    }
+    if worked < 0 {
+        return Err(String::from("Could not insert event into q"));
+    }
+    Ok(())
+}
+
+pub fn poll_events_from_q(fd: i32, timeout: &Timeout) -> Result<Vec<EpollEvent>, String> {
+    let mut finished_events: Vec<EpollEvent> = Vec::with_capacity(256);
+    let res = unsafe {
+        epoll_wait(
+            fd,
+            finished_events.as_mut_ptr(),
+            finished_events.capacity() as i32,
+            timeout.millis,
+        )
+    };
+    if res < 0 {
+        return Err(String::from("Could not wait for event"));
+    };
+
+    unsafe { finished_events.set_len(res as usize) };
+    Ok(finished_events)
+}
+
+
+// the kernel declares the struct packed on x86_64 only
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+#[cfg_attr(target_arch = "x86_64", repr(packed))]
+pub struct EpollEvent {
+    pub events: u32,
+    pub data: u64,
+}
+
+impl EpollEvent {
+    /// The identifier of the event, which is the file descriptor it watches
+    pub fn ident(&self) -> u64 {
+        self.data
+    }
+}
+
+
+/// The timeout epoll_wait waits for events
+#[derive(Debug)]
+pub struct Timeout {
+    millis: i32,
+}
+
+
+impl Timeout {
+    pub fn zero() -> Self {
+        Timeout {
+            millis: 0,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Polls the q until an event fired or a second passed
+    fn poll_until_event(q: i32) -> Vec<EpollEvent> {
+        let start = Instant::now();
+        loop {
+            let events = poll_events_from_q(q, &Timeout::zero()).unwrap();
+            if !events.is_empty() || start.elapsed() > Duration::from_secs(1) {
+                return events;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn register_poll_round_trip_test() {
+        let q = create_queue().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let listener_fd = listener.as_raw_fd() as u64;
+
+        put_event_in_queue(q, &create_read_event(listener_fd), &Timeout::zero()).unwrap();
+        assert!(poll_events_from_q(q, &Timeout::zero()).unwrap().is_empty());
+
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let events = poll_until_event(q);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].ident(), listener_fd);
+
+        // the one shot event stays disabled until it is put into the q again
+        assert!(poll_events_from_q(q, &Timeout::zero()).unwrap().is_empty());
+        put_event_in_queue(q, &create_read_event(listener_fd), &Timeout::zero()).unwrap();
+        assert_eq!(poll_until_event(q)[0].ident(), listener_fd);
+
+        // a connected socket can be written to right away
+        let client_fd = client.as_raw_fd() as u64;
+        put_event_in_queue(q, &create_write_event(client_fd), &Timeout::zero()).unwrap();
+        let events = poll_until_event(q);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].ident(), client_fd);
+
+        close_queue(q);
+    }
+}
@@ -31,12 +31,21 @@ extern "C" {
     fn close(d: i32) -> i32;
 }
 
-pub fn create_kqueue() -> Result<i32, String> {
+/// The event type the kqueue backend works with
+pub type InternalEvent = KeventInternal;
+/// The timeout type the kqueue backend works with
+pub type Timeout = Timespec;
+
+pub fn create_queue() -> Result<i32, String> {
     let file_desc = unsafe { kqueue() };
     if file_desc < 0 {
         return Err(String::from("Error creating new k queue"));
     }
-    return Ok(file_desc);
+    Ok(file_desc)
+}
+
+pub fn close_queue(fd: i32) {
+    unsafe { close(fd) };
 }
 
 
@@ -51,16 +60,16 @@ fn create_k_event(filter: i16, listener_fd: u64) -> KeventInternal {
     }
 }
 
-pub fn create_k_read_event(fd: u64) -> KeventInternal {
+pub fn create_read_event(fd: u64) -> KeventInternal {
     create_k_event(EVFILT_READ, fd)
 }
 
-pub fn create_k_write_event(fd: u64) -> KeventInternal {
+pub fn create_write_event(fd: u64) -> KeventInternal {
     create_k_event(EVFILT_WRITE, fd)
 }
 
 
-pub fn put_kevent_in_kqueue(fd: i32, event: &KeventInternal, time_spec: & Timespec) -> Result<(), String> {
+pub fn put_event_in_queue(fd: i32, event: &KeventInternal, time_spec: &Timespec) -> Result<(), String> {
     let worked = unsafe {
         kevent(
             fd,
@@ -77,7 +86,7 @@ pub fn put_kevent_in_kqueue(fd: i32, event: &KeventInternal, time_spec: & Timesp
     Ok(())
 }
 
-pub fn poll_kevents_from_q(fd: i32, timeout: &Timespec) -> Result<Vec<KeventInternal>, String> {
+pub fn poll_events_from_q(fd: i32, timeout: &Timespec) -> Result<Vec<KeventInternal>, String> {
     let mut finished_events: Vec<KeventInternal> = Vec::with_capacity(256);// todo change to proper size/
     let res = unsafe {
         kevent(
@@ -94,7 +103,7 @@ pub fn poll_kevents_from_q(fd: i32, timeout: &Timespec) -> Result<Vec<KeventInte
     };
 
     unsafe { finished_events.set_len(res as usize) };
-    return Ok(finished_events);
+    Ok(finished_events)
 }
 
 
@@ -102,21 +111,28 @@ pub fn poll_kevents_from_q(fd: i32, timeout: &Timespec) -> Result<Vec<KeventInte
 #[derive(Debug, PartialEq)]
 #[repr(C)]
 pub struct KeventInternal {
-    pub ident: uintptr_t,
-    pub filter: int16_t,
-    pub flags: uint16_t,
-    pub fflags: uint32_t,
-    pub data: intptr_t,
-    pub udata: void_ptr,
+    pub ident: UintptrT,
+    pub filter: Int16T,
+    pub flags: Uint16T,
+    pub fflags: Uint32T,
+    pub data: IntptrT,
+    pub udata: VoidPtr,
+}
+
+impl KeventInternal {
+    /// The identifier of the event, which is the file descriptor it watches
+    pub fn ident(&self) -> u64 {
+        self.ident
+    }
 }
 
 
-type uintptr_t = u64;
-type int16_t = i16;
-type uint16_t = u16;
-type uint32_t = u32;
-type intptr_t = i64;
-type void_ptr = u64;
+type UintptrT = u64;
+type Int16T = i16;
+type Uint16T = u16;
+type Uint32T = u32;
+type IntptrT = i64;
+type VoidPtr = u64;
 
 
 #[derive(Debug)]
@@ -130,8 +146,8 @@ pub struct Timespec {
 impl Timespec {
     pub fn zero() -> Self {
         Timespec {
-            tv_sec: 0 as isize,
-            v_nsec: 0 as usize,
+            tv_sec: 0,
+            v_nsec: 0,
         }
     }
 }
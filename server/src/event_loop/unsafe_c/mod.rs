@@ -0,0 +1,14 @@
+//! The OS specific event queue backends.
+//!
+//! Both backends provide the same functions and types,
+//! the one matching the target OS is chosen at compile time.
+
+#[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))]
+mod kqueue;
+#[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))]
+pub use kqueue::*;
+
+#[cfg(target_os = "linux")]
+mod epoll;
+#[cfg(target_os = "linux")]
+pub use epoll::*;
@@ -1,21 +1,194 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fs::read_dir;
-use std::io::{Error as IoError, ErrorKind};
+use std::io::Error as IoError;
 use std::path::{Path, PathBuf};
 use std::{env, fs};
-use std::error::Error;
+use std::sync::Arc;
+use std::time::SystemTime;
+use chrono::{DateTime, Timelike, Utc};
+use sha1_smol::Sha1;
+use crate::{StaticFiles, DynamicFiles};
+use crate::body::Body;
+use crate::disk::DiskFiles;
+use crate::compression::{is_compressible, is_worth_compressing, CompressionMode, Encoding, ENCODINGS};
+use crate::mime::content_type;
 
+/// Wrapper for both static and dynamic files the servers are able to serve
+#[derive(Clone)]
+pub struct ServerFiles {
+    pub static_files: StaticFiles,
+    pub dynamic_files: DynamicFiles,
+    /// whether directories without an index file are served as generated listing
+    pub directory_listing: bool,
+    /// when and how the responses are compressed
+    pub compression: CompressionMode,
+    /// the served directory, if its files are read from disk on demand instead of being kept in memory
+    pub disk_files: Option<Arc<DiskFiles>>,
+}
+
+impl ServerFiles {
+    /// The file stored under the normalised path, looked up in memory first and on disk afterwards
+    pub fn file(&self, path: &str) -> Option<StaticFile> {
+        if let Some(file) = self.static_files().get(path) {
+            return Some(file.clone());
+        }
+        self.disk_files.as_ref()?.file(path)
+    }
+
+    /// Whether any file is stored below the directory, the root always exists
+    pub fn is_directory(&self, dir: &str) -> bool {
+        dir == "/"
+            || self.static_files().keys().any(|path| path.starts_with(dir))
+            || self.disk_files.as_ref().is_some_and(|disk_files| disk_files.is_directory(dir))
+    }
+
+    /// The names of the subdirectories, with a trailing slash, and the names of the files in the directory
+    pub fn directory_entries(&self, dir: &str) -> (BTreeSet<String>, BTreeSet<String>) {
+        let mut directories = BTreeSet::new();
+        let mut file_names = BTreeSet::new();
+        self.static_files().keys()
+            .filter_map(|path| path.strip_prefix(dir))
+            .for_each(|rest| match rest.split_once('/') {
+                Some((directory, _)) => { directories.insert(format!("{}/", directory)); }
+                None => { file_names.insert(rest.to_string()); }
+            });
+        if let Some(disk_files) = &self.disk_files {
+            let (disk_directories, disk_file_names) = disk_files.directory_entries(dir);
+            directories.extend(disk_directories);
+            file_names.extend(disk_file_names);
+        }
+        (directories, file_names)
+    }
+
+    /// The static files as they are right now, unaffected by later reloads
+    pub fn static_files(&self) -> Arc<HashMap<String, StaticFile>> {
+        match self.static_files.read() {
+            Ok(static_files) => Arc::clone(&static_files),
+            Err(poisoned) => Arc::clone(&poisoned.into_inner())
+        }
+    }
+
+    /// Swaps in the reloaded static files, requests already being answered keep the previous ones
+    pub fn replace_static_files(&self, static_files: HashMap<String, StaticFile>) {
+        let static_files = Arc::new(static_files);
+        match self.static_files.write() {
+            Ok(mut current) => *current = static_files,
+            Err(poisoned) => *poisoned.into_inner() = static_files
+        }
+    }
+}
 
-/// Loads all static files into memory
-pub fn load_static_files(dir: &Path) -> Result<HashMap<String, Vec<u8>>, String> {
+/// A served file loaded into memory, together with the validators of its content
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StaticFile {
+    /// the content, shared by all responses sending it
+    pub content: Body,
+    /// the strong entity tag, the quoted sha1 hash of the content
+    pub etag: String,
+    /// the time of the last modification, in whole seconds as http dates have no fractions
+    pub modified: Option<DateTime<Utc>>,
+    /// the content compressed ahead of time, either at startup or by the user as .gz and .br files
+    pub encodings: HashMap<Encoding, Body>,
+}
+
+impl StaticFile {
+    /// Creates the file, computing the hash of the content
+    pub fn new(content: Vec<u8>, modified: Option<SystemTime>) -> Self {
+        let etag = format!("\"{}\"", Sha1::from(&content).digest());
+        Self { content: Body::shared(content), etag, modified: whole_seconds(modified), encodings: HashMap::new() }
+    }
+
+    /// Creates the file sent from disk, without reading it
+    ///
+    /// The entity tag is made of the length and the time of the last modification instead of the hash.
+    pub fn from_disk(file: fs::File, length: u64, modified: Option<SystemTime>) -> Self {
+        let nanos = modified
+            .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |since_epoch| since_epoch.as_nanos());
+        let etag = format!("\"{:x}-{:x}\"", length, nanos);
+        Self { content: Body::file(file, length), etag, modified: whole_seconds(modified), encodings: HashMap::new() }
+    }
+
+    /// The codings the content is available in ahead of time, the preferred first
+    pub fn available_encodings(&self) -> Vec<Encoding> {
+        ENCODINGS.iter().filter(|encoding| self.encodings.contains_key(encoding)).copied().collect()
+    }
+}
+
+
+/// Truncates the time to whole seconds, as http dates have no fractions
+fn whole_seconds(time: Option<SystemTime>) -> Option<DateTime<Utc>> {
+    time.map(DateTime::<Utc>::from)
+        .and_then(|time| time.with_nanosecond(0))
+}
+
+/// Loads all static files into memory, compressing them ahead of time if the mode asks to
+pub fn load_static_files(dir: &Path, compression: CompressionMode) -> Result<HashMap<String, StaticFile>, String> {
     let provided_directory = load_directory(Path::new(&dir))?;
     let static_respources = load_static_resources()?;
 
     let static_files = provided_directory.into_iter().chain(static_respources).collect();
 
+    compress_ahead(static_files, compression)
+}
+
+/// Loads only the files from the static resources directory, when the served directory is read from disk on demand
+pub fn load_resource_files(compression: CompressionMode) -> Result<HashMap<String, StaticFile>, String> {
+    compress_ahead(load_static_resources()?, compression)
+}
+
+/// Compresses the files ahead of time, or attaches their compressed siblings, if the mode asks to
+fn compress_ahead(mut static_files: HashMap<String, StaticFile>, compression: CompressionMode) -> Result<HashMap<String, StaticFile>, String> {
+    match compression {
+        CompressionMode::Precompress => precompress(&mut static_files)?,
+        CompressionMode::Siblings => attach_siblings(&mut static_files),
+        CompressionMode::Off | CompressionMode::OnTheFly => {}
+    }
+
     Ok(static_files)
 }
 
+/// Compresses the files of compressible types with the best level of brotli and gzip
+///
+/// A coding is left out if it does not make the file smaller.
+fn precompress(files: &mut HashMap<String, StaticFile>) -> Result<(), String> {
+    files.iter_mut().try_for_each(|(path, file)| precompress_file(path, file))
+}
+
+/// Compresses the file stored under the path, if its type is compressible
+pub fn precompress_file(path: &str, file: &mut StaticFile) -> Result<(), String> {
+    let content = match file.content.bytes() {
+        Some(content) if is_compressible(&content_type(path)) && is_worth_compressing(content) => content,
+        _ => return Ok(())
+    };
+    for encoding in [Encoding::Brotli, Encoding::Gzip] {
+        let compressed = encoding.compress(content, true)
+            .map_err(|error| format!("Could not compress {}: {}", path, error))?;
+        if compressed.len() < content.len() {
+            file.encodings.insert(encoding, Body::shared(compressed));
+        }
+    }
+    Ok(())
+}
+
+/// Attaches the .gz and .br files to the files they were compressed from, e.g. /app.js.gz to /app.js
+///
+/// The compressed files themselves stay available under their own paths.
+fn attach_siblings(files: &mut HashMap<String, StaticFile>) {
+    let siblings: Vec<(String, Encoding, Body)> = files.iter()
+        .filter_map(|(path, file)| ENCODINGS.iter().find_map(|encoding| {
+            let original = path.strip_suffix(&format!(".{}", encoding.extension()?))?;
+            Some((original.to_string(), *encoding, file.content.clone()))
+        }))
+        .collect();
+
+    for (original, encoding, content) in siblings {
+        if let Some(file) = files.get_mut(&original) {
+            file.encodings.insert(encoding, content);
+        }
+    }
+}
+
 /// Loads the files from the dynamic resources directory into memory
 pub fn load_dynamic_files() -> Result<HashMap<String, String>, String> {
     let current_dir = get_current_dir()?;
@@ -55,30 +228,30 @@ pub fn load_dynamic_files() -> Result<HashMap<String, String>, String> {
 }
 
 /// Loads the files in the path from the filesystem into memory
-fn load_directory(path: &Path) -> Result<HashMap<String, Vec<u8>>, String> {
-    return match get_all_files_in_dir(path) {
+fn load_directory(path: &Path) -> Result<HashMap<String, StaticFile>, String> {
+    match get_all_files_in_dir(path) {
         Err(error) => {
             let err = format!("Could not read files in path {}: {}", path.display(), error);
             Err(err)
         }
         Ok(list) => Ok(list)
-    };
+    }
 }
 
 /// Loads the files from the static resources directory into memory
-fn load_static_resources() -> Result<HashMap<String, Vec<u8>>, String> {
+fn load_static_resources() -> Result<HashMap<String, StaticFile>, String> {
     let current_dir = get_current_dir()?;
     match load_directory(&PathBuf::from(string_from_path(current_dir)? + "/resources/static")) {
-        Ok(ok) => return Ok(ok),
-        Err(err) => return Err("Make sure the resources folder exists in the current directory".to_string())
+        Ok(ok) => Ok(ok),
+        Err(_) => Err("Make sure the resources folder exists in the current directory".to_string())
     }
 }
 
 /// Get all files in a directory.
 ///
 /// Returns a map consisting of all the files in the root level of the provided dir
-/// More specific return a hashmap containing the filename as key and the file content as utf-8 value
-fn get_all_files_in_dir(path: &Path) -> Result<HashMap<String, Vec<u8>>, IoError> {
+/// More specific return a hashmap containing the filename as key and the file with its content as value
+fn get_all_files_in_dir(path: &Path) -> Result<HashMap<String, StaticFile>, IoError> {
     if path.is_dir() {
         read_directory_rec(path)
     } else {
@@ -90,7 +263,7 @@ fn get_all_files_in_dir(path: &Path) -> Result<HashMap<String, Vec<u8>>, IoError
 }
 
 /// Reads a specific file recursively from the path into Memory
-fn read_directory_rec(path: &Path) -> Result<HashMap<String, Vec<u8>>, IoError> {
+fn read_directory_rec(path: &Path) -> Result<HashMap<String, StaticFile>, IoError> {
     let mut result = HashMap::with_capacity(8);
 
     for entry in read_dir(path)? {
@@ -109,19 +282,20 @@ fn read_directory_rec(path: &Path) -> Result<HashMap<String, Vec<u8>>, IoError>
 }
 
 /// Reads a specific file from the path into Memory
-fn read_file(file: &Path) -> Result<(String, Vec<u8>), IoError> {
+fn read_file(file: &Path) -> Result<(String, StaticFile), IoError> {
     let filename = file_or_dir_name(file)?;
     let file_content = std::fs::read(file)?;
-    Ok((filename, file_content))
+    let modified = fs::metadata(file).and_then(|metadata| metadata.modified()).ok();
+    Ok((filename, StaticFile::new(file_content, modified)))
 }
 
 /// Determines wheter the file is a file or directory
 fn file_or_dir_name(file: &Path) -> Result<String, IoError> {
     Ok(file
         .file_name()
-        .ok_or(IoError::new(ErrorKind::Other, "Filename no valid os string"))?
+        .ok_or_else(|| IoError::other("Filename no valid os string"))?
         .to_str()
-        .ok_or(IoError::new(ErrorKind::Other, "Filename is no valid utf-8"))?
+        .ok_or_else(|| IoError::other("Filename is no valid utf-8"))?
         .to_string())
 }
 
@@ -141,3 +315,66 @@ fn get_current_dir() -> Result<PathBuf, String> {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn static_file_test() {
+        let modified = UNIX_EPOCH + Duration::from_millis(784_111_777_500);
+        let file = StaticFile::new(b"abc".to_vec(), Some(modified));
+        assert_eq!(file.etag, "\"a9993e364706816aba3e25717850c26c9cd0d89d\"");
+        assert_eq!(crate::http_date::format(file.modified.unwrap()), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(StaticFile::new(Vec::new(), None).modified, None);
+    }
+
+    #[test]
+    fn replace_static_files_test() {
+        let mut static_files = HashMap::new();
+        static_files.insert("/old.html".to_string(), StaticFile::new(b"old".to_vec(), None));
+        let files = ServerFiles {
+            static_files: Arc::new(std::sync::RwLock::new(Arc::new(static_files))),
+            dynamic_files: Arc::new(HashMap::new()),
+            directory_listing: false,
+            compression: CompressionMode::Off,
+            disk_files: None,
+        };
+        let before = files.static_files();
+
+        let mut reloaded = HashMap::new();
+        reloaded.insert("/new.html".to_string(), StaticFile::new(b"new".to_vec(), None));
+        files.clone().replace_static_files(reloaded);
+
+        assert!(before.contains_key("/old.html"));
+        assert!(files.static_files().contains_key("/new.html"));
+        assert!(!files.static_files().contains_key("/old.html"));
+    }
+
+    #[test]
+    fn precompress_test() {
+        let mut files = HashMap::new();
+        files.insert("/index.html".to_string(), StaticFile::new("<p>Hello</p>".repeat(100).into_bytes(), None));
+        files.insert("/small.html".to_string(), StaticFile::new(b"<p>Hello</p>".to_vec(), None));
+        files.insert("/image.png".to_string(), StaticFile::new(vec![0; 1000], None));
+        precompress(&mut files).unwrap();
+        assert_eq!(files["/index.html"].available_encodings(), vec![Encoding::Brotli, Encoding::Gzip]);
+        assert!(files["/small.html"].encodings.is_empty());
+        assert!(files["/image.png"].encodings.is_empty());
+    }
+
+    #[test]
+    fn attach_siblings_test() {
+        let mut files = HashMap::new();
+        files.insert("/app.js".to_string(), StaticFile::new(b"original".to_vec(), None));
+        files.insert("/app.js.gz".to_string(), StaticFile::new(b"gzip".to_vec(), None));
+        files.insert("/app.js.br".to_string(), StaticFile::new(b"brotli".to_vec(), None));
+        files.insert("/other.js.gz".to_string(), StaticFile::new(b"orphan".to_vec(), None));
+        attach_siblings(&mut files);
+        assert_eq!(files["/app.js"].encodings[&Encoding::Gzip].to_vec().unwrap(), b"gzip");
+        assert_eq!(files["/app.js"].encodings[&Encoding::Brotli].to_vec().unwrap(), b"brotli");
+        assert!(files["/app.js.gz"].encodings.is_empty());
+        assert_eq!(files.len(), 4);
+    }
+}
@@ -0,0 +1,49 @@
+//! Module formatting and parsing the dates used in http headers
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+/// The preferred format of http dates, e.g. Sun, 06 Nov 1994 08:49:37 GMT
+const IMF_FIXDATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+/// The obsolete formats clients may still send, e.g. Sunday, 06-Nov-94 08:49:37 GMT and Sun Nov  6 08:49:37 1994
+const OBSOLETE_FORMATS: [&str; 2] = ["%A, %d-%b-%y %H:%M:%S GMT", "%a %b %e %H:%M:%S %Y"];
+
+/// Formats the time as http date
+pub fn format(time: DateTime<Utc>) -> String {
+    time.format(IMF_FIXDATE).to_string()
+}
+
+/// The current time as http date
+pub fn now() -> String {
+    format(Utc::now())
+}
+
+/// Parses a http date in the preferred or one of the obsolete formats
+pub fn parse(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    std::iter::once(IMF_FIXDATE).chain(OBSOLETE_FORMATS)
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .map(|time| time.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn format_test() {
+        let time = Utc.with_ymd_and_hms(1994, 11, 6, 8, 49, 37).unwrap();
+        assert_eq!(format(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+
+    #[test]
+    fn parse_test() {
+        let time = Utc.with_ymd_and_hms(1994, 11, 6, 8, 49, 37).unwrap();
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+        assert_eq!(parse("Sunday, 06-Nov-94 08:49:37 GMT"), Some(time));
+        assert_eq!(parse("Sun Nov  6 08:49:37 1994"), Some(time));
+        assert_eq!(parse(&format(time)), Some(time));
+        assert_eq!(parse("yesterday"), None);
+        assert_eq!(parse(""), None);
+    }
+}
@@ -1,39 +1,85 @@
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
 use std::thread;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 mod threaded;
 mod single_thread;
 mod event_loop;
 mod request;
+mod uri;
+mod reader;
 mod response;
+mod body;
 mod file;
+mod directory;
+mod mime;
+mod http_date;
+mod range;
+mod compression;
+mod watch;
+mod cache;
+mod disk;
+mod connection;
+mod shutdown;
 mod cli;
+#[cfg(test)]
+mod test_client;
 
 use colored::Colorize;
-use crate::file::{load_dynamic_files, load_static_files};
+use crate::disk::DiskFiles;
+use crate::file::{load_dynamic_files, load_resource_files, load_static_files, ServerFiles, StaticFile};
 
 /// Wrapper for all static server files. As in the directory provided by the user, as well as from the resources directory
-type StaticFiles = Arc<HashMap<String, Vec<u8>>>;
+///
+/// The inner map is replaced as a whole when the directory is reloaded, requests keep the map they started with.
+type StaticFiles = Arc<RwLock<Arc<HashMap<String, StaticFile>>>>;
 /// Wrapper for all dynamic server files from the resources directory
 type DynamicFiles = Arc<HashMap<String, String>>;
-/// Wrapper for the fixed length byte buffer
-type Buffer = [u8; 2048];
 
 /// Starts all the webservers depending on the users input
 fn main() {
-    let (ip, port, dir, threads, type_) = cli::start_cli();
+    let cli::Config { ip, port, dir, pool, type_, limits, keep_alive, directory_listing, mime_types, compression, watch, disk_cache, tls, shutdown_timeout } = cli::start_cli();
 
     println!("Serving directory: {}", dir.cyan());
 
-    let static_files = match load_static_files(Path::new(&dir)) {
+    if let Some(mime_types) = mime_types {
+        if let Err(error) = mime::load_overrides(Path::new(&mime_types)) {
+            println!("{}", error);
+            return;
+        }
+    }
+
+    let tls = match tls.map(|(cert, key)| connection::load_tls_config(Path::new(&cert), Path::new(&key))) {
+        Some(Ok(tls)) => Some(tls),
+        Some(Err(error)) => {
+            println!("{}", error);
+            return;
+        }
+        None => None
+    };
+
+    let static_files = match disk_cache {
+        Some(_) => load_resource_files(compression),
+        None => load_static_files(Path::new(&dir), compression)
+    };
+    let static_files = match static_files {
         Ok(static_files) => Arc::new(static_files),
         Err(error) => {
             println!("{}",error);
             return;
         }
     };
+    println!("Successfully read dir in memory: {:#?}", &static_files.keys());
+
+    let disk_files = match disk_cache.map(|cache_size| DiskFiles::new(Path::new(&dir), cache_size, compression)) {
+        Some(Ok(disk_files)) => Some(Arc::new(disk_files)),
+        Some(Err(error)) => {
+            println!("{}", error);
+            return;
+        }
+        None => None
+    };
 
     let dynamic_files = match load_dynamic_files() {
         Ok(dynamic_files) => Arc::new(dynamic_files),
@@ -43,35 +89,54 @@ fn main() {
         }
     };
 
-    println!("Successfully read dir in memory: {:#?}", &static_files.keys());
+    let static_files = Arc::new(RwLock::new(static_files));
+    let files = ServerFiles { static_files, dynamic_files, directory_listing, compression, disk_files };
+    if let Some(cache_size) = disk_cache {
+        println!("Serving {} from disk with a cache of {} bytes", dir.cyan(), cache_size);
+        if watch.is_some() {
+            println!("Files served from disk are always up to date, the directory is not watched");
+        }
+    } else if let Some(interval) = watch {
+        watch::start_watching(PathBuf::from(&dir), interval, files.clone());
+        println!("Watching {} for changes every {}ms", dir.cyan(), interval.as_millis());
+    }
+    if tls.is_some() {
+        println!("Connections are encrypted with TLS, use https://");
+    }
+    if let Err(error) = shutdown::install(shutdown_timeout) {
+        println!("{}", error);
+        return;
+    }
     println!("Starting the webserver/s!");
 
     match &type_[..] {
         "threaded" => {
             println!("Server is a {} server\nServer is listening on {}:{}",
                      type_.cyan(), ip.to_string().cyan(), port.to_string().cyan());
-            threaded::start_server(ip, port, threads, static_files, dynamic_files);
+            threaded::start_server(ip, port, pool, files, limits, keep_alive, tls);
         }
         "event_loop" => {
             println!("Server is a {} server\n Server is listening on {}:{}",
                      type_.cyan(), ip.to_string().cyan(), port.to_string().cyan());
-            event_loop::start_server(ip, port, static_files)
+            event_loop::start_server(ip, port, files, limits, keep_alive, tls)
         }
         "single_thread" => {
             println!("Server is a {} server\n Server is listening on {}:{}",
                      type_.cyan(), ip.to_string().cyan(), port.to_string().cyan());
-            single_thread::start_server(ip, port, static_files);//todo
+            single_thread::start_server(ip, port, files, limits, keep_alive, tls);//todo
         }
         _ => {
             let ip_t = ip.clone();
             let port_t = port + 1;
-            let dir_t = static_files.clone();
-            thread::spawn(move || threaded::start_server(ip_t, port_t, threads, dir_t, dynamic_files));
+            let files_t = files.clone();
+            let tls_t = tls.clone();
+            let threaded = thread::spawn(move || threaded::start_server(ip_t, port_t, pool, files_t, limits, keep_alive, tls_t));
 
             let ip_e = ip.clone();
             let port_e = port + 2;
-            let dir_e = static_files.clone();
-            thread::spawn(move || event_loop::start_server(ip_e, port_e, dir_e));
+            let files_e = files.clone();
+            let tls_e = tls.clone();
+            let event_loop = thread::spawn(move || event_loop::start_server(ip_e, port_e, files_e, limits, keep_alive, tls_e));
 
             println!("Starting all servers\n\
                       Threaded server is listening on {ip}:{port_t}\n\
@@ -82,7 +147,12 @@ fn main() {
                      port_e = (port_e).to_string().cyan(),
                      port = (port).to_string().cyan());
 
-            single_thread::start_server(ip, port, static_files);
+            single_thread::start_server(ip, port, files, limits, keep_alive, tls);
+            let _ = threaded.join();
+            let _ = event_loop.join();
         }
     };
+    if shutdown::is_requested() {
+        println!("Shut down the webserver/s");
+    }
 }
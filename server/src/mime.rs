@@ -0,0 +1,151 @@
+//! Module mapping the extensions of the served files to their media types
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// The media type of files without or with an unknown extension
+const DEFAULT_TYPE: &str = "application/octet-stream";
+
+/// The media types provided by the user, replacing the builtin ones
+static OVERRIDES: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+/// Loads the media types from a file in the format of mime.types, e.g.
+///
+/// ```text
+/// # comment
+/// text/markdown   md markdown
+/// ```
+///
+/// The types apply to all servers, they can only be loaded once at startup.
+pub fn load_overrides(path: &Path) -> Result<(), String> {
+    let content = fs::read_to_string(path)
+        .map_err(|error| format!("Could not read media types from {}: {}", path.display(), error))?;
+    OVERRIDES.set(parse_overrides(&content))
+        .map_err(|_| "Media types have already been loaded.".to_string())
+}
+
+/// Parses the lines of a mime.types file into a map from extension to media type
+fn parse_overrides(content: &str) -> HashMap<String, String> {
+    let mut overrides = HashMap::new();
+    content.lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .for_each(|line| {
+            let mut words = line.split_whitespace();
+            if let Some(media_type) = words.next() {
+                words.for_each(|extension| {
+                    overrides.insert(extension.to_ascii_lowercase(), media_type.to_string());
+                });
+            }
+        });
+    overrides
+}
+
+/// Returns the content type of the file, including the charset for text types
+///
+/// Files without an extension, as /stats, get the default type instead of being guessed.
+pub fn content_type(file: &str) -> String {
+    let extension = Path::new(file).extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_ascii_lowercase);
+    let media_type = extension
+        .and_then(|extension| overridden_type(&extension).or_else(|| builtin_type(&extension)))
+        .unwrap_or(DEFAULT_TYPE);
+
+    if has_charset(media_type) {
+        format!("{}; charset=utf-8", media_type)
+    } else {
+        media_type.to_string()
+    }
+}
+
+/// The media type the user provided for the extension
+fn overridden_type(extension: &str) -> Option<&'static str> {
+    OVERRIDES.get()?.get(extension).map(String::as_str)
+}
+
+/// The builtin media type of the extension, covering the common web types
+fn builtin_type(extension: &str) -> Option<&'static str> {
+    let media_type = match extension {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "txt" => "text/plain",
+        "csv" => "text/csv",
+        "md" => "text/markdown",
+        "json" | "map" => "application/json",
+        "webmanifest" => "application/manifest+json",
+        "xml" => "application/xml",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "bmp" => "image/bmp",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "oga" => "audio/ogg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "ogv" => "video/ogg",
+        _ => return None
+    };
+    Some(media_type)
+}
+
+/// Whether the media type is textual and has no parameters yet, so the charset is added
+fn has_charset(media_type: &str) -> bool {
+    !media_type.contains(';') && (media_type.starts_with("text/") || matches!(media_type,
+        "application/json" | "application/manifest+json" | "application/xml" | "image/svg+xml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_type_test() {
+        assert_eq!(content_type("/index.html"), "text/html; charset=utf-8");
+        assert_eq!(content_type("/app.js"), "text/javascript; charset=utf-8");
+        assert_eq!(content_type("/data.json"), "application/json; charset=utf-8");
+        assert_eq!(content_type("/map.png"), "image/png");
+        assert_eq!(content_type("/photo.JPG"), "image/jpeg");
+        assert_eq!(content_type("/logo.svg"), "image/svg+xml; charset=utf-8");
+        assert_eq!(content_type("/favicon.ico"), "image/x-icon");
+        assert_eq!(content_type("/font.woff2"), "font/woff2");
+        assert_eq!(content_type("/module.wasm"), "application/wasm");
+        assert_eq!(content_type("/video.mp4"), "video/mp4");
+        assert_eq!(content_type("/paper.pdf"), "application/pdf");
+    }
+
+    #[test]
+    fn content_type_without_extension_test() {
+        assert_eq!(content_type("/stats"), DEFAULT_TYPE);
+        assert_eq!(content_type("/"), DEFAULT_TYPE);
+        assert_eq!(content_type(""), DEFAULT_TYPE);
+        assert_eq!(content_type("/.hidden"), DEFAULT_TYPE);
+        assert_eq!(content_type("/archive.unknown"), DEFAULT_TYPE);
+    }
+
+    #[test]
+    fn parse_overrides_test() {
+        let overrides = parse_overrides("# comment\ntext/x-rust rs RLIB # trailing\n\napplication/x-empty\nimage/png   png\n");
+        assert_eq!(overrides.get("rs").unwrap(), "text/x-rust");
+        assert_eq!(overrides.get("rlib").unwrap(), "text/x-rust");
+        assert_eq!(overrides.get("png").unwrap(), "image/png");
+        assert_eq!(overrides.len(), 3);
+    }
+}
@@ -0,0 +1,127 @@
+//! Module parsing the byte ranges of the Range header
+
+use std::ops::Range;
+
+/// The most ranges served in one response, requests with more ranges get the whole file
+const MAX_RANGES: usize = 16;
+
+/// The parts of the file the client requested
+#[derive(Debug, Eq, PartialEq)]
+pub enum ByteRanges {
+    /// the whole file, as the header is missing, invalid or uses another unit
+    Full,
+    /// the satisfiable ranges, in the order they were requested
+    Partial(Vec<Range<usize>>),
+    /// none of the ranges lies within the file
+    Unsatisfiable,
+}
+
+/// Parses the Range header for a file of the length
+///
+/// E.g. bytes=0-499, bytes=500-, bytes=-500 or bytes=0-0,-1
+/// Ranges exceeding the file are shortened to its end, ranges starting after it are left out.
+pub fn parse_ranges(header: &str, length: usize) -> ByteRanges {
+    let specs = match header.trim().split_once('=') {
+        Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+        _ => return ByteRanges::Full
+    };
+
+    let specs: Vec<&str> = specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()).collect();
+    if specs.is_empty() {
+        return ByteRanges::Full;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs {
+        match parse_spec(spec, length) {
+            Some(Some(range)) => ranges.push(range),
+            Some(None) => {}
+            None => return ByteRanges::Full
+        }
+    }
+
+    if ranges.len() > MAX_RANGES {
+        ByteRanges::Full
+    } else if ranges.is_empty() {
+        ByteRanges::Unsatisfiable
+    } else {
+        ByteRanges::Partial(ranges)
+    }
+}
+
+/// Parses a single range, returning None if it is invalid and Some(None) if it is not satisfiable
+fn parse_spec(spec: &str, length: usize) -> Option<Option<Range<usize>>> {
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    if first.is_empty() {
+        let suffix = last.parse::<usize>().ok()?;
+        if suffix == 0 || length == 0 {
+            return Some(None);
+        }
+        return Some(Some(length - suffix.min(length)..length));
+    }
+
+    let first = first.parse::<usize>().ok()?;
+    let last = match last {
+        "" => None,
+        last => Some(last.parse::<usize>().ok()?)
+    };
+    if last.is_some_and(|last| last < first) {
+        return None;
+    }
+    if first >= length {
+        return Some(None);
+    }
+    let end = last.map_or(length, |last| (last + 1).min(length));
+    Some(Some(first..end))
+}
+
+/// Formats the range for the Content-Range header, e.g. bytes 0-499/1234
+pub fn content_range(range: &Range<usize>, length: usize) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(ranges: &[(usize, usize)]) -> ByteRanges {
+        ByteRanges::Partial(ranges.iter().map(|&(start, end)| start..end).collect())
+    }
+
+    #[test]
+    fn parse_ranges_test() {
+        assert_eq!(parse_ranges("bytes=0-499", 1000), partial(&[(0, 500)]));
+        assert_eq!(parse_ranges("bytes=500-", 1000), partial(&[(500, 1000)]));
+        assert_eq!(parse_ranges("bytes=-200", 1000), partial(&[(800, 1000)]));
+        assert_eq!(parse_ranges("bytes=-2000", 1000), partial(&[(0, 1000)]));
+        assert_eq!(parse_ranges("bytes=900-1999", 1000), partial(&[(900, 1000)]));
+        assert_eq!(parse_ranges("Bytes = 0-0, -1", 1000), partial(&[(0, 1), (999, 1000)]));
+        assert_eq!(parse_ranges("bytes=0-1,2000-3000,5-9", 1000), partial(&[(0, 2), (5, 10)]));
+    }
+
+    #[test]
+    fn parse_ranges_unsatisfiable_test() {
+        assert_eq!(parse_ranges("bytes=1000-", 1000), ByteRanges::Unsatisfiable);
+        assert_eq!(parse_ranges("bytes=-0", 1000), ByteRanges::Unsatisfiable);
+        assert_eq!(parse_ranges("bytes=0-", 0), ByteRanges::Unsatisfiable);
+    }
+
+    #[test]
+    fn parse_ranges_ignored_test() {
+        assert_eq!(parse_ranges("items=0-1", 1000), ByteRanges::Full);
+        assert_eq!(parse_ranges("bytes=5-1", 1000), ByteRanges::Full);
+        assert_eq!(parse_ranges("bytes=a-b", 1000), ByteRanges::Full);
+        assert_eq!(parse_ranges("bytes=0-1,x", 1000), ByteRanges::Full);
+        assert_eq!(parse_ranges("0-1", 1000), ByteRanges::Full);
+        assert_eq!(parse_ranges("bytes= , ", 1000), ByteRanges::Full);
+        let many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(parse_ranges(&many, 1000), ByteRanges::Full);
+    }
+
+    #[test]
+    fn content_range_test() {
+        assert_eq!(content_range(&(0..500), 1234), "bytes 0-499/1234");
+        assert_eq!(content_range(&(1233..1234), 1234), "bytes 1233-1233/1234");
+    }
+}
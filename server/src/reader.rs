@@ -0,0 +1,302 @@
+use std::io::{self, Read, ErrorKind};
+use std::time::{Duration, Instant};
+use crate::connection::Connection;
+use crate::request::{parse_request_resuming, ChunkedProgress, InvalidRequest, Request, RequestLimits};
+use crate::shutdown;
+
+/// The amount of bytes read from a stream at once
+const READ_CHUNK_SIZE: usize = 4096;
+/// How often idle persistent connections check whether the servers shut down
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The reason why no request could be read from a connection
+#[derive(Debug)]
+pub enum ReadError {
+    /// The connection was closed or broke, no response can be sent
+    Closed,
+    /// The request is invalid or did not arrive in time, an error response should be sent
+    Invalid(InvalidRequest),
+}
+
+/// A growable buffer collecting the bytes of a connection until a whole request arrived
+///
+/// Used by all servers, either filled blocking with `read_request`
+/// or nonblocking by calling `fill_from` whenever the stream is readable.
+/// The buffer lives as long as the connection, so bytes of pipelined requests are kept.
+#[derive(Debug)]
+pub struct RequestBuffer {
+    data: Vec<u8>,
+    limits: RequestLimits,
+    deadline: Instant,
+    idle: bool,
+    requests_taken: usize,
+    /// the chunks of the body of the next request decoded so far
+    chunked: ChunkedProgress,
+}
+
+impl RequestBuffer {
+    /// Creates an empty buffer, the read timeout starts now
+    pub fn new(limits: RequestLimits) -> Self {
+        Self {
+            data: Vec::with_capacity(READ_CHUNK_SIZE),
+            limits,
+            deadline: Instant::now() + limits.read_timeout,
+            idle: false,
+            requests_taken: 0,
+            chunked: ChunkedProgress::default(),
+        }
+    }
+
+    /// Reads the available bytes from the stream once into the buffer
+    ///
+    /// Returns the amount of bytes read, 0 means the stream was closed.
+    /// The first bytes after an idle period start the read timeout of the next request.
+    pub fn fill_from<R: Read>(&mut self, stream: &mut R) -> std::io::Result<usize> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let bytes_read = stream.read(&mut chunk)?;
+        if self.idle && bytes_read > 0 {
+            self.idle = false;
+            self.deadline = Instant::now() + self.limits.read_timeout;
+        }
+        self.data.extend_from_slice(&chunk[..bytes_read]);
+        Ok(bytes_read)
+    }
+
+    /// Takes the next complete request out of the buffer
+    ///
+    /// Returns None while parts of the request are still missing.
+    /// Bytes following the request stay in the buffer.
+    /// The chunks of a chunked body are only decoded once, however often the buffer is filled.
+    pub fn next_request(&mut self) -> Result<Option<Request>, InvalidRequest> {
+        match parse_request_resuming(&self.data, self.limits, &mut self.chunked) {
+            Ok(Some((request, length))) => {
+                self.data.drain(..length);
+                self.requests_taken += 1;
+                self.chunked = ChunkedProgress::default();
+                Ok(Some(request))
+            }
+            Ok(None) => Ok(None),
+            Err(error) => {
+                self.chunked = ChunkedProgress::default();
+                Err(error)
+            }
+        }
+    }
+
+    /// Waits for the next request of a persistent connection
+    ///
+    /// The connection may stay idle for the keep-alive timeout,
+    /// unless parts of the next request are already buffered.
+    pub fn wait_for_next(&mut self, idle_timeout: Duration) {
+        if self.is_empty() {
+            self.idle = true;
+            self.deadline = Instant::now() + idle_timeout;
+        } else {
+            self.deadline = Instant::now() + self.limits.read_timeout;
+        }
+    }
+
+    /// The amount of requests taken out of the buffer, which is the amount of requests on the connection
+    pub fn requests_taken(&self) -> usize {
+        self.requests_taken
+    }
+
+    /// Whether the received bytes start with the prefix, e.g. a preface of another protocol
+    pub fn starts_with(&self, prefix: &[u8]) -> bool {
+        self.data.starts_with(prefix)
+    }
+
+    /// Ends reading requests, returning the bytes received after the last request
+    pub fn into_unparsed(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Whether no bytes of a request have been received yet
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Whether the request did not arrive within the read or keep-alive timeout
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    /// The error to report when the request did not arrive in time
+    ///
+    /// A connection that never sent anything is closed silently.
+    pub fn timeout_error(&self) -> ReadError {
+        if self.is_empty() {
+            ReadError::Closed
+        } else {
+            ReadError::Invalid(InvalidRequest::timeout())
+        }
+    }
+}
+
+/// A blocking stream whose reads wait at most for a timeout
+pub trait TimedRead: Read {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl TimedRead for Connection {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Connection::set_read_timeout(self, timeout)
+    }
+}
+
+/// Reads the next request from the stream, blocking until it arrived completely
+///
+/// Gives up when the request did not arrive within the read timeout of the limits.
+/// Idle persistent connections are closed once the servers shut down.
+pub fn read_request<S: TimedRead>(stream: &mut S, buffer: &mut RequestBuffer) -> Result<Request, ReadError> {
+    loop {
+        if let Some(request) = buffer.next_request().map_err(ReadError::Invalid)? {
+            return Ok(request);
+        }
+        if buffer.idle && shutdown::is_requested() {
+            return Err(ReadError::Closed);
+        }
+
+        let remaining = match buffer.deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) if !remaining.is_zero() && buffer.idle => remaining.min(IDLE_CHECK_INTERVAL),
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => return Err(buffer.timeout_error())
+        };
+        if stream.set_read_timeout(Some(remaining)).is_err() {
+            return Err(ReadError::Closed);
+        }
+
+        match buffer.fill_from(stream) {
+            Ok(0) => return Err(ReadError::Closed),
+            Ok(_) => {}
+            Err(error) => match error.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted => {}
+                _ => return Err(ReadError::Closed)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+    use crate::request::InvalidRequestKind;
+
+    const LIMITS: RequestLimits = RequestLimits { max_body_size: 64, max_header_size: 128, read_timeout: Duration::from_millis(100) };
+
+    /// A stream returning one chunk per read, and waiting for the read timeout once it ran out of chunks
+    struct MockStream {
+        chunks: VecDeque<Vec<u8>>,
+        timeout: Cell<Option<Duration>>,
+        reads: usize,
+    }
+
+    impl MockStream {
+        fn new(chunks: &[&[u8]]) -> Self {
+            Self { chunks: chunks.iter().map(|chunk| chunk.to_vec()).collect(), timeout: Cell::new(None), reads: 0 }
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            match self.chunks.pop_front() {
+                Some(chunk) => {
+                    buf[..chunk.len()].copy_from_slice(&chunk);
+                    Ok(chunk.len())
+                }
+                None => {
+                    std::thread::sleep(self.timeout.get().unwrap());
+                    Err(ErrorKind::WouldBlock.into())
+                }
+            }
+        }
+    }
+
+    impl TimedRead for MockStream {
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            self.timeout.set(timeout);
+            Ok(())
+        }
+    }
+
+    fn invalid_kind(result: Result<Request, ReadError>) -> InvalidRequestKind {
+        match result {
+            Err(ReadError::Invalid(error)) => error.kind(),
+            other => panic!("Expected an invalid request, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn request_split_across_reads_test() {
+        let mut stream = MockStream::new(&[b"GET /hel", b"lo HTTP/1.1\r\nHost: local", b"host\r\n", b"\r\n"]);
+        let mut buffer = RequestBuffer::new(LIMITS);
+        let request = read_request(&mut stream, &mut buffer).unwrap();
+        assert_eq!(request.request_identifiers.path, "/hello");
+        assert_eq!(request.get_header("Host"), Some("localhost"));
+        assert_eq!(stream.reads, 4);
+        assert_eq!(buffer.requests_taken(), 1);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn header_too_large_test() {
+        let head = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "x".repeat(LIMITS.max_header_size));
+        let mut stream = MockStream::new(&[head.as_bytes()]);
+        let result = read_request(&mut stream, &mut RequestBuffer::new(LIMITS));
+        assert_eq!(invalid_kind(result), InvalidRequestKind::HeaderTooLarge);
+
+        // the limit applies while the head is still incomplete as well
+        let mut stream = MockStream::new(&[&[b'a'; 100], &[b'a'; 100]]);
+        let result = read_request(&mut stream, &mut RequestBuffer::new(LIMITS));
+        assert_eq!(invalid_kind(result), InvalidRequestKind::HeaderTooLarge);
+    }
+
+    #[test]
+    fn read_timeout_test() {
+        let mut stream = MockStream::new(&[b"GET / HTTP/1.1\r\n"]);
+        let mut buffer = RequestBuffer::new(LIMITS);
+        let start = Instant::now();
+        assert_eq!(invalid_kind(read_request(&mut stream, &mut buffer)), InvalidRequestKind::Timeout);
+        assert!(start.elapsed() >= LIMITS.read_timeout);
+        assert!(start.elapsed() < LIMITS.read_timeout * 5);
+        assert!(buffer.is_expired());
+
+        // a connection that never sent anything is closed without a response
+        let mut stream = MockStream::new(&[]);
+        assert!(matches!(read_request(&mut stream, &mut RequestBuffer::new(LIMITS)), Err(ReadError::Closed)));
+    }
+
+    #[test]
+    fn idle_timeout_test() {
+        let mut stream = MockStream::new(&[b"GET / HTTP/1.1\r\n\r\n"]);
+        let mut buffer = RequestBuffer::new(LIMITS);
+        read_request(&mut stream, &mut buffer).unwrap();
+
+        // idle connections wait in slices, so they notice a shutdown in time
+        let idle_timeout = IDLE_CHECK_INTERVAL * 3;
+        buffer.wait_for_next(idle_timeout);
+        let start = Instant::now();
+        assert!(matches!(read_request(&mut stream, &mut buffer), Err(ReadError::Closed)));
+        assert!(start.elapsed() >= idle_timeout);
+        assert!(stream.reads >= 4);
+    }
+
+    #[test]
+    fn pipelined_requests_test() {
+        let mut stream = MockStream::new(&[b"GET /first HTTP/1.1\r\n\r\nPOST /second HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel", b"lo"]);
+        let mut buffer = RequestBuffer::new(LIMITS);
+        assert_eq!(read_request(&mut stream, &mut buffer).unwrap().request_identifiers.path, "/first");
+        assert!(!buffer.is_empty());
+        assert!(buffer.starts_with(b"POST /second"));
+
+        buffer.wait_for_next(Duration::from_secs(1));
+        let second = read_request(&mut stream, &mut buffer).unwrap();
+        assert_eq!(second.request_identifiers.path, "/second");
+        assert_eq!(second.body, b"hello");
+        assert_eq!(buffer.requests_taken(), 2);
+        assert!(buffer.into_unparsed().is_empty());
+    }
+}
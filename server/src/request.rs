@@ -1,41 +1,164 @@
 use std::collections::HashMap;
 use std::error;
+use std::time::Duration;
 use core::fmt;
+use crate::uri::{normalize_path, QueryParams};
 
-type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+type Result<T> = std::result::Result<T, InvalidRequest>;
 
 /// The object used in all the servers, to represent the http request.
 #[derive(Debug)]
 pub struct Request {
     pub request_identifiers: RequestIdentifiers,
     pub headers: HashMap<String, String>,
-    pub body: String,
+    pub body: Vec<u8>,
+    /// the parameters of the query string, percent-decoded
+    pub query_params: QueryParams,
 }
 
-/// The identifier for the request, containing http method, version and path
+/// The identifier for the request, containing http method, version, path and the raw query string
 ///
-/// E.g. GET, Http1.1, /example.html
+/// E.g. GET, Http1.1, /example.html, limit=50
 #[derive(Debug)]
 #[derive(Eq, PartialEq)]
 pub struct RequestIdentifiers {
     pub method: RequestType,
     pub path: String,
+    pub query: Option<String>,
     pub version: String,
 }
 
 /// The http request type
 ///
 ///E.g. GET
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[derive(Eq, PartialEq)]
 pub enum RequestType {
     Get,
+    Head,
     Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+}
+
+impl RequestType {
+    /// Reads the request type from the method token of the request line, which is case sensitive
+    fn parse(method: &str) -> Result<Self> {
+        match method {
+            "GET" => Ok(RequestType::Get),
+            "HEAD" => Ok(RequestType::Head),
+            "POST" => Ok(RequestType::Post),
+            "PUT" => Ok(RequestType::Put),
+            "DELETE" => Ok(RequestType::Delete),
+            "CONNECT" => Ok(RequestType::Connect),
+            "OPTIONS" => Ok(RequestType::Options),
+            "TRACE" => Ok(RequestType::Trace),
+            "PATCH" => Ok(RequestType::Patch),
+            _ => Err(InvalidRequest::not_implemented(method))
+        }
+    }
+
+    /// The method token of the request type, as sent in the request line
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RequestType::Get => "GET",
+            RequestType::Head => "HEAD",
+            RequestType::Post => "POST",
+            RequestType::Put => "PUT",
+            RequestType::Delete => "DELETE",
+            RequestType::Connect => "CONNECT",
+            RequestType::Options => "OPTIONS",
+            RequestType::Trace => "TRACE",
+            RequestType::Patch => "PATCH",
+        }
+    }
+}
+
+/// The limits a request has to stay within to be accepted by the servers
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    pub max_body_size: usize,
+    pub max_header_size: usize,
+    pub read_timeout: Duration,
+}
+
+/// The settings for persistent connections, serving multiple requests per connection
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAlive {
+    /// how long an idle connection is kept open waiting for the next request, zero disables keep-alive
+    pub timeout: Duration,
+    /// how many requests are served over one connection before it is closed
+    pub max_requests: usize,
 }
 
+impl KeepAlive {
+    /// Whether the connection should stay open after responding to the request
+    ///
+    /// handled is the amount of requests already handled on the connection, including this one.
+    pub fn allows(&self, request: &Request, handled: usize) -> bool {
+        !self.timeout.is_zero() && handled < self.max_requests && request.wants_keep_alive()
+    }
+}
+
+/// The reason why a request could not be accepted
+///
+/// E.g. PayloadTooLarge - the body exceeds the configured limit
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum InvalidRequestKind {
+    BadRequest,
+    PayloadTooLarge,
+    HeaderTooLarge,
+    Timeout,
+    NotImplemented,
+    VersionNotSupported,
+}
+
+/// The error returned when a request could not be parsed
 #[derive(Debug)]
-struct InvalidRequest {
-    message: String
+pub struct InvalidRequest {
+    message: String,
+    kind: InvalidRequestKind,
+}
+
+impl InvalidRequest {
+    /// Creates an error for a malformed request
+    fn bad_request(message: &str) -> Self {
+        Self { message: message.to_string(), kind: InvalidRequestKind::BadRequest }
+    }
+
+    /// Creates an error for a request whose body exceeds the limit
+    pub fn payload_too_large(limit: usize) -> Self {
+        Self { message: format!("Request body exceeds the limit of {} bytes.", limit), kind: InvalidRequestKind::PayloadTooLarge }
+    }
+
+    /// Creates an error for a request whose head exceeds the limit
+    pub fn header_too_large(limit: usize) -> Self {
+        Self { message: format!("Request head exceeds the limit of {} bytes.", limit), kind: InvalidRequestKind::HeaderTooLarge }
+    }
+
+    /// Creates an error for a request using a method the servers do not know
+    fn not_implemented(method: &str) -> Self {
+        Self { message: format!("Request method {} is not implemented.", method), kind: InvalidRequestKind::NotImplemented }
+    }
+
+    /// Creates an error for a request using a protocol version the servers do not speak
+    fn version_not_supported(version: &str) -> Self {
+        Self { message: format!("HTTP version {} is not supported.", version), kind: InvalidRequestKind::VersionNotSupported }
+    }
+
+    /// Creates an error for a request that did not arrive in time
+    pub fn timeout() -> Self {
+        Self { message: "Request was not received in time.".to_string(), kind: InvalidRequestKind::Timeout }
+    }
+
+    /// The reason why the request could not be accepted
+    pub fn kind(&self) -> InvalidRequestKind {
+        self.kind
+    }
 }
 
 impl fmt::Display for InvalidRequest {
@@ -45,74 +168,243 @@ impl fmt::Display for InvalidRequest {
     }
 }
 
-impl error::Error for InvalidRequest {
-    /// setting the error message.
-    fn description(&self) -> &str {
-        &self.message
+impl error::Error for InvalidRequest {}
+
+impl Request {
+    /// Returns the value of the header, ignoring the case of the header name
+    pub fn get_header(&self, key: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.trim())
     }
+
+    /// Builds an HTTP/2 request, whose method and target arrive as header fields instead of a request line
+    pub fn from_fields(method: &str, target: &str, headers: HashMap<String, String>, body: Vec<u8>) -> Result<Self> {
+        let method = RequestType::parse(method)?;
+        let (path, query) = split_target(target)?;
+        let query_params = query.as_deref().map(QueryParams::parse).unwrap_or_default();
+        Ok(Request {
+            request_identifiers: RequestIdentifiers { method, path, query, version: "HTTP/2.0".to_string() },
+            headers,
+            body,
+            query_params,
+        })
+    }
+
+    /// Whether the client wants to keep the connection open after this request
+    ///
+    /// HTTP/1.1 connections are persistent unless the client sends Connection: close,
+    /// HTTP/1.0 connections only if the client sends Connection: keep-alive.
+    pub fn wants_keep_alive(&self) -> bool {
+        let has_option = |option: &str| self.get_header("Connection")
+            .map(|value| value.split(',').any(|token| token.trim().eq_ignore_ascii_case(option)))
+            .unwrap_or(false);
+
+        match &self.request_identifiers.version[..] {
+            "HTTP/1.1" => !has_option("close"),
+            "HTTP/1.0" => has_option("keep-alive"),
+            _ => false
+        }
+    }
+}
+
+/// Parses the first request in the byte buffer into Request object, if it has been received completely.
+///
+/// The head of the request has to be valid utf-8,
+/// the body is read according to the Content-Length or chunked Transfer-Encoding header and kept as raw bytes.
+/// Returns the request together with the number of bytes it occupies in the buffer,
+/// or None while parts of the request are still missing.
+#[cfg(test)]
+pub fn parse_request(buffer: &[u8], limits: RequestLimits) -> Result<Option<(Request, usize)>> {
+    parse_request_resuming(buffer, limits, &mut ChunkedProgress::default())
 }
 
-/// Parses the byte Vector stream into Request object.
-pub fn parse_request(buffer: Vec<u8>) -> Result<Request> {
-    let raw_request = match String::from_utf8(buffer) {
+/// Parses the first request in the byte buffer like `parse_request`,
+/// continuing to decode a chunked body where the previous call on the same buffer stopped.
+///
+/// The progress has to be reset whenever the request is taken out of the buffer or rejected.
+pub fn parse_request_resuming(buffer: &[u8], limits: RequestLimits, progress: &mut ChunkedProgress) -> Result<Option<(Request, usize)>> {
+    let head_end = match find_head_end(buffer) {
+        Some(head_end) if head_end + 4 <= limits.max_header_size => head_end,
+        None if buffer.len() <= limits.max_header_size => return Ok(None),
+        _ => return Err(InvalidRequest::header_too_large(limits.max_header_size))
+    };
+
+    let raw_head = match std::str::from_utf8(&buffer[..head_end]) {
         Ok(string) => string,
-        Err(_) => return Err(Box::new(Box::new(InvalidRequest{message: "Request could not be interpreted as string.".to_string()})))
+        Err(_) => return Err(InvalidRequest::bad_request("Request could not be interpreted as string."))
     };
 
-    read_request(&raw_request)
+    let mut request = read_request(raw_head)?;
+    let body_start = head_end + 4;
+    match read_body(&request, &buffer[body_start..], limits, progress)? {
+        Some((body, body_length)) => {
+            request.body = body;
+            Ok(Some((request, body_start + body_length)))
+        }
+        None => Ok(None)
+    }
 }
 
-/// Reads the contents from the byte Vector into the Request object.
-fn read_request(buffer: &str) -> Result<Request> {
-    let lines: Vec<&str> = buffer.split("\r\n").collect();
+/// Returns the position of the empty line separating the head from the body
+fn find_head_end(buffer: &[u8]) -> Option<usize> {
+    buffer.windows(4).position(|window| window == b"\r\n\r\n")
+}
+
+/// Reads the contents of the request head into the Request object.
+fn read_request(head: &str) -> Result<Request> {
+    let lines: Vec<&str> = head.split("\r\n").collect();
     let request_identifiers = get_request_identifiers(&lines)?;
-    let headers = get_headers(&lines.into_iter().skip(1).collect())?;
+    let headers = get_headers(&lines)?;
+    let query_params = request_identifiers.query.as_deref().map(QueryParams::parse).unwrap_or_default();
 
     Ok(Request {
         request_identifiers,
         headers,
-        body: "".to_string(), //todo change to real implementation
+        body: Vec::new(),
+        query_params,
     })
 }
 
+/// Reads the body following the head, according to the headers of the request
+///
+/// Returns the body together with the number of bytes it occupies,
+/// or None while parts of the body are still missing.
+fn read_body(request: &Request, raw_body: &[u8], limits: RequestLimits, progress: &mut ChunkedProgress) -> Result<Option<(Vec<u8>, usize)>> {
+    if let Some(encoding) = request.get_header("Transfer-Encoding") {
+        return if encoding.eq_ignore_ascii_case("chunked") {
+            decode_chunked(raw_body, limits.max_body_size, progress)
+        } else {
+            Err(InvalidRequest::bad_request("Transfer-Encoding not supported."))
+        };
+    }
+
+    let content_length = match request.get_header("Content-Length") {
+        Some(length) => length.parse::<usize>()
+            .map_err(|_| InvalidRequest::bad_request("Content-Length is not a valid number."))?,
+        None => return Ok(Some((Vec::new(), 0)))
+    };
+
+    if content_length > limits.max_body_size {
+        return Err(InvalidRequest::payload_too_large(limits.max_body_size));
+    }
+    Ok(raw_body.get(..content_length).map(|body| (body.to_vec(), content_length)))
+}
+
+/// The longest chunk size line, including extensions, that is accepted
+const MAX_CHUNK_LINE: usize = 1024;
+
+/// The chunks of a chunked body decoded so far, so the next read only decodes the new chunks
+#[derive(Debug, Default)]
+pub struct ChunkedProgress {
+    /// the decoded content of the complete chunks
+    body: Vec<u8>,
+    /// the position in the raw body after the last complete chunk
+    position: usize,
+}
+
+/// Decodes a body sent with chunked Transfer-Encoding, starting after the chunks decoded before
+///
+/// Chunk extensions and trailers are ignored.
+/// Returns None while parts of the body are still missing.
+fn decode_chunked(raw_body: &[u8], max_body_size: usize, progress: &mut ChunkedProgress) -> Result<Option<(Vec<u8>, usize)>> {
+    let out_of_range = || InvalidRequest::bad_request("Chunk size is out of range.");
+    loop {
+        let mut position = progress.position;
+        let size_line = match next_line(&raw_body[position..])? {
+            Some(line) => line,
+            None => return Ok(None)
+        };
+        let size_line = std::str::from_utf8(size_line)
+            .map_err(|_| InvalidRequest::bad_request("Chunk size could not be interpreted as string."))?;
+        let size = size_line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| InvalidRequest::bad_request("Chunk size is not a valid hex number."))?;
+        position += size_line.len() + 2;
+
+        if size == 0 {
+            return match skip_trailers(raw_body, position)? {
+                Some(end) => Ok(Some((std::mem::take(&mut progress.body), end))),
+                None => Ok(None)
+            };
+        }
+        if size > max_body_size - progress.body.len() {
+            return Err(InvalidRequest::payload_too_large(max_body_size));
+        }
+        let chunk_end = position.checked_add(size).ok_or_else(out_of_range)?;
+        let line_end = chunk_end.checked_add(2).ok_or_else(out_of_range)?;
+        match (raw_body.get(position..chunk_end), raw_body.get(chunk_end..line_end)) {
+            (Some(chunk), Some(b"\r\n")) => progress.body.extend_from_slice(chunk),
+            (_, None) => return Ok(None),
+            _ => return Err(InvalidRequest::bad_request("Chunk is not terminated after its size."))
+        }
+        progress.position = line_end;
+    }
+}
+
+/// Skips the trailers after the last chunk, returning the position after the final empty line
+fn skip_trailers(raw_body: &[u8], mut position: usize) -> Result<Option<usize>> {
+    loop {
+        match next_line(&raw_body[position..])? {
+            Some([]) => return Ok(Some(position + 2)),
+            Some(line) => position += line.len() + 2,
+            None => return Ok(None)
+        }
+    }
+}
+
+/// Returns the next line without its line break, or None if the line is not terminated yet
+fn next_line(raw: &[u8]) -> Result<Option<&[u8]>> {
+    match raw.windows(2).position(|window| window == b"\r\n") {
+        Some(line_end) if line_end <= MAX_CHUNK_LINE => Ok(Some(&raw[..line_end])),
+        None if raw.len() <= MAX_CHUNK_LINE => Ok(None),
+        _ => Err(InvalidRequest::bad_request("Chunk line is too long."))
+    }
+}
+
 /// Reads the request identifier from the http request
-fn get_request_identifiers(lines: &Vec<&str>) -> Result<RequestIdentifiers> {
+fn get_request_identifiers(lines: &[&str]) -> Result<RequestIdentifiers> {
     let first_line_content: Vec<&str> = lines
-        .get(0).ok_or(InvalidRequest{message: "First line does not confirm HTTP protocol.".to_string()})?
+        .first().ok_or_else(|| InvalidRequest::bad_request("First line does not confirm HTTP protocol."))?
         .split_whitespace().collect();
 
-    let req_type = match first_line_content
-        .get(0)
-        .ok_or(InvalidRequest{message: "HTTP Type not specified".to_string()})? {
-        &"GET" => RequestType::Get,
-        &"POST" => RequestType::Post,
-        _ => RequestType::Get
-    };
+    let req_type = RequestType::parse(first_line_content
+        .first()
+        .ok_or_else(|| InvalidRequest::bad_request("HTTP Type not specified"))?)?;
 
-    let req_path = first_line_content.get(1)
-        .ok_or(InvalidRequest{message: "path not provided".to_string()})?;
-    let req_path = req_path.split("?").collect::<Vec<&str>>();
-    let req_path = req_path.get(0)
-        .ok_or(InvalidRequest{message: "path not provided".to_string()})?;
+    let req_target = first_line_content.get(1)
+        .ok_or_else(|| InvalidRequest::bad_request("path not provided"))?;
     let req_version = first_line_content.get(2)
-        .ok_or(InvalidRequest{message: "http version not specified.".to_string()})?;
+        .ok_or_else(|| InvalidRequest::bad_request("http version not specified."))?;
+    if !matches!(*req_version, "HTTP/1.0" | "HTTP/1.1") {
+        return Err(InvalidRequest::version_not_supported(req_version));
+    }
+    let (req_path, req_query) = split_target(req_target)?;
 
     Ok(RequestIdentifiers {
         method: req_type,
-        path: req_path.to_string(),
+        path: req_path,
+        query: req_query,
         version: req_version.to_string(),
     })
 }
 
+/// Splits the request target into the normalised path and the raw query string
+fn split_target(target: &str) -> Result<(String, Option<String>)> {
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (target, None)
+    };
+    let path = normalize_path(path).map_err(|message| InvalidRequest::bad_request(&message))?;
+    Ok((path, query))
+}
+
 /// Reads the header from the http request
-fn get_headers(lines: &Vec<&str>) -> Result<HashMap<String, String>> {
-    Ok(lines.into_iter()
+fn get_headers(lines: &[&str]) -> Result<HashMap<String, String>> {
+    Ok(lines.iter()
         .skip(1)
-        .map(|line| -> Vec<&str> { line.splitn(2, ": ").collect() })
-        .filter(|header_pair| header_pair.len() == 2)
-        .map(|header_pair|
-            ((header_pair.get(0).unwrap().to_string()), header_pair.get(1).unwrap().to_string())
-        )
+        .filter_map(|line| line.split_once(": "))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
         .collect()
     )
 }
@@ -196,6 +488,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(unused_mut)]
     fn get_headers_test_empty() {
         let request = vec![
             "GET /hello HTTP/1.1\r\n"
@@ -207,6 +500,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(unused_mut)]
     fn get_request_identifiers_test() {
         let request = vec![
             "GET /hello HTTP/1.1\r\n"
@@ -215,12 +509,78 @@ mod tests {
         let mut result = RequestIdentifiers {
             method: RequestType::Get,
             path: "/hello".to_string(),
+            query: None,
             version: "HTTP/1.1".to_string(),
         };
 
         assert_eq!(get_request_identifiers(&request).unwrap(), result)
     }
 
+    #[test]
+    fn get_request_identifiers_test_query() {
+        let identifiers = get_request_identifiers(&["GET /stats?path=/hello.html&limit=50 HTTP/1.1"]).unwrap();
+        assert_eq!(identifiers.path, "/stats");
+        assert_eq!(identifiers.query.as_deref(), Some("path=/hello.html&limit=50"));
+
+        let identifiers = get_request_identifiers(&["GET /stats? HTTP/1.1"]).unwrap();
+        assert_eq!(identifiers.path, "/stats");
+        assert_eq!(identifiers.query.as_deref(), Some(""));
+    }
+
+    #[test]
+    fn get_request_identifiers_test_normalized_path() {
+        let identifiers = get_request_identifiers(&["GET //a/../my%20file.html?x=%2F HTTP/1.1"]).unwrap();
+        assert_eq!(identifiers.path, "/my file.html");
+        assert_eq!(identifiers.query.as_deref(), Some("x=%2F"));
+
+        let error = get_request_identifiers(&["GET /../hello.html HTTP/1.1"]).err().unwrap();
+        assert_eq!(error.kind(), InvalidRequestKind::BadRequest);
+    }
+
+    #[test]
+    fn parse_request_query_params_test() {
+        let request = parse_request(b"GET /stats?path=%2Fa.html&path=/b HTTP/1.1\r\n\r\n", LIMITS).unwrap().unwrap().0;
+        assert_eq!(request.request_identifiers.path, "/stats");
+        assert_eq!(request.query_params.get_all("path"), &["/a.html".to_string(), "/b".to_string()]);
+    }
+
+    #[test]
+    fn get_request_identifiers_test_methods() {
+        for method in &["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"] {
+            let line = format!("{} /hello HTTP/1.1", method);
+            let identifiers = get_request_identifiers(&[&line[..]]).unwrap();
+            assert_eq!(identifiers.method.as_str(), *method);
+        }
+    }
+
+    #[test]
+    fn get_request_identifiers_test_unknown_method() {
+        let error = get_request_identifiers(&["BREW /pot HTTP/1.1"]).err().unwrap();
+        assert_eq!(error.kind(), InvalidRequestKind::NotImplemented);
+        let error = get_request_identifiers(&["get /hello HTTP/1.1"]).err().unwrap();
+        assert_eq!(error.kind(), InvalidRequestKind::NotImplemented);
+    }
+
+    #[test]
+    fn get_request_identifiers_test_version() {
+        assert_eq!(get_request_identifiers(&["GET /hello HTTP/1.0"]).unwrap().version, "HTTP/1.0");
+        for line in &["GET /hello HTTP/2.0", "GET /hello HTTP/1.2", "GET /hello http/1.1"] {
+            let error = get_request_identifiers(&[line]).err().unwrap();
+            assert_eq!(error.kind(), InvalidRequestKind::VersionNotSupported);
+        }
+    }
+
+    #[test]
+    fn from_fields_test() {
+        let request = Request::from_fields("GET", "/a/../stats?limit=5", HashMap::new(), Vec::new()).unwrap();
+        assert_eq!(request.request_identifiers.path, "/stats");
+        assert_eq!(request.request_identifiers.version, "HTTP/2.0");
+        assert_eq!(request.query_params.get_all("limit"), &["5".to_string()]);
+        assert!(!request.wants_keep_alive());
+        assert_eq!(Request::from_fields("BREW", "/", HashMap::new(), Vec::new()).err().unwrap().kind(), InvalidRequestKind::NotImplemented);
+        assert_eq!(Request::from_fields("GET", "/../a", HashMap::new(), Vec::new()).err().unwrap().kind(), InvalidRequestKind::BadRequest);
+    }
+
     #[test]
     fn get_request_identifiers_test_missing_path() {
         let request = vec![
@@ -236,4 +596,130 @@ mod tests {
 
         assert_eq!(get_request_identifiers(&request).err().unwrap().to_string(), "Could not parse request.First line does not confirm HTTP protocol.")
     }
-}
\ No newline at end of file
+
+    const LIMITS: RequestLimits = RequestLimits { max_body_size: 16, max_header_size: 64, read_timeout: Duration::from_secs(1) };
+
+    #[test]
+    fn parse_request_without_body_test() {
+        let request = parse_request(b"GET /hello HTTP/1.1\r\nHost: localhost:8080\r\n\r\n", LIMITS).unwrap().unwrap().0;
+
+        assert_eq!(request.request_identifiers.path, "/hello");
+        assert_eq!(request.get_header("host"), Some("localhost:8080"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn parse_request_content_length_test() {
+        let request = parse_request(b"POST /form HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\0\0\0", LIMITS).unwrap().unwrap().0;
+
+        assert_eq!(request.request_identifiers.method, RequestType::Post);
+        assert_eq!(request.body, b"hello".to_vec());
+    }
+
+    #[test]
+    fn parse_request_content_length_too_short_test() {
+        assert!(parse_request(b"POST /form HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello", LIMITS).unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_request_content_length_too_large_test() {
+        let error = parse_request(b"POST /form HTTP/1.1\r\nContent-Length: 17\r\n\r\n", LIMITS).err().unwrap();
+
+        assert_eq!(error.kind(), InvalidRequestKind::PayloadTooLarge);
+    }
+
+    #[test]
+    fn parse_request_chunked_test() {
+        let raw = b"POST /form HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: x\r\n\r\n";
+        let request = parse_request(raw, LIMITS).unwrap().unwrap().0;
+
+        assert_eq!(request.body, b"hello world".to_vec());
+    }
+
+    #[test]
+    fn parse_request_chunked_too_large_test() {
+        let raw = b"POST /form HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n10\r\n0123456789abcdef\r\n1\r\nx\r\n0\r\n\r\n";
+        let error = parse_request(raw, LIMITS).err().unwrap();
+
+        assert_eq!(error.kind(), InvalidRequestKind::PayloadTooLarge);
+    }
+
+    #[test]
+    fn parse_request_chunked_huge_size_test() {
+        let raw = b"POST /form HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\nffffffffffffffff\r\nx\r\n0\r\n\r\n";
+        assert_eq!(parse_request(raw, LIMITS).err().unwrap().kind(), InvalidRequestKind::PayloadTooLarge);
+
+        // without a body limit the size still must not overflow the position in the body
+        let raw = b"POST /form HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nfffffffffffffffa\r\nx\r\n0\r\n\r\n";
+        let unlimited = RequestLimits { max_body_size: usize::MAX, ..LIMITS };
+        assert_eq!(parse_request(raw, unlimited).err().unwrap().kind(), InvalidRequestKind::BadRequest);
+    }
+
+    #[test]
+    fn parse_request_chunked_resuming_test() {
+        let raw = b"POST /form HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let mut progress = ChunkedProgress::default();
+        let body_start = raw.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+
+        assert!(parse_request_resuming(&raw[..body_start + 12], LIMITS, &mut progress).unwrap().is_none());
+        assert_eq!(progress.body, b"hello");
+        assert_eq!(progress.position, 10);
+        let (request, length) = parse_request_resuming(raw, LIMITS, &mut progress).unwrap().unwrap();
+        assert_eq!(request.body, b"hello world");
+        assert_eq!(length, raw.len());
+    }
+
+    #[test]
+    fn parse_request_chunked_malformed_test() {
+        let raw = b"POST /form HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\nhello\r\n0\r\n\r\n";
+        let error = parse_request(raw, LIMITS).err().unwrap();
+
+        assert_eq!(error.kind(), InvalidRequestKind::BadRequest);
+    }
+
+    #[test]
+    fn parse_request_incomplete_test() {
+        assert!(parse_request(b"GET /hello HTTP/1.1\r\nHost: local", LIMITS).unwrap().is_none());
+        assert!(parse_request(b"POST /form HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel", LIMITS).unwrap().is_none());
+        assert!(parse_request(b"POST /form HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n", LIMITS).unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_request_pipelined_test() {
+        let raw = b"POST /form HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /next HTTP/1.1\r\n\r\n";
+        let (request, length) = parse_request(raw, LIMITS).unwrap().unwrap();
+
+        assert_eq!(request.body, b"hello".to_vec());
+        assert_eq!(&raw[length..], b"GET /next HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn parse_request_header_too_large_test() {
+        let raw = format!("GET /hello HTTP/1.1\r\nCookie: {}", "x".repeat(64));
+        let error = parse_request(raw.as_bytes(), LIMITS).err().unwrap();
+
+        assert_eq!(error.kind(), InvalidRequestKind::HeaderTooLarge);
+    }
+
+    #[test]
+    fn wants_keep_alive_test() {
+        let request = |raw: &[u8]| parse_request(raw, LIMITS).unwrap().unwrap().0;
+
+        assert!(request(b"GET / HTTP/1.1\r\n\r\n").wants_keep_alive());
+        assert!(!request(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").wants_keep_alive());
+        assert!(!request(b"GET / HTTP/1.1\r\nconnection: Upgrade, Close\r\n\r\n").wants_keep_alive());
+        assert!(!request(b"GET / HTTP/1.0\r\n\r\n").wants_keep_alive());
+        assert!(request(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").wants_keep_alive());
+    }
+
+    #[test]
+    fn keep_alive_allows_test() {
+        let request = parse_request(b"GET / HTTP/1.1\r\n\r\n", LIMITS).unwrap().unwrap().0;
+        let keep_alive = KeepAlive { timeout: Duration::from_secs(1), max_requests: 2 };
+        let disabled = KeepAlive { timeout: Duration::from_secs(0), max_requests: 2 };
+
+        assert!(keep_alive.allows(&request, 1));
+        assert!(!keep_alive.allows(&request, 2));
+        assert!(!disabled.allows(&request, 1));
+    }
+}
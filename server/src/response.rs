@@ -1,17 +1,51 @@
 use std::collections::HashMap;
-use std::path::Path;
-use std::ffi::OsStr;
-use crate::{Buffer, StaticFiles, DynamicFiles};
-use crate::request::parse_request;
-use std::net::TcpStream;
-use std::io::Write;
+use crate::DynamicFiles;
+use crate::body::{self, Body, Chunks};
+use crate::connection::Connection;
+use crate::compression::{is_compressible, is_worth_compressing, negotiate, CompressionMode, Encoding, ENCODINGS};
+use crate::file::{ServerFiles, StaticFile};
+use crate::http_date;
+use crate::range::{content_range, parse_ranges, ByteRanges};
+use std::ops::Range;
+use crate::directory::{resolve, Resource};
+use crate::mime::content_type;
+use crate::request::{InvalidRequest, InvalidRequestKind, Request, RequestType};
+
+/// The value of the server header, naming this server and its version
+const SERVER_NAME: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// The methods the servers support on their resources, other known methods are answered with 405
+pub const ALLOWED_METHODS: [RequestType; 3] = [RequestType::Get, RequestType::Head, RequestType::Options];
 
 /// The object used in all the servers, to represent the http response.
 #[derive(Debug)]
 pub struct Response {
     pub response_identifiers: ResponseIdentifiers,
     pub headers: HashMap<String, String>,
-    pub body: Vec<u8>,
+    pub body: Body,
+    /// whether the connection stays open for further requests after this response
+    pub keep_alive: bool,
+    /// the headers added automatically when the response is made sendable
+    pub automatic_headers: AutomaticHeaders,
+    /// whether only the head is sent, e.g. answering a HEAD request. The content-length still describes the body
+    pub omit_body: bool,
+}
+
+/// The headers every response contains, unless they are turned off
+///
+/// Streaming responses whose length is unknown upfront turn off the content-length.
+/// A header added explicitly to the response always replaces the automatic one.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct AutomaticHeaders {
+    pub content_length: bool,
+    pub date: bool,
+    pub server: bool,
+}
+
+impl Default for AutomaticHeaders {
+    fn default() -> Self {
+        Self { content_length: true, date: true, server: true }
+    }
 }
 
 /// The identifier for the response, containing http method and version
@@ -33,12 +67,40 @@ pub struct ResponseType {
 }
 
 impl ResponseType {
+    /// The default 101 - Switching Protocols response
+    fn switching_protocols() -> Self { Self { name: "Switching Protocols".to_string(), id: 101 } }
     /// The default 200 - OK response
     fn ok() -> Self { Self { name: "OK".to_string(), id: 200 } }
+    /// The default 301 - Moved Permanently response
+    fn moved_permanently() -> Self { Self { name: "Moved Permanently".to_string(), id: 301 } }
+    /// The default 206 - Partial Content response
+    fn partial_content() -> Self { Self { name: "Partial Content".to_string(), id: 206 } }
+    /// The default 304 - Not Modified response
+    fn not_modified() -> Self { Self { name: "Not Modified".to_string(), id: 304 } }
     /// The default 404 - Not Found response
     fn not_found() -> Self { Self { name: "Not Found".to_string(), id: 404 } }
     /// The default 400 - Bad Request response
     fn bad_request() -> Self { Self { name: "Bad Request".to_string(), id: 400 } }
+    /// The default 405 - Method Not Allowed response
+    fn method_not_allowed() -> Self { Self { name: "Method Not Allowed".to_string(), id: 405 } }
+    /// The default 408 - Request Timeout response
+    fn request_timeout() -> Self { Self { name: "Request Timeout".to_string(), id: 408 } }
+    /// The default 413 - Payload Too Large response
+    fn payload_too_large() -> Self { Self { name: "Payload Too Large".to_string(), id: 413 } }
+    /// The default 431 - Request Header Fields Too Large response
+    fn request_header_fields_too_large() -> Self { Self { name: "Request Header Fields Too Large".to_string(), id: 431 } }
+    /// The default 416 - Range Not Satisfiable response
+    fn range_not_satisfiable() -> Self { Self { name: "Range Not Satisfiable".to_string(), id: 416 } }
+    /// The default 426 - Upgrade Required response
+    fn upgrade_required() -> Self { Self { name: "Upgrade Required".to_string(), id: 426 } }
+    /// The default 500 - Internal Server Error response
+    fn internal_server_error() -> Self { Self { name: "Internal Server Error".to_string(), id: 500 } }
+    /// The default 501 - Not Implemented response
+    fn not_implemented() -> Self { Self { name: "Not Implemented".to_string(), id: 501 } }
+    /// The default 503 - Service Unavailable response
+    fn service_unavailable() -> Self { Self { name: "Service Unavailable".to_string(), id: 503 } }
+    /// The default 505 - HTTP Version Not Supported response
+    fn http_version_not_supported() -> Self { Self { name: "HTTP Version Not Supported".to_string(), id: 505 } }
 }
 
 
@@ -51,84 +113,313 @@ impl ResponseIdentifiers {
 }
 
 impl Response {
-    /// Creates the default OK 200 response
-    pub fn default_ok() -> Self {
+    /// Creates an empty HTTP/1.1 response of the given type
+    fn new(method: ResponseType) -> Self {
         Self {
             response_identifiers: ResponseIdentifiers {
-                method: ResponseType::ok(),
+                method,
                 version: "1.1".to_string(),
             },
             headers: HashMap::new(),
-            body: Vec::new(),
+            body: Body::default(),
+            keep_alive: false,
+            automatic_headers: AutomaticHeaders::default(),
+            omit_body: false,
+        }
+    }
+
+    /// Creates the default Switching Protocols 101 response, upgrading the connection to the protocol
+    ///
+    /// The response has no body, the connection continues with the new protocol right after its head.
+    pub fn default_switching_protocols(protocol: &str) -> Self {
+        let mut response = Self::new(ResponseType::switching_protocols());
+        response.automatic_headers.content_length = false;
+        response.add_header("connection", "upgrade");
+        response.add_header("upgrade", protocol);
+        response
+    }
+
+    /// Creates the default OK 200 response
+    pub fn default_ok() -> Self {
+        Self::new(ResponseType::ok())
+    }
+
+    /// Creates the default Moved Permanently 301 response, redirecting to the location
+    pub fn default_moved_permanently(location: &str) -> Self {
+        let mut response = Self::new(ResponseType::moved_permanently());
+        response.add_header("location", location);
+        response
+    }
+
+    /// Creates the response containing the file together with its validators
+    ///
+    /// If the copy the client cached is still fresh, the response is turned into a 304
+    /// with the same headers, but without the body.
+    /// Otherwise only the requested ranges of the file are sent, if the client asked for them.
+    /// A file compressed ahead of time is sent compressed if the client accepts the coding,
+    /// the validators and ranges then apply to the compressed content.
+    pub fn from_static_file(request: &Request, path: String, file: StaticFile) -> Self {
+        let mut response = Self::default_ok();
+        response.add_content_type(path);
+        response.add_header("accept-ranges", "bytes");
+        if !file.encodings.is_empty() {
+            response.add_header("vary", "accept-encoding");
+        }
+        let file = match select_encoding(request, file) {
+            (file, Some(encoding)) => {
+                response.add_header("content-encoding", encoding.as_str());
+                file
+            }
+            (file, None) => file
+        };
+        response.add_header("etag", &file.etag);
+        if let Some(modified) = file.modified {
+            response.add_header("last-modified", &http_date::format(modified));
+        }
+
+        if is_not_modified(request, &file) {
+            response.response_identifiers.method = ResponseType::not_modified();
+            response.omit_body = true;
+            response.body = file.content;
+            return response;
+        }
+        match requested_ranges(request, &file) {
+            ByteRanges::Full => response.body = file.content,
+            ByteRanges::Partial(ranges) => response.add_partial_content(&file, ranges),
+            ByteRanges::Unsatisfiable => return Self::default_range_not_satisfiable(file.content.len())
+        }
+        response
+    }
+
+    /// Creates the response streaming the chunks while it is sent, instead of building the body upfront
+    ///
+    /// HTTP/1.1 clients receive the chunks with the chunked transfer coding.
+    /// Older clients do not know it, so the end of the body is marked by closing the connection.
+    pub fn from_stream(request: &Request, chunks: impl Iterator<Item = Vec<u8>> + Send + 'static) -> Self {
+        let chunked = request.request_identifiers.version == "HTTP/1.1";
+        let mut response = Self::default_ok();
+        if chunked {
+            response.add_header("transfer-encoding", "chunked");
         }
+        response.body = Body::Stream(Chunks::new(chunks, chunked));
+        response
+    }
+
+    /// Creates the default Range Not Satisfiable 416 response for a file of the length
+    pub fn default_range_not_satisfiable(length: usize) -> Self {
+        let mut response = Self::new(ResponseType::range_not_satisfiable());
+        response.add_header("content-range", &format!("bytes */{}", length));
+        response
+    }
+
+    /// Turns the response into a 206 containing the ranges of the file
+    ///
+    /// A single range is sent as it is, multiple ranges as multipart/byteranges,
+    /// each part with the content type of the file and its range.
+    fn add_partial_content(&mut self, file: &StaticFile, ranges: Vec<Range<usize>>) {
+        let length = file.content.len();
+        self.response_identifiers.method = ResponseType::partial_content();
+        if let [range] = &ranges[..] {
+            self.add_header("content-range", &content_range(range, length));
+            self.body = file.content.slice(range.clone());
+            return;
+        }
+
+        let content_type = self.headers.remove("content-type").unwrap_or_default();
+        let boundary = format!("byteranges-{}", file.etag.trim_matches('"'));
+        self.add_header("content-type", &format!("multipart/byteranges; boundary={}", boundary));
+        let mut parts = Vec::with_capacity(ranges.len() * 2 + 1);
+        for range in ranges {
+            let part_head = format!("\r\n--{}\r\ncontent-type: {}\r\ncontent-range: {}\r\n\r\n",
+                                    boundary, content_type, content_range(&range, length));
+            parts.push(Body::from(part_head.into_bytes()));
+            parts.push(file.content.slice(range));
+        }
+        parts.push(Body::from(format!("\r\n--{}--\r\n", boundary).into_bytes()));
+        self.body = Body::Parts(parts);
     }
 
     /// Creates the default Not Found 404 response
     pub fn default_not_found() -> Self {
-        Self {
-            response_identifiers: ResponseIdentifiers {
-                method: ResponseType::not_found(),
-                version: "1.1".to_string(),
-            },
-            headers: HashMap::new(),
-            body: Vec::new(),
-        }
+        Self::new(ResponseType::not_found())
     }
 
     /// Creates the default Bad Request 400 response
     pub fn default_bad_request() -> Self {
-        Self {
-            response_identifiers: ResponseIdentifiers {
-                method: ResponseType::bad_request(),
-                version: "1.1".to_string(),
-            },
-            headers: HashMap::new(),
-            body: Vec::new(),
+        Self::new(ResponseType::bad_request())
+    }
+
+    /// Creates the default OK 200 response to an OPTIONS request, listing the allowed methods
+    pub fn default_options() -> Self {
+        let mut response = Self::new(ResponseType::ok());
+        response.add_allow_header();
+        response
+    }
+
+    /// Creates the default Method Not Allowed 405 response, listing the allowed methods
+    pub fn default_method_not_allowed() -> Self {
+        let mut response = Self::new(ResponseType::method_not_allowed());
+        response.add_allow_header();
+        response
+    }
+
+    /// Creates the default Upgrade Required 426 response, naming the protocol the resource is only served with
+    pub fn default_upgrade_required(protocol: &str) -> Self {
+        let mut response = Self::new(ResponseType::upgrade_required());
+        response.add_header("connection", "upgrade");
+        response.add_header("upgrade", protocol);
+        response
+    }
+
+    /// Creates the default Internal Server Error 500 response
+    pub fn default_internal_server_error() -> Self {
+        Self::new(ResponseType::internal_server_error())
+    }
+
+    /// Creates the default Not Implemented 501 response
+    pub fn default_not_implemented() -> Self {
+        Self::new(ResponseType::not_implemented())
+    }
+
+    /// Creates the default Service Unavailable 503 response, asking the client to retry after the seconds
+    pub fn default_service_unavailable(retry_after: u64) -> Self {
+        let mut response = Self::new(ResponseType::service_unavailable());
+        response.add_header("retry-after", &retry_after.to_string());
+        response
+    }
+
+    /// Creates the default HTTP Version Not Supported 505 response
+    pub fn default_http_version_not_supported() -> Self {
+        Self::new(ResponseType::http_version_not_supported())
+    }
+
+    /// Creates the default Request Timeout 408 response
+    pub fn default_request_timeout() -> Self {
+        Self::new(ResponseType::request_timeout())
+    }
+
+    /// Creates the default Payload Too Large 413 response
+    pub fn default_payload_too_large() -> Self {
+        Self::new(ResponseType::payload_too_large())
+    }
+
+    /// Creates the default Request Header Fields Too Large 431 response
+    pub fn default_request_header_fields_too_large() -> Self {
+        Self::new(ResponseType::request_header_fields_too_large())
+    }
+
+    /// Creates the default response for a request that could not be parsed
+    pub fn default_invalid_request(error: &InvalidRequest) -> Self {
+        match error.kind() {
+            InvalidRequestKind::BadRequest => Self::default_bad_request(),
+            InvalidRequestKind::PayloadTooLarge => Self::default_payload_too_large(),
+            InvalidRequestKind::HeaderTooLarge => Self::default_request_header_fields_too_large(),
+            InvalidRequestKind::Timeout => Self::default_request_timeout(),
+            InvalidRequestKind::NotImplemented => Self::default_not_implemented(),
+            InvalidRequestKind::VersionNotSupported => Self::default_http_version_not_supported(),
         }
     }
 
     /// Creates a default error page response
     pub fn dynamic_error_response(&mut self, error_message: String, files: DynamicFiles) {
-        match files.get("/error_page.html") {
-            Some(resource) => {
-                self.add_content_type("_.html".to_string());
-                self.body = build_error_html(self, resource.to_string(), error_message);
-            },
-            None => return
+        if let Some(resource) = files.get("/error_page.html") {
+            self.add_content_type("_.html".to_string());
+            self.body = build_error_html(self, resource.to_string(), error_message).into();
         }
     }
 
-    /// Makes the response into a sendable byte vector
-    pub fn make_sendable(&mut self) -> Vec<u8> {
+    /// Makes the status line and headers into a sendable byte vector, the body is sent after it
+    ///
+    /// The automatic and connection headers are always sent first,
+    /// so the client knows where the response ends and whether the connection stays open.
+    /// Without a content-length or transfer-encoding the end of the body is only marked
+    /// by closing the connection, so it is not kept alive, unless only the head is sent.
+    /// The body is dropped if only the head is sent.
+    pub fn make_head_sendable(&mut self) -> Vec<u8> {
+        let unknown_length = !self.automatic_headers.content_length || self.body.is_stream();
+        if unknown_length && !self.omit_body && !self.has_header("transfer-encoding") {
+            self.keep_alive = false;
+        }
         let mut ident = self.response_identifiers.make_sendable();
+        let mut connection_headers = self.make_connection_headers_sendable();
         let mut headers = self.make_headers_sendable();
         let mut vec = Vec::with_capacity(1);
         vec.append(&mut ident);
+        vec.append(&mut connection_headers);
         vec.append(&mut headers);
         vec.append(&mut "\r\n".as_bytes().to_vec());
-        vec.append(&mut self.body);
+        if self.omit_body {
+            self.body = Body::default();
+        }
         vec
     }
 
+    /// Makes the status and headers into the header fields of an HTTP/2 response, the body is sent in frames after them
+    ///
+    /// HTTP/2 frames every body itself, so the connection specific headers of HTTP/1 are left out.
+    /// The body is dropped if only the head is sent.
+    pub fn make_header_fields(&mut self) -> Vec<(String, String)> {
+        let mut fields = vec![(":status".to_string(), self.response_identifiers.method.id.to_string())];
+        fields.extend(self.automatic_header_fields().into_iter().map(|(name, value)| (name.to_string(), value)));
+        for (name, value) in &self.headers {
+            let name = name.to_ascii_lowercase();
+            if !matches!(&name[..], "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade") {
+                fields.push((name, value.clone()));
+            }
+        }
+        if self.omit_body {
+            self.body = Body::default();
+        }
+        fields
+    }
+
     /// Adds a specific header into the response
     pub fn add_header(&mut self, header_key: &str, header_value: &str) {
         self.headers.insert(header_key.to_string(), header_value.to_string());
     }
 
-    /// Adds the content type into the response
+    /// Whether the header was added explicitly, ignoring the case of the key
+    pub fn has_header(&self, header_key: &str) -> bool {
+        self.headers.keys().any(|key| key.eq_ignore_ascii_case(header_key))
+    }
+
+    /// Adds the allow header listing the methods the servers support
+    fn add_allow_header(&mut self) {
+        let methods = ALLOWED_METHODS.iter().map(RequestType::as_str).collect::<Vec<&str>>();
+        self.add_header("allow", &methods.join(", "));
+    }
+
+    /// Adds the content type of the file into the response
     pub fn add_content_type(&mut self, file: String) {
-        let file_ending = Path::new(&file).extension().and_then(OsStr::to_str).unwrap();
-        let content_type = match file_ending {
-            "json" => "text/json",
-            "js" => "application/javascript",
-            "png"=> "img/png",
-            "jpeg"=> "img/jpeg",
-            "html"=> "text/html",
-            "txt" => "text/plain",
-            "css" => "text/css",
-            _ => "text/plain",
-        };
-        self.add_header("content-type",content_type);
+        self.add_header("content-type", &content_type(&file));
+    }
+
+    /// The automatic headers which were not added explicitly, as names and values
+    fn automatic_header_fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = Vec::new();
+        if self.automatic_headers.content_length && !self.body.is_stream() && !self.has_header("content-length") {
+            fields.push(("content-length", self.body.len().to_string()));
+        }
+        if self.automatic_headers.date && !self.has_header("date") {
+            fields.push(("date", http_date::now()));
+        }
+        if self.automatic_headers.server && !self.has_header("server") {
+            fields.push(("server", SERVER_NAME.to_string()));
+        }
+        fields
+    }
+
+    /// Makes the automatic headers and the connection state into a sendable byte vector
+    fn make_connection_headers_sendable(&self) -> Vec<u8> {
+        let mut headers = String::new();
+        for (name, value) in self.automatic_header_fields() {
+            headers.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !self.has_header("connection") {
+            let connection = if self.keep_alive { "keep-alive" } else { "close" };
+            headers.push_str(&format!("connection: {}\r\n", connection));
+        }
+        headers.into_bytes()
     }
 
     /// Makes the response header into a sendable byte vector
@@ -144,30 +435,79 @@ impl Response {
     }
 }
 
-/// Creates a response according to the requested ressource
-pub fn create_response(buffer: Buffer, files: StaticFiles) -> Vec<u8> {
-    let mut response = Response::default_ok();
+/// Creates a response according to the requested ressource and method
+///
+/// HEAD requests get the same head as GET requests, without the body.
+pub fn create_response(request: &Request, files: &ServerFiles) -> Response {
+    let method = request.request_identifiers.method;
+    let mut response = match method {
+        RequestType::Get | RequestType::Head => create_file_response(request, files),
+        RequestType::Options => Response::default_options(),
+        _ => Response::default_method_not_allowed()
+    };
+    response.omit_body = method == RequestType::Head;
+    compress_response(request, &mut response, files.compression);
+    response
+}
 
-    let request = parse_request(buffer.to_vec());
-    let request = match request {
-        Ok(request) => request,
-        Err(error) => {
-            println!("{}", error);
-            return Response::default_bad_request().make_sendable();
-        }
+/// Compresses the body on the fly with the coding the client prefers
+///
+/// Only complete bodies of compressible types are compressed, partial content and bodies
+/// which are already compressed are sent as they are. The entity tag becomes weak,
+/// as the compressed bytes depend on the compression level. Ranges are only served
+/// from the identity bytes, so compressed responses do not advertise them.
+/// Responses without a body, i.e. 304 and HEAD, only get the headers of the coding,
+/// the body they never send is not compressed, and its length is left out.
+pub fn compress_response(request: &Request, response: &mut Response, mode: CompressionMode) {
+    let compressible = response.headers.get("content-type").is_some_and(|content_type| is_compressible(content_type));
+    if mode == CompressionMode::Off || !compressible {
+        return;
+    }
+    response.headers.entry("vary".to_string()).or_insert_with(|| "accept-encoding".to_string());
+
+    let complete = matches!(response.response_identifiers.method.id, 200 | 304);
+    let body = match response.body.bytes() {
+        Some(body) if complete && !response.has_header("content-encoding") && is_worth_compressing(body) => body,
+        _ => return
+    };
+    let encoding = match request.get_header("Accept-Encoding").and_then(|accepted| negotiate(accepted, &ENCODINGS)) {
+        Some(encoding) => encoding,
+        None => return
     };
+    if response.omit_body {
+        response.automatic_headers.content_length = false;
+    } else {
+        response.body = match encoding.compress(body, false) {
+            Ok(compressed) => compressed.into(),
+            Err(error) => {
+                println!("Error while compressing response: {}", error);
+                return;
+            }
+        };
+    }
+    response.add_header("content-encoding", encoding.as_str());
+    response.headers.remove("accept-ranges");
+    if let Some(etag) = response.headers.get_mut("etag") {
+        if !etag.starts_with("W/") {
+            etag.insert_str(0, "W/");
+        }
+    }
+}
 
-    let req_file = request.request_identifiers.path;
-    let file = match files.get(&req_file) {
-        Some(file) => file,
-        None => {
-            println!("Requested source could not be found");
-            return Response::default_not_found().make_sendable();
+/// Creates a response containing the requested file, or the index file or listing of the requested directory
+fn create_file_response(request: &Request, files: &ServerFiles) -> Response {
+    let identifiers = &request.request_identifiers;
+    match resolve(&identifiers.path, identifiers.query.as_deref(), files) {
+        Resource::File(path, file) => Response::from_static_file(request, path, file),
+        Resource::Listing(html) => {
+            let mut response = Response::default_ok();
+            response.add_content_type("_.html".to_string());
+            response.body = html.into();
+            response
         }
-    };
-    response.add_content_type(req_file);
-    response.body = file.clone();
-    response.make_sendable()
+        Resource::Redirect(location) => Response::default_moved_permanently(&location),
+        Resource::Missing => Response::default_not_found()
+    }
 }
 
 /// Dynamically replaces placeholders in the error_page resource with the code and description
@@ -181,13 +521,79 @@ pub fn build_error_html(response: &mut Response, mut resource: String, error_mes
     resource.as_bytes().to_vec()
 }
 
+/// Replaces the content of the file with the compressed content the client accepts, if there is any
+///
+/// The compressed content gets its own strong entity tag, e.g. "<hash>-br".
+fn select_encoding(request: &Request, mut file: StaticFile) -> (StaticFile, Option<Encoding>) {
+    let encoding = request.get_header("Accept-Encoding")
+        .and_then(|accepted| negotiate(accepted, &file.available_encodings()));
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => return (file, None)
+    };
+    if let Some(content) = file.encodings.remove(&encoding) {
+        file.content = content;
+        file.etag = format!("{}-{}\"", file.etag.trim_end_matches('"'), encoding.as_str());
+    }
+    (file, Some(encoding))
+}
+
+/// Whether the copy of the file the client cached is still fresh
+///
+/// If-None-Match takes precedence over If-Modified-Since, as the entity tag is exact.
+fn is_not_modified(request: &Request, file: &StaticFile) -> bool {
+    if let Some(if_none_match) = request.get_header("If-None-Match") {
+        return etag_matches(if_none_match, &file.etag);
+    }
+    let since = request.get_header("If-Modified-Since").and_then(http_date::parse);
+    match (since, file.modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false
+    }
+}
+
+/// The ranges of the file the GET request asks for
+///
+/// If-Range only allows partial responses while the file still matches the validator.
+fn requested_ranges(request: &Request, file: &StaticFile) -> ByteRanges {
+    if request.request_identifiers.method != RequestType::Get {
+        return ByteRanges::Full;
+    }
+    let header = match request.get_header("Range") {
+        Some(header) => header,
+        None => return ByteRanges::Full
+    };
+    match request.get_header("If-Range") {
+        Some(if_range) if !if_range_matches(if_range, file) => ByteRanges::Full,
+        _ => parse_ranges(header, file.content.len())
+    }
+}
+
+/// Whether the If-Range validator matches the file, using the strong comparison
+fn if_range_matches(if_range: &str, file: &StaticFile) -> bool {
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return if_range == file.etag;
+    }
+    match (http_date::parse(if_range), file.modified) {
+        (Some(date), Some(modified)) => date == modified,
+        _ => false
+    }
+}
+
+/// Whether one of the entity tags of the header matches, using the weak comparison
+fn etag_matches(header: &str, etag: &str) -> bool {
+    let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    header.trim() == "*" || header.split(',').any(|tag| weak(tag) == weak(etag))
+}
+
 /// Send a response to the requester
-pub fn send_response(mut stream: TcpStream, response: &mut Response) {
-    let worked = stream.write(&response.make_sendable());
-    if let Err(err) =  worked {
-        println!("Error while sending response: {}",err)
+pub fn send_response(stream: &mut Connection, response: &mut Response) -> std::io::Result<()> {
+    let head = response.make_head_sendable();
+    let worked = body::write_all(stream, &head, &response.body);
+    if let Err(err) = &worked {
+        println!("Error while sending response: {}", err)
     }
-    stream.flush().unwrap();
+    worked
 }
 
 #[cfg(test)]
@@ -217,30 +623,110 @@ mod response_identifiers_test {
 mod response_test {
     use super::*;
 
+    impl Response {
+        /// The head followed by the body, as it is sent
+        fn make_sendable(&mut self) -> Vec<u8> {
+            let mut sendable = self.make_head_sendable();
+            sendable.extend_from_slice(&self.body.to_vec().unwrap());
+            sendable
+        }
+    }
+
     const CORRECT_OK: &str = "HTTP/1.1 200 OK\r\n";
     const CORRECT_NOT_FOUND: &str = "HTTP/1.1 404 Not Found\r\n";
     const CORRECT_BAD_REQUEST: &str = "HTTP/1.1 400 Bad Request\r\n";
+    const EMPTY_CLOSE: &str = "content-length: 0\r\nconnection: close\r\n";
+
+    /// A response without the headers depending on the time or build
+    fn without_date_and_server() -> Response {
+        let mut response = Response::default_ok();
+        response.automatic_headers.date = false;
+        response.automatic_headers.server = false;
+        response
+    }
 
     #[test]
     fn make_sendable_simple_tests() {
-        assert_eq!(format!("{}\r\n", CORRECT_OK),
-                   String::from_utf8(Response::default_ok().make_sendable()).unwrap());
-        assert_eq!(format!("{}\r\n", CORRECT_NOT_FOUND),
-                   String::from_utf8(Response::default_not_found().make_sendable()).unwrap());
-        assert_eq!(format!("{}\r\n", CORRECT_BAD_REQUEST),
-                   String::from_utf8(Response::default_bad_request().make_sendable()).unwrap());
+        let mut response = without_date_and_server();
+        assert_eq!(format!("{}{}\r\n", CORRECT_OK, EMPTY_CLOSE),
+                   String::from_utf8(response.make_sendable()).unwrap());
+        response.response_identifiers.method = ResponseType::not_found();
+        assert_eq!(format!("{}{}\r\n", CORRECT_NOT_FOUND, EMPTY_CLOSE),
+                   String::from_utf8(response.make_sendable()).unwrap());
+        response.response_identifiers.method = ResponseType::bad_request();
+        assert_eq!(format!("{}{}\r\n", CORRECT_BAD_REQUEST, EMPTY_CLOSE),
+                   String::from_utf8(response.make_sendable()).unwrap());
+    }
+
+    #[test]
+    fn automatic_headers_tests() {
+        let sendable = String::from_utf8(Response::default_ok().make_sendable()).unwrap();
+        let date = sendable.lines().find(|line| line.starts_with("date: ")).unwrap();
+        assert!(date.ends_with(" GMT"));
+        assert_eq!(date.len(), "date: Sun, 06 Nov 1994 08:49:37 GMT".len());
+        assert!(sendable.contains(&format!("server: {}\r\n", SERVER_NAME)));
+
+        let mut response = without_date_and_server();
+        response.add_header("Server", "custom");
+        assert!(String::from_utf8(response.make_sendable()).unwrap().contains("Server: custom\r\n"));
+        assert!(!String::from_utf8(response.make_sendable()).unwrap().contains(SERVER_NAME));
+    }
+
+    #[test]
+    fn without_content_length_tests() {
+        let mut response = without_date_and_server();
+        response.automatic_headers.content_length = false;
+        response.keep_alive = true;
+        response.body = "Hello".to_string().into_bytes().into();
+        assert_eq!(format!("{}connection: close\r\n\r\nHello", CORRECT_OK),
+                   String::from_utf8(response.make_sendable()).unwrap());
+        assert!(!response.keep_alive);
+
+        let mut response = without_date_and_server();
+        response.automatic_headers.content_length = false;
+        response.keep_alive = true;
+        response.add_header("transfer-encoding", "chunked");
+        assert_eq!(format!("{}connection: keep-alive\r\ntransfer-encoding: chunked\r\n\r\n", CORRECT_OK),
+                   String::from_utf8(response.make_sendable()).unwrap());
     }
 
 
     #[test]
     fn response_with_header_tests() {
-        let mut response = Response::default_ok();
+        let mut response = without_date_and_server();
         response.add_header("content-type", "text/json");
-        response.body = "Hello".to_string().into_bytes();
-        assert_eq!(format!("{}content-type: text/json\r\n\r\nHello", CORRECT_OK),
+        response.body = "Hello".to_string().into_bytes().into();
+        assert_eq!(format!("{}content-length: 5\r\nconnection: close\r\ncontent-type: text/json\r\n\r\nHello", CORRECT_OK),
                    String::from_utf8(response.make_sendable()).unwrap());
     }
 
+    #[test]
+    fn response_keep_alive_tests() {
+        let mut response = without_date_and_server();
+        response.keep_alive = true;
+        assert_eq!(format!("{}content-length: 0\r\nconnection: keep-alive\r\n\r\n", CORRECT_OK),
+                   String::from_utf8(response.make_sendable()).unwrap());
+    }
+
+    #[test]
+    fn omit_body_tests() {
+        let mut response = without_date_and_server();
+        response.omit_body = true;
+        response.body = "Hello".to_string().into_bytes().into();
+        assert_eq!(format!("{}content-length: 5\r\nconnection: close\r\n\r\n", CORRECT_OK),
+                   String::from_utf8(response.make_sendable()).unwrap());
+    }
+
+    #[test]
+    fn allowed_methods_tests() {
+        let response = Response::default_options();
+        assert_eq!(response.response_identifiers.method.id, 200);
+        assert_eq!(response.headers.get("allow").unwrap(), "GET, HEAD, OPTIONS");
+        let response = Response::default_method_not_allowed();
+        assert_eq!(response.response_identifiers.method.id, 405);
+        assert_eq!(response.headers.get("allow").unwrap(), "GET, HEAD, OPTIONS");
+    }
+
     #[test]
     fn make_headers_sendable_test() {
         let mut response = Response::default_ok();
@@ -251,6 +737,205 @@ mod response_test {
     }
 
     #[test]
+    fn add_content_type_test() {
+        let mut response = Response::default_ok();
+        response.add_content_type("/map.png".to_string());
+        assert_eq!(response.headers.get("content-type").unwrap(), "image/png");
+        response.add_content_type("/stats".to_string());
+        assert_eq!(response.headers.get("content-type").unwrap(), "application/octet-stream");
+    }
+
+    fn conditional_request(header: &str) -> Request {
+        let raw = format!("GET /hello.html HTTP/1.1\r\n{}\r\n\r\n", header);
+        let limits = crate::request::RequestLimits { max_body_size: 0, max_header_size: 1024, read_timeout: std::time::Duration::from_secs(1) };
+        crate::request::parse_request(raw.as_bytes(), limits).unwrap().unwrap().0
+    }
+
+    fn static_file() -> StaticFile {
+        StaticFile::new(b"Hello".to_vec(), http_date::parse("Sun, 06 Nov 1994 08:49:37 GMT").map(Into::into))
+    }
+
+    #[test]
+    fn from_static_file_test() {
+        let response = Response::from_static_file(&conditional_request("Accept: */*"), "/hello.html".to_string(), static_file());
+        assert_eq!(response.response_identifiers.method.id, 200);
+        assert_eq!(response.headers.get("etag").unwrap(), &static_file().etag);
+        assert_eq!(response.headers.get("last-modified").unwrap(), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert!(!response.omit_body);
+    }
+
+    #[test]
+    fn from_static_file_not_modified_test() {
+        let etag = static_file().etag;
+        let not_modified = |header: &str| {
+            let response = Response::from_static_file(&conditional_request(header), "/hello.html".to_string(), static_file());
+            response.response_identifiers.method.id == 304 && response.omit_body
+        };
+        assert!(not_modified(&format!("If-None-Match: {}", etag)));
+        assert!(not_modified(&format!("If-None-Match: \"other\", W/{}", etag)));
+        assert!(not_modified("If-None-Match: *"));
+        assert!(!not_modified("If-None-Match: \"other\""));
+        assert!(not_modified("If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(not_modified("If-Modified-Since: Mon, 07 Nov 1994 08:49:37 GMT"));
+        assert!(!not_modified("If-Modified-Since: Sat, 05 Nov 1994 08:49:37 GMT"));
+        assert!(!not_modified("If-Modified-Since: yesterday"));
+        assert!(!not_modified("If-None-Match: \"other\"\r\nIf-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT"));
+    }
+
+    #[test]
+    fn from_static_file_range_test() {
+        let file = || StaticFile::new(b"Hello World".to_vec(), http_date::parse("Sun, 06 Nov 1994 08:49:37 GMT").map(Into::into));
+        let response = |header: &str| Response::from_static_file(&conditional_request(header), "/hello.txt".to_string(), file());
+
+        let single = response("Range: bytes=6-");
+        assert_eq!(single.response_identifiers.method.id, 206);
+        assert_eq!(single.headers.get("content-range").unwrap(), "bytes 6-10/11");
+        assert_eq!(single.body.to_vec().unwrap(), b"World");
+
+        let multi = response("Range: bytes=0-1,-2");
+        let boundary = format!("byteranges-{}", file().etag.trim_matches('"'));
+        assert_eq!(multi.response_identifiers.method.id, 206);
+        assert_eq!(multi.headers.get("content-type").unwrap(), &format!("multipart/byteranges; boundary={}", boundary));
+        assert_eq!(String::from_utf8(multi.body.to_vec().unwrap()).unwrap(), format!(
+            "\r\n--{b}\r\ncontent-type: text/plain; charset=utf-8\r\ncontent-range: bytes 0-1/11\r\n\r\nHe\
+             \r\n--{b}\r\ncontent-type: text/plain; charset=utf-8\r\ncontent-range: bytes 9-10/11\r\n\r\nld\
+             \r\n--{b}--\r\n", b = boundary));
+
+        let unsatisfiable = response("Range: bytes=11-");
+        assert_eq!(unsatisfiable.response_identifiers.method.id, 416);
+        assert_eq!(unsatisfiable.headers.get("content-range").unwrap(), "bytes */11");
+        assert_eq!(response("Range: lines=1-2").body.to_vec().unwrap(), b"Hello World");
+    }
+
+    #[test]
+    fn from_static_file_if_range_test() {
+        let etag = static_file().etag;
+        let status = |headers: &str| Response::from_static_file(&conditional_request(headers), "/hello.html".to_string(), static_file())
+            .response_identifiers.method.id;
+        assert_eq!(status(&format!("Range: bytes=0-1\r\nIf-Range: {}", etag)), 206);
+        assert_eq!(status(&format!("Range: bytes=0-1\r\nIf-Range: W/{}", etag)), 200);
+        assert_eq!(status("Range: bytes=0-1\r\nIf-Range: \"other\""), 200);
+        assert_eq!(status("Range: bytes=0-1\r\nIf-Range: Sun, 06 Nov 1994 08:49:37 GMT"), 206);
+        assert_eq!(status("Range: bytes=0-1\r\nIf-Range: Mon, 07 Nov 1994 08:49:37 GMT"), 200);
+    }
+
+    fn compressed_file() -> StaticFile {
+        let mut file = static_file();
+        file.encodings.insert(Encoding::Gzip, Body::shared(b"gzipped".to_vec()));
+        file
+    }
+
+    #[test]
+    fn from_static_file_precompressed_test() {
+        let response = Response::from_static_file(&conditional_request("Accept-Encoding: gzip, br"), "/hello.html".to_string(), compressed_file());
+        assert_eq!(response.headers.get("content-encoding").unwrap(), "gzip");
+        assert_eq!(response.headers.get("vary").unwrap(), "accept-encoding");
+        assert_eq!(response.headers.get("etag").unwrap(), &format!("{}-gzip\"", static_file().etag.trim_end_matches('"')));
+        assert_eq!(response.body.to_vec().unwrap(), b"gzipped");
+
+        let range = Response::from_static_file(&conditional_request("Accept-Encoding: gzip\r\nRange: bytes=0-1"), "/hello.html".to_string(), compressed_file());
+        assert_eq!(range.headers.get("content-range").unwrap(), "bytes 0-1/7");
+        assert_eq!(range.body.to_vec().unwrap(), b"gz");
+
+        let identity = Response::from_static_file(&conditional_request("Accept-Encoding: br"), "/hello.html".to_string(), compressed_file());
+        assert!(!identity.has_header("content-encoding"));
+        assert_eq!(identity.headers.get("vary").unwrap(), "accept-encoding");
+        assert_eq!(identity.body.to_vec().unwrap(), b"Hello");
+    }
+
+    #[test]
+    fn compress_response_test() {
+        let large = || StaticFile::new("Hello World ".repeat(100).into_bytes(), None);
+        let compressed = |header: &str, path: &str, mode: CompressionMode| {
+            let request = conditional_request(header);
+            let mut response = Response::from_static_file(&request, path.to_string(), large());
+            compress_response(&request, &mut response, mode);
+            response
+        };
+
+        let gzip = compressed("Accept-Encoding: gzip", "/hello.txt", CompressionMode::OnTheFly);
+        assert_eq!(gzip.headers.get("content-encoding").unwrap(), "gzip");
+        assert_eq!(gzip.headers.get("vary").unwrap(), "accept-encoding");
+        assert_eq!(gzip.headers.get("etag").unwrap(), &format!("W/{}", large().etag));
+        assert!(gzip.body.len() < large().content.len());
+
+        let identity = compressed("Accept: */*", "/hello.txt", CompressionMode::OnTheFly);
+        assert!(!identity.has_header("content-encoding"));
+        assert_eq!(identity.headers.get("vary").unwrap(), "accept-encoding");
+
+        let partial = compressed("Accept-Encoding: gzip\r\nRange: bytes=0-1", "/hello.txt", CompressionMode::OnTheFly);
+        assert!(!partial.has_header("content-encoding"));
+
+        let image = compressed("Accept-Encoding: gzip", "/hello.png", CompressionMode::OnTheFly);
+        assert!(!image.has_header("content-encoding") && !image.has_header("vary"));
+
+        let off = compressed("Accept-Encoding: gzip", "/hello.txt", CompressionMode::Off);
+        assert!(!off.has_header("content-encoding") && !off.has_header("vary"));
+        assert!(off.has_header("accept-ranges"));
+        assert!(!gzip.has_header("accept-ranges"));
+    }
+
+    #[test]
+    fn compress_response_without_body_test() {
+        let file = StaticFile::new("Hello World ".repeat(100).into_bytes(), None);
+        let request = conditional_request(&format!("Accept-Encoding: gzip\r\nIf-None-Match: W/{}", file.etag));
+        let mut not_modified = Response::from_static_file(&request, "/hello.txt".to_string(), file.clone());
+        compress_response(&request, &mut not_modified, CompressionMode::OnTheFly);
+        assert_eq!(not_modified.response_identifiers.method.id, 304);
+        assert_eq!(not_modified.headers.get("content-encoding").unwrap(), "gzip");
+        assert_eq!(not_modified.body.len(), file.content.len());
+
+        let mut head = Response::from_static_file(&conditional_request("Accept-Encoding: gzip"), "/hello.txt".to_string(), file.clone());
+        head.omit_body = true;
+        head.keep_alive = true;
+        compress_response(&conditional_request("Accept-Encoding: gzip"), &mut head, CompressionMode::OnTheFly);
+        assert_eq!(head.body.len(), file.content.len());
+        let sendable = String::from_utf8(head.make_head_sendable()).unwrap();
+        assert!(sendable.contains("content-encoding: gzip\r\n"));
+        assert!(sendable.contains("connection: keep-alive\r\n"));
+        assert!(!sendable.contains("content-length"));
+    }
+
+    #[test]
+    fn from_stream_test() {
+        let chunks = || vec![b"Hello".to_vec(), b" World".to_vec()].into_iter();
+        let mut chunked = Response::from_stream(&conditional_request("Accept: */*"), chunks());
+        chunked.keep_alive = true;
+        let sendable = String::from_utf8(chunked.make_sendable()).unwrap();
+        assert!(sendable.contains("transfer-encoding: chunked\r\n"));
+        assert!(sendable.contains("connection: keep-alive\r\n"));
+        assert!(!sendable.contains("content-length"));
+        assert!(sendable.ends_with("\r\n\r\n5\r\nHello\r\n6\r\n World\r\n0\r\n\r\n"));
+
+        let limits = crate::request::RequestLimits { max_body_size: 0, max_header_size: 1024, read_timeout: std::time::Duration::from_secs(1) };
+        let old_request = crate::request::parse_request(b"GET /stats HTTP/1.0\r\n\r\n", limits).unwrap().unwrap().0;
+        let mut closing = Response::from_stream(&old_request, chunks());
+        closing.keep_alive = true;
+        let sendable = String::from_utf8(closing.make_sendable()).unwrap();
+        assert!(!sendable.contains("transfer-encoding"));
+        assert!(sendable.contains("connection: close\r\n"));
+        assert!(sendable.ends_with("\r\n\r\nHello World"));
+    }
+
+    #[test]
+    fn make_header_fields_test() {
+        let mut response = without_date_and_server();
+        response.add_header("Transfer-Encoding", "chunked");
+        response.add_header("Content-Type", "text/plain");
+        response.body = b"Hello".to_vec().into();
+        response.omit_body = true;
+        let mut fields = response.make_header_fields();
+        fields.sort();
+        assert_eq!(fields, vec![
+            (":status".to_string(), "200".to_string()),
+            ("content-length".to_string(), "5".to_string()),
+            ("content-type".to_string(), "text/plain".to_string()),
+        ]);
+        assert_eq!(response.body.len(), 0);
+    }
+
+    #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn add_header_test() {
         let mut response = Response::default_ok();
         response.add_header("content-type", "text/json");
@@ -0,0 +1,117 @@
+//! Module shutting the servers down gracefully on SIGINT and SIGTERM
+//!
+//! Once a signal arrives, the servers stop accepting connections and finish the requests they already accepted,
+//! persistent connections are closed after their current response. Whatever is still running when the
+//! shutdown timeout expired is abandoned. A second signal exits right away.
+
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// whether a signal asked the servers to shut down
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+/// how long the servers have to finish after the signal
+static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+/// the time the servers have to be finished by, set once the shutdown is requested
+static DEADLINE: OnceLock<Instant> = OnceLock::new();
+/// the addresses of the listeners, connected to once to return from their blocking accept
+static LISTENERS: Mutex<Vec<SocketAddr>> = Mutex::new(Vec::new());
+
+/// How long to wait for the threads to finish, if no timeout was configured
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the threads are checked while waiting for them
+const JOIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Handles SIGINT and SIGTERM from now on, giving the servers the timeout to finish
+pub fn install(timeout: Duration) -> Result<(), String> {
+    let _ = TIMEOUT.set(timeout);
+    ctrlc::set_handler(request).map_err(|error| format!("Could not handle the shutdown signals: {}", error))
+}
+
+/// Asks the servers to shut down, exiting immediately if they were already asked before
+pub fn request() {
+    if REQUESTED.swap(true, Ordering::SeqCst) {
+        println!("Shutting down immediately");
+        std::process::exit(130);
+    }
+    let timeout = timeout();
+    let _ = DEADLINE.set(Instant::now() + timeout);
+    println!("Shutting down, the accepted requests have {}ms to finish. Send the signal again to exit immediately", timeout.as_millis());
+    for address in LISTENERS.lock().unwrap().iter() {
+        let _ = TcpStream::connect_timeout(address, JOIN_INTERVAL);
+    }
+}
+
+/// Whether the servers should stop accepting connections and close the open ones after their current response
+pub fn is_requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// The time the servers have to be finished by
+///
+/// Without a requested shutdown, e.g. when a server stops because of an error, the timeout starts now.
+pub fn deadline() -> Instant {
+    DEADLINE.get().copied().unwrap_or_else(|| Instant::now() + timeout())
+}
+
+fn timeout() -> Duration {
+    TIMEOUT.get().copied().unwrap_or(DEFAULT_TIMEOUT)
+}
+
+/// Lets the blocking accept of the listener return once the shutdown is requested
+///
+/// Returns whether the shutdown was already requested, so the listener should not accept at all.
+pub fn wake_on_shutdown(listener: &TcpListener) -> bool {
+    if let Ok(mut address) = listener.local_addr() {
+        if address.ip().is_unspecified() {
+            address.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        LISTENERS.lock().unwrap().push(address);
+    }
+    is_requested()
+}
+
+/// Waits for the threads to finish until the deadline, returning how many are still running afterwards
+pub fn join_until(threads: Vec<JoinHandle<()>>, deadline: Instant) -> usize {
+    let mut running = threads;
+    loop {
+        let (finished, unfinished): (Vec<JoinHandle<()>>, Vec<JoinHandle<()>>) = running.into_iter()
+            .partition(|thread| thread.is_finished());
+        for thread in finished {
+            let _ = thread.join();
+        }
+        running = unfinished;
+        if running.is_empty() || Instant::now() >= deadline {
+            return running.len();
+        }
+        thread::sleep(JOIN_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_until_test() {
+        let quick = thread::spawn(|| {});
+        let slow = thread::spawn(|| thread::sleep(Duration::from_millis(500)));
+        let start = Instant::now();
+        assert_eq!(join_until(vec![quick, slow], Instant::now() + Duration::from_millis(50)), 1);
+        assert!(start.elapsed() < Duration::from_millis(400));
+
+        let threads = (0..4).map(|_| thread::spawn(|| thread::sleep(Duration::from_millis(20)))).collect();
+        assert_eq!(join_until(threads, Instant::now() + Duration::from_secs(5)), 0);
+    }
+
+    #[test]
+    fn wake_on_shutdown_test() {
+        let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+        assert!(!wake_on_shutdown(&listener));
+        let address = *LISTENERS.lock().unwrap().last().unwrap();
+        assert_eq!(address.ip(), Ipv4Addr::LOCALHOST);
+        assert_eq!(address.port(), listener.local_addr().unwrap().port());
+    }
+}
@@ -1,12 +1,18 @@
-use crate::StaticFiles;
+use crate::file::ServerFiles;
 use std::thread;
 use std::net::TcpListener;
-use crate::response::{Response, send_response};
-use std::io::{ Read};
-use crate::request::parse_request;
+use crate::connection::{Connection, TlsConfig};
+use crate::response::{Response, send_response, create_response};
+use crate::request::{KeepAlive, RequestLimits};
+use crate::reader::{read_request, ReadError, RequestBuffer};
+use crate::shutdown;
 
 
-pub fn start_server(ip: String, port: i32, dir: StaticFiles) {
+/// Starts the single thread server, spawning a thread for every connection
+///
+/// With a TLS config the connections are encrypted, the handshake is done by the spawned threads.
+/// On shutdown the threads of the accepted connections are joined until the deadline.
+pub fn start_server(ip: String, port: i32, files: ServerFiles, limits: RequestLimits, keep_alive: KeepAlive, tls: Option<TlsConfig>) {
     let address = format!("{}:{}", ip, port);
 
     let listener = match TcpListener::bind(address) {
@@ -16,54 +22,70 @@ pub fn start_server(ip: String, port: i32, dir: StaticFiles) {
             return;
         }
     };
+    if shutdown::wake_on_shutdown(&listener) {
+        return;
+    }
+    let mut connections = Vec::new();
     for stream in listener.incoming() {
-        let mut connection = match stream {
-            Ok(stream) => stream,
+        if shutdown::is_requested() {
+            break;
+        }
+        let connection = match stream.and_then(|stream| Connection::new(stream, tls.as_ref())) {
+            Ok(connection) => connection,
             Err(_) => {
                 println!("Connection error. Ignoring request");
                 continue;
             }
         };
 
-        let dir = dir.clone();
+        let files = files.clone();
 
-        match thread::Builder::new().spawn(move || {
-            let mut buffer = [0; 2048];
-            match connection.read(&mut buffer) {
-                Ok(request) => { request }
-                Err(_) => {
-                    println!("Connection error. Could not read from socket");
-                    return;
-                }
-            };
-            let request = match parse_request(buffer.to_vec()) {
-                Ok(request) => request,
-                Err(_) => {
-                    let mut response = Response::default_bad_request();
-                    send_response(connection, &mut response);
-                    return;
-                }
-            };
-            let mut response = match dir.get(&request.request_identifiers.path) {
-                Some(resource) => {
-                    let mut response = Response::default_ok();
-                    &response.add_content_type(request.request_identifiers.path);
-                    response.body = resource.clone();
-                    response
-                }
-                None => {
-                    let mut response = Response::default_not_found();
-                    send_response(connection, &mut response);
-                    return;
-                }
-            };
-            send_response(connection, &mut response);
-        }) {
+        let spawned = thread::Builder::new().spawn(move || handle_connection(connection, files, limits, keep_alive));
+        match spawned {
+            Ok(thread) => connections.push(thread),
             Err(_) => {
                 println!("No more resources for creating thread");
+                break;
+            }
+        }
+        connections.retain(|thread| !thread.is_finished());
+    }
+    drop(listener);
+    let running = shutdown::join_until(connections, shutdown::deadline());
+    if running > 0 {
+        println!("{} connections did not finish in time", running);
+    }
+}
+
+/// Answers the requests of the connection, as long as it is kept alive
+fn handle_connection(mut connection: Connection, files: ServerFiles, limits: RequestLimits, keep_alive: KeepAlive) {
+    let mut buffer = RequestBuffer::new(limits);
+    loop {
+        let request = match read_request(&mut connection, &mut buffer) {
+            Ok(request) => request,
+            Err(ReadError::Invalid(error)) => {
+                let mut response = Response::default_invalid_request(&error);
+                let _ = send_response(&mut connection, &mut response);
                 return;
             }
-            _ => {}
+            Err(ReadError::Closed) => return
+        };
+        let mut response = create_response(&request, &files);
+        response.keep_alive = keep_alive.allows(&request, buffer.requests_taken()) && !shutdown::is_requested();
+        if send_response(&mut connection, &mut response).is_err() || !response.keep_alive {
+            return;
         }
+        buffer.wait_for_next(keep_alive.timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_client::{check_connection_loop, server_files, LIMITS};
+
+    #[test]
+    fn connection_loop_test() {
+        check_connection_loop(|connection, keep_alive| handle_connection(connection, server_files(), LIMITS, keep_alive));
     }
-}
\ No newline at end of file
+}
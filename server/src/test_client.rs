@@ -0,0 +1,122 @@
+//! The loopback tests of the keep-alive loops the servers run for each connection
+
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crate::compression::CompressionMode;
+use crate::connection::Connection;
+use crate::file::{ServerFiles, StaticFile};
+use crate::request::{KeepAlive, RequestLimits};
+
+pub const LIMITS: RequestLimits = RequestLimits { max_body_size: 1024, max_header_size: 1024, read_timeout: Duration::from_secs(5) };
+/// How long the client waits for the server, before the test fails
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The served files, only /hello.html
+pub fn server_files() -> ServerFiles {
+    let mut static_files = HashMap::new();
+    static_files.insert("/hello.html".to_string(), StaticFile::new(b"Hello".to_vec(), None));
+    ServerFiles {
+        static_files: Arc::new(RwLock::new(Arc::new(static_files))),
+        dynamic_files: Arc::new(HashMap::new()),
+        directory_listing: false,
+        compression: CompressionMode::Off,
+        disk_files: None,
+    }
+}
+
+/// Runs the requests of the keep-alive tests against a connection loop
+///
+/// The loop serves the accepted end of a loopback connection until it closes it, and has to return afterwards.
+pub fn check_connection_loop(serve: fn(Connection, KeepAlive)) {
+    let keep_alive = KeepAlive { timeout: CLIENT_TIMEOUT, max_requests: 100 };
+
+    // several requests on one stream, also if they are sent at once
+    let (mut client, server) = connect(serve, keep_alive);
+    for _ in 0..3 {
+        let head = send(&mut client, "GET /hello.html HTTP/1.1\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains("connection: keep-alive\r\n"), "{}", head);
+    }
+    client.write_all(b"GET /hello.html HTTP/1.1\r\n\r\nGET /missing.html HTTP/1.1\r\n\r\n").unwrap();
+    assert!(read_response(&mut client).unwrap().starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(read_response(&mut client).unwrap().starts_with("HTTP/1.1 404 Not Found\r\n"));
+    drop(client);
+    server.join().unwrap();
+
+    // the client closes the connection
+    let (mut client, server) = connect(serve, keep_alive);
+    assert!(send(&mut client, "GET /hello.html HTTP/1.1\r\nConnection: close\r\n\r\n").contains("connection: close\r\n"));
+    assert_closed(&mut client);
+    server.join().unwrap();
+
+    // HTTP/1.0 closes the connection, unless keep-alive is asked for
+    let (mut client, server) = connect(serve, keep_alive);
+    assert!(send(&mut client, "GET /hello.html HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").contains("connection: keep-alive\r\n"));
+    assert!(send(&mut client, "GET /hello.html HTTP/1.0\r\n\r\n").contains("connection: close\r\n"));
+    assert_closed(&mut client);
+    server.join().unwrap();
+
+    // the connection is closed after the most requests
+    let (mut client, server) = connect(serve, KeepAlive { max_requests: 2, ..keep_alive });
+    assert!(send(&mut client, "GET /hello.html HTTP/1.1\r\n\r\n").contains("connection: keep-alive\r\n"));
+    assert!(send(&mut client, "GET /hello.html HTTP/1.1\r\n\r\n").contains("connection: close\r\n"));
+    assert_closed(&mut client);
+    server.join().unwrap();
+
+    // an idle connection is closed after the timeout
+    let idle_timeout = Duration::from_millis(200);
+    let (mut client, server) = connect(serve, KeepAlive { timeout: idle_timeout, ..keep_alive });
+    assert!(send(&mut client, "GET /hello.html HTTP/1.1\r\n\r\n").contains("connection: keep-alive\r\n"));
+    let start = Instant::now();
+    assert_closed(&mut client);
+    assert!(start.elapsed() >= idle_timeout / 2);
+    server.join().unwrap();
+}
+
+/// Connects a client to a loopback listener, serving the other end on a thread of its own
+fn connect(serve: fn(Connection, KeepAlive), keep_alive: KeepAlive) -> (TcpStream, JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    client.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+    let connection = Connection::new(listener.accept().unwrap().0, None).unwrap();
+    (client, thread::spawn(move || serve(connection, keep_alive)))
+}
+
+/// Sends the request and reads its response, returning the head
+fn send(client: &mut TcpStream, request: &str) -> String {
+    client.write_all(request.as_bytes()).unwrap();
+    read_response(client).expect("The connection was closed before the response")
+}
+
+/// Reads the next response, returning its head, or none if the connection was closed before
+fn read_response(client: &mut TcpStream) -> Option<String> {
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if client.read(&mut byte).unwrap() == 0 {
+            assert!(head.is_empty(), "The connection was closed within the response");
+            return None;
+        }
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    let content_length = head.lines()
+        .find_map(|line| line.strip_prefix("content-length: "))
+        .map_or(0, |length| length.parse().unwrap());
+    let mut body = vec![0; content_length];
+    client.read_exact(&mut body).unwrap();
+    Some(head)
+}
+
+/// Asserts that the server closes the connection without sending anything else
+fn assert_closed(client: &mut TcpStream) {
+    match client.read(&mut [0; 1]) {
+        Ok(read) => assert_eq!(read, 0, "The server sent more data"),
+        Err(error) if error.kind() == ErrorKind::ConnectionReset => {}
+        Err(error) => panic!("The server did not close the connection: {}", error)
+    }
+}
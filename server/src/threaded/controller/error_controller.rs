@@ -1,6 +1,14 @@
 use crate::response::Response;
+use crate::request::InvalidRequest;
 use crate::DynamicFiles;
 
+/// Standard dynamic error response for a request that could not be parsed
+pub fn error_response_invalid_request(error: &InvalidRequest, dynamic_files: DynamicFiles) -> Response {
+    let mut response = Response::default_invalid_request(error);
+    response.dynamic_error_response(format!("{}", error), dynamic_files);
+    response
+}
+
 /// Standard dynamic 400 error response
 pub fn error_response_400(error_message: String, dynamic_files: DynamicFiles) -> Response {
     let mut response = Response::default_bad_request();
@@ -15,10 +23,23 @@ pub fn error_response_404(error_message: String, dynamic_files: DynamicFiles) ->
     response
 }
 
+/// Standard dynamic 405 error response, listing the allowed methods
+pub fn error_response_405(error_message: String, dynamic_files: DynamicFiles) -> Response {
+    let mut response = Response::default_method_not_allowed();
+    response.dynamic_error_response(error_message, dynamic_files);
+    response
+}
+
+/// Standard dynamic 426 error response, naming the protocol the resource is only served with
+pub fn error_response_426(error_message: String, protocol: &str, dynamic_files: DynamicFiles) -> Response {
+    let mut response = Response::default_upgrade_required(protocol);
+    response.dynamic_error_response(error_message, dynamic_files);
+    response
+}
+
 /// Standard dynamic 500 error response
-#[allow(dead_code)]
 pub fn error_response_500(error_message: String, dynamic_files: DynamicFiles) -> Response {
-    let mut response = Response::default_not_found();
+    let mut response = Response::default_internal_server_error();
     response.dynamic_error_response(error_message, dynamic_files);
     response
 }
@@ -1,16 +1,22 @@
+use crate::request::Request;
 use crate::response::Response;
 use crate::threaded::controller::error_controller::error_response_404;
-use crate::threaded::server::ServerFiles;
+use crate::file::ServerFiles;
+use crate::directory::{resolve, Resource};
 
-/// Endpoint that serves a static file
-pub fn file_response(server_files: ServerFiles, path: String) -> Result<Response, Response> {
-    match server_files.static_files.get(&path) {
-        Some(resource) => {
+/// Endpoint that serves a static file, or the index file or listing of a directory
+#[allow(clippy::result_large_err)]
+pub fn file_response(server_files: ServerFiles, request: &Request) -> Result<Response, Response> {
+    let path = &request.request_identifiers.path;
+    match resolve(path, request.request_identifiers.query.as_deref(), &server_files) {
+        Resource::File(file_path, file) => Ok(Response::from_static_file(request, file_path, file)),
+        Resource::Listing(html) => {
             let mut response = Response::default_ok();
-            &response.add_content_type(path);
-            response.body = resource.clone();
+            response.add_content_type("_.html".to_string());
+            response.body = html.into();
             Ok(response)
         }
-        None => Err(error_response_404(format!("Requested resource {} could not be found.", path), server_files.dynamic_files))
+        Resource::Redirect(location) => Ok(Response::default_moved_permanently(&location)),
+        Resource::Missing => Err(error_response_404(format!("Requested resource {} could not be found.", path), server_files.dynamic_files))
     }
 }
\ No newline at end of file
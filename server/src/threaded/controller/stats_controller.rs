@@ -1,12 +1,26 @@
-use crate::response::Response;
-use crate::threaded::server::{ServerStats, RequestResult};
-use std::sync::{Arc, MutexGuard};
+use crate::connection::Connection;
+use crate::request::Request;
+use crate::response::{send_response, Response};
+use crate::threaded::server::{PoolMetrics, ServerStats, RequestResult};
+use crate::threaded::controller::error_controller::error_response_400;
+use crate::threaded::websocket::{handshake_response, WebSocket, CLOSE_GOING_AWAY, CLOSE_NORMAL};
+use crate::shutdown;
+use crate::uri::QueryParams;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::collections::HashMap;
+use std::time::Duration;
+
+/// The amount of requests exported per chunk of the csv export
+const EXPORT_CHUNK_SIZE: usize = 256;
+/// How long the live stats wait for new requests, before looking for frames of the client
+const LIVE_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
 /// A wrapper for extended stats about the server
 #[derive(Debug)]
 struct ResultView {
     request_successes: Vec<bool>,
+    #[allow(dead_code)]
     path_counts: HashMap<String, PathCount>
 }
 
@@ -27,6 +41,7 @@ impl ResultView {
 /// A struct to represent which path has been requested how many times
 #[derive(Debug)]
 struct PathCount {
+    #[allow(dead_code)]
     path: String,
     num_requested: i32
 }
@@ -37,36 +52,200 @@ impl PathCount {
     }
 }
 
+/// The filtering and pagination of the stats, requested via the query string
+///
+/// E.g. /stats?path=/hello.html&code=200&offset=100&limit=50
+#[derive(Debug, Default, Eq, PartialEq)]
+struct StatsFilter {
+    /// only requests of these paths, all paths if empty
+    paths: Vec<String>,
+    /// only requests answered with this code
+    code: Option<u32>,
+    /// the amount of matching entries skipped in the table
+    offset: usize,
+    /// the maximum amount of entries in the table
+    limit: Option<usize>,
+}
+
+impl StatsFilter {
+    /// Reads the filter from the query parameters, returning an error message for invalid values
+    fn from_params(params: &QueryParams) -> Result<Self, String> {
+        Ok(Self {
+            paths: params.get_all("path").to_vec(),
+            code: parse_param(params, "code")?,
+            offset: parse_param(params, "offset")?.unwrap_or(0),
+            limit: parse_param(params, "limit")?,
+        })
+    }
+
+    fn matches(&self, result: &RequestResult) -> bool {
+        (self.paths.is_empty() || self.paths.contains(&result.requested_resource))
+            && self.code.is_none_or(|code| code == result.response_code)
+    }
+}
+
+/// Parses the numeric query parameter, if it was sent
+fn parse_param<T: std::str::FromStr>(params: &QueryParams, key: &str) -> Result<Option<T>, String> {
+    match params.get(key) {
+        Some(value) => value.parse::<T>()
+            .map(Some)
+            .map_err(|_| format!("Query parameter {} has to be a positive number, but was {}.", key, value)),
+        None => Ok(None)
+    }
+}
+
 /// Endpoint returns the accumulated stats about the server via html file
-pub fn stats_response(stats: Arc<ServerStats>, resources: Arc<HashMap<String, String>>) -> Result<Response, Response> {
-    let results = stats.request_results.lock().unwrap();
+///
+/// The counts cover all requests matching the filter, the table only the requested page of them.
+#[allow(clippy::result_large_err)]
+pub fn stats_response(stats: Arc<ServerStats>, resources: Arc<HashMap<String, String>>, params: &QueryParams) -> Result<Response, Response> {
+    let filter = StatsFilter::from_params(params).map_err(|message| error_response_400(message, resources.clone()))?;
+    let results = stats.results();
+    let results: Vec<&RequestResult> = results.iter().filter(|result| filter.matches(result)).collect();
 
     let request_successes: Vec<bool> = results.iter().map(|result| result.is_successful()).collect();
 
     let mut path_counts: HashMap<String, PathCount> = HashMap::new();
-    &results.iter().for_each(|result| {
-        let resource = (&result.requested_resource).to_string();
+    results.iter().for_each(|result| {
+        let resource = result.requested_resource.to_string();
         path_counts.entry(resource.to_string()).or_insert(PathCount{path: resource.to_string(), num_requested: 0}).increase_num();
     });
 
     let result_view = ResultView{request_successes, path_counts};
 
-    let html = build_html(resources, results, result_view);
+    let page = results.iter()
+        .skip(filter.offset)
+        .take(filter.limit.unwrap_or(usize::MAX))
+        .copied()
+        .collect::<Vec<&RequestResult>>();
+    let html = build_html(resources, &page, result_view, &stats.pool);
 
     let mut response = Response::default_ok();
-    &response.add_content_type("_.html".to_string());
-    response.body = html.as_bytes().to_vec();
+    response.add_content_type("_.html".to_string());
+    response.body = html.into_bytes().into();
 
     Ok(response)
 }
 
+/// Endpoint streams all requests matching the filter as csv, chunk by chunk while the response is sent
+///
+/// E.g. /stats/export?code=404
+/// The export covers the requests made before it started. The stats are only locked while a chunk is built,
+/// so the export never holds all requests in memory and does not block the other workers.
+#[allow(clippy::result_large_err)]
+pub fn stats_export_response(stats: Arc<ServerStats>, resources: Arc<HashMap<String, String>>, request: &Request) -> Result<Response, Response> {
+    let filter = StatsFilter::from_params(&request.query_params).map_err(|message| error_response_400(message, resources))?;
+    let end = stats.results().len();
+    let mut position = 0;
+    let mut matched = 0;
+
+    let rows = std::iter::from_fn(move || {
+        if position >= end {
+            return None;
+        }
+        let results = stats.results();
+        let chunk_end = (position + EXPORT_CHUNK_SIZE).min(end);
+        let mut chunk = String::new();
+        for result in results[position..chunk_end].iter().filter(|result| filter.matches(result)) {
+            if filter.limit.is_some_and(|limit| matched >= filter.offset + limit) {
+                position = end;
+                return Some(chunk.into_bytes());
+            }
+            if matched >= filter.offset {
+                chunk.push_str(&csv_row(result));
+            }
+            matched += 1;
+        }
+        position = chunk_end;
+        Some(chunk.into_bytes())
+    });
+    let head = std::iter::once(b"time,path,code,duration_ms\n".to_vec());
+
+    let mut response = Response::from_stream(request, head.chain(rows));
+    response.add_content_type("_.csv".to_string());
+    Ok(response)
+}
+
+/// Endpoint upgrades the connection to a WebSocket and sends each new request matching the filter as JSON text message
+///
+/// E.g. ws://localhost:9000/stats/live?code=404
+/// Only requests recorded after the handshake are sent, the limit closes the WebSocket once reached.
+/// The calling thread keeps serving the WebSocket until either side closes it.
+/// Returns the sent handshake response, or the error response which still has to be sent for an invalid filter.
+#[allow(clippy::result_large_err)]
+pub fn stats_live_response(stream: &mut Connection, request: &Request, key: &str, stats: Arc<ServerStats>, resources: Arc<HashMap<String, String>>) -> Result<Response, Response> {
+    let filter = StatsFilter::from_params(&request.query_params).map_err(|message| error_response_400(message, resources))?;
+    let mut response = handshake_response(key);
+    if send_response(stream, &mut response).is_ok() {
+        let mut socket = WebSocket::new(stream);
+        if send_live_stats(&mut socket, stats, filter).is_ok() {
+            let _ = socket.close(if shutdown::is_requested() { CLOSE_GOING_AWAY } else { CLOSE_NORMAL });
+        }
+    }
+    Ok(response)
+}
+
+/// Sends the new requests matching the filter, until the client closes the WebSocket, the limit is reached or the server shuts down
+fn send_live_stats(socket: &mut WebSocket, stats: Arc<ServerStats>, filter: StatsFilter) -> std::io::Result<()> {
+    let mut position = stats.results().len();
+    let mut matched = 0;
+    let end = filter.limit.map(|limit| filter.offset + limit);
+    while end.is_none_or(|end| matched < end) && !shutdown::is_requested() {
+        let messages: Vec<String> = {
+            let results = stats.wait_for_results(position, LIVE_POLL_INTERVAL);
+            let messages = results[position..].iter().filter(|result| filter.matches(result)).map(json_row).collect();
+            position = results.len();
+            messages
+        };
+        for message in messages.iter().take(end.map_or(usize::MAX, |end| end - matched)) {
+            if matched >= filter.offset {
+                socket.send_text(message)?;
+            }
+            matched += 1;
+        }
+        if !socket.receive()? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Formats the request as JSON object for the live stats
+fn json_row(result: &RequestResult) -> String {
+    format!("{{\"time\":\"{}\",\"path\":\"{}\",\"code\":{},\"duration_ms\":{}}}", result.time, json_escape(&result.requested_resource), result.response_code, result.duration)
+}
+
+/// Escapes the text for a JSON string
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            character if character.is_control() => escaped.push_str(&format!("\\u{:04x}", character as u32)),
+            character => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+/// Formats the request as a line of the csv export, quoting the path as it may contain commas
+fn csv_row(result: &RequestResult) -> String {
+    format!("{},\"{}\",{},{}\n", result.time, result.requested_resource.replace('"', "\"\""), result.response_code, result.duration)
+}
+
 /// Builds the html file to display the stats dynamically
-fn build_html(resources: Arc<HashMap<String, String>>, results: MutexGuard<Vec<RequestResult>>, result_view: ResultView) -> String {
+fn build_html(resources: Arc<HashMap<String, String>>, results: &[&RequestResult], result_view: ResultView, pool: &PoolMetrics) -> String {
     let mut html = resources.get("/stats.html").unwrap().to_string();
 
     html = html.replace("{{num_total}}", &result_view.num_total().to_string());
     html = html.replace("{{num_successful}}", &result_view.num_successful().to_string());
     html = html.replace("{{num_unsuccessful}}", &result_view.num_unsuccessful().to_string());
+    html = html.replace("{{queue_depth}}", &pool.depth.load(Ordering::Relaxed).to_string());
+    html = html.replace("{{queue_max_depth}}", &pool.max_depth.load(Ordering::Relaxed).to_string());
+    html = html.replace("{{queue_rejected}}", &pool.rejected.load(Ordering::Relaxed).to_string());
+    html = html.replace("{{pool_panicked}}", &pool.panicked.load(Ordering::Relaxed).to_string());
+    html = html.replace("{{pool_respawned}}", &pool.respawned.load(Ordering::Relaxed).to_string());
 
     let table_entry = resources.get("/stats_table_entry.html").unwrap().to_string();
     let mut table_entries = String::new();
@@ -83,4 +262,128 @@ fn build_html(resources: Arc<HashMap<String, String>>, results: MutexGuard<Vec<R
     html = html.replace("{{result_entries}}", &table_entries);
 
     html
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn result(path: &str, response_code: u32) -> RequestResult {
+        RequestResult { response_code, requested_resource: path.to_string(), time: Utc::now().naive_local(), duration: 0 }
+    }
+
+    fn export(results: Vec<RequestResult>, target: &str) -> String {
+        let stats = Arc::new(ServerStats::new(results));
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+        let limits = crate::request::RequestLimits { max_body_size: 0, max_header_size: 1024, read_timeout: std::time::Duration::from_secs(1) };
+        let request = crate::request::parse_request(raw.as_bytes(), limits).unwrap().unwrap().0;
+        let response = stats_export_response(stats, Arc::new(HashMap::new()), &request).unwrap();
+        let mut csv = Vec::new();
+        while let Some(chunk) = response.body.next_chunk() {
+            csv.extend_from_slice(&chunk);
+        }
+        String::from_utf8(csv).unwrap()
+    }
+
+    #[test]
+    fn stats_export_response_test() {
+        let results = || (0..EXPORT_CHUNK_SIZE * 2).map(|index| result(&format!("/{}.html", index), if index % 2 == 0 { 200 } else { 404 })).collect();
+
+        let csv = export(results(), "/stats/export");
+        assert!(csv.starts_with("1b\r\ntime,path,code,duration_ms\n\r\n"));
+        assert!(csv.ends_with("\r\n0\r\n\r\n"));
+        assert_eq!(csv.matches(",200,").count() + csv.matches(",404,").count(), EXPORT_CHUNK_SIZE * 2);
+
+        assert_eq!(export(results(), "/stats/export?code=404&offset=1").matches(",404,").count(), EXPORT_CHUNK_SIZE - 1);
+        let csv = export(results(), "/stats/export?code=404&offset=1&limit=200");
+        assert_eq!(csv.matches(",404,").count(), 200);
+        assert!(!csv.contains("\"/1.html\""));
+        assert!(csv.contains("\"/3.html\""));
+        assert!(!csv.contains(",200,"));
+    }
+
+    #[test]
+    fn csv_row_test() {
+        let row = csv_row(&result("/a,\"b\".html", 200));
+        assert!(row.ends_with(",\"/a,\"\"b\"\".html\",200,0\n"));
+    }
+
+    #[test]
+    fn json_row_test() {
+        let row = json_row(&result("/a \"b\"\\\n.html", 404));
+        assert!(row.starts_with("{\"time\":\""));
+        assert!(row.ends_with("\",\"path\":\"/a \\\"b\\\"\\\\\\u000a.html\",\"code\":404,\"duration_ms\":0}"));
+    }
+
+    #[test]
+    fn stats_filter_from_params_test() {
+        let filter = StatsFilter::from_params(&QueryParams::parse("path=/a.html&path=/b.html&code=404&offset=10&limit=50")).unwrap();
+        assert_eq!(filter, StatsFilter {
+            paths: vec!["/a.html".to_string(), "/b.html".to_string()],
+            code: Some(404),
+            offset: 10,
+            limit: Some(50),
+        });
+        assert_eq!(StatsFilter::from_params(&QueryParams::parse("")).unwrap(), StatsFilter::default());
+        assert!(StatsFilter::from_params(&QueryParams::parse("limit=-1")).is_err());
+        assert!(StatsFilter::from_params(&QueryParams::parse("offset=ten")).is_err());
+    }
+
+    #[test]
+    fn stats_filter_matches_test() {
+        let filter = StatsFilter::from_params(&QueryParams::parse("path=/a.html&code=200")).unwrap();
+        assert!(filter.matches(&result("/a.html", 200)));
+        assert!(!filter.matches(&result("/a.html", 404)));
+        assert!(!filter.matches(&result("/b.html", 200)));
+        assert!(StatsFilter::default().matches(&result("/b.html", 404)));
+    }
+
+    #[test]
+    fn stats_live_response_test() {
+        use std::io::Read;
+        use std::net::{TcpListener, TcpStream};
+        use std::sync::atomic::AtomicBool;
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut connection = Connection::new(listener.accept().unwrap().0, None).unwrap();
+        let stats = Arc::new(ServerStats::new(vec![result("/before.html", 404)]));
+
+        let raw = "GET /stats/live?code=404&limit=1 HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        let limits = crate::request::RequestLimits { max_body_size: 0, max_header_size: 1024, read_timeout: Duration::from_secs(1) };
+        let request = crate::request::parse_request(raw.as_bytes(), limits).unwrap().unwrap().0;
+        let live_stats = stats.clone();
+        let server = thread::spawn(move || {
+            let response = stats_live_response(&mut connection, &request, "dGhlIHNhbXBsZSBub25jZQ==", live_stats, Arc::new(HashMap::new())).unwrap();
+            assert_eq!(response.response_identifiers.method.id, 101);
+        });
+
+        // the subscriber only gets the requests recorded after its handshake, so they are recorded until it is done
+        let done = Arc::new(AtomicBool::new(false));
+        let recorder = {
+            let (stats, done) = (stats.clone(), done.clone());
+            thread::spawn(move || while !done.load(Ordering::SeqCst) {
+                stats.record(result("/ok.html", 200));
+                stats.record(result("/missing.html", 404));
+                thread::sleep(Duration::from_millis(10));
+            })
+        };
+        server.join().unwrap();
+        done.store(true, Ordering::SeqCst);
+        recorder.join().unwrap();
+
+        let mut received = Vec::new();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.read_to_end(&mut received).unwrap();
+        let head_end = received.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+        assert!(received.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+        let frames = &received[head_end..];
+        assert_eq!(frames[0], 0x81);
+        let message = String::from_utf8(frames[2..2 + frames[1] as usize].to_vec()).unwrap();
+        assert!(message.contains("\"path\":\"/missing.html\",\"code\":404"), "{}", message);
+        // the limit closes the WebSocket after the single message
+        assert_eq!(frames[2 + frames[1] as usize], 0x88);
+    }
+}
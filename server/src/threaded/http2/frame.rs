@@ -0,0 +1,295 @@
+//! Module reading and writing the frames of HTTP/2 (RFC 9113, section 4 and 6)
+
+/// The length of the header in front of every frame payload
+pub const HEADER_LENGTH: usize = 9;
+/// The largest payload both sides accept before the peer's settings say otherwise
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+/// The window of new streams and of the connection before the settings say otherwise
+pub const DEFAULT_WINDOW_SIZE: i64 = 65_535;
+/// The largest flow control window, exceeding it is an error
+pub const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+
+pub const FLAG_END_STREAM: u8 = 0x1;
+pub const FLAG_ACK: u8 = 0x1;
+pub const FLAG_END_HEADERS: u8 = 0x4;
+pub const FLAG_PADDED: u8 = 0x8;
+pub const FLAG_PRIORITY: u8 = 0x20;
+
+/// The frame types, unknown types are ignored
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FrameType {
+    Data,
+    Headers,
+    Priority,
+    RstStream,
+    Settings,
+    PushPromise,
+    Ping,
+    GoAway,
+    WindowUpdate,
+    Continuation,
+    Unknown(u8),
+}
+
+impl FrameType {
+    fn from_byte(byte: u8) -> Self {
+        match byte {
+            0x0 => FrameType::Data,
+            0x1 => FrameType::Headers,
+            0x2 => FrameType::Priority,
+            0x3 => FrameType::RstStream,
+            0x4 => FrameType::Settings,
+            0x5 => FrameType::PushPromise,
+            0x6 => FrameType::Ping,
+            0x7 => FrameType::GoAway,
+            0x8 => FrameType::WindowUpdate,
+            0x9 => FrameType::Continuation,
+            byte => FrameType::Unknown(byte),
+        }
+    }
+
+    fn as_byte(&self) -> u8 {
+        match self {
+            FrameType::Data => 0x0,
+            FrameType::Headers => 0x1,
+            FrameType::Priority => 0x2,
+            FrameType::RstStream => 0x3,
+            FrameType::Settings => 0x4,
+            FrameType::PushPromise => 0x5,
+            FrameType::Ping => 0x6,
+            FrameType::GoAway => 0x7,
+            FrameType::WindowUpdate => 0x8,
+            FrameType::Continuation => 0x9,
+            FrameType::Unknown(byte) => *byte,
+        }
+    }
+}
+
+/// The error codes of RST_STREAM and GOAWAY frames
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ErrorCode {
+    NoError = 0x0,
+    ProtocolError = 0x1,
+    InternalError = 0x2,
+    FlowControlError = 0x3,
+    StreamClosed = 0x5,
+    FrameSizeError = 0x6,
+    RefusedStream = 0x7,
+    CompressionError = 0x9,
+    EnhanceYourCalm = 0xb,
+}
+
+/// A frame received from the peer
+#[derive(Debug, Eq, PartialEq)]
+pub struct Frame {
+    pub frame_type: FrameType,
+    pub flags: u8,
+    pub stream_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Takes the next complete frame out of the buffer
+    ///
+    /// Returns None while parts of the frame are still missing,
+    /// and an error if the frame is larger than the largest size we accept.
+    pub fn take(buffer: &mut Vec<u8>, max_frame_size: usize) -> Result<Option<Frame>, ErrorCode> {
+        if buffer.len() < HEADER_LENGTH {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes([0, buffer[0], buffer[1], buffer[2]]) as usize;
+        if length > max_frame_size {
+            return Err(ErrorCode::FrameSizeError);
+        }
+        if buffer.len() < HEADER_LENGTH + length {
+            return Ok(None);
+        }
+        let frame = Frame {
+            frame_type: FrameType::from_byte(buffer[3]),
+            flags: buffer[4],
+            stream_id: read_u32(&buffer[5..9]) & 0x7fff_ffff,
+            payload: buffer[HEADER_LENGTH..HEADER_LENGTH + length].to_vec(),
+        };
+        buffer.drain(..HEADER_LENGTH + length);
+        Ok(Some(frame))
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// The payload without the padding, and without the priority fields of HEADERS frames
+    pub fn content(&self) -> Result<&[u8], ErrorCode> {
+        let mut content = &self.payload[..];
+        if self.has_flag(FLAG_PADDED) && matches!(self.frame_type, FrameType::Data | FrameType::Headers) {
+            let (&padding, rest) = content.split_first().ok_or(ErrorCode::FrameSizeError)?;
+            if padding as usize > rest.len() {
+                return Err(ErrorCode::ProtocolError);
+            }
+            content = &rest[..rest.len() - padding as usize];
+        }
+        if self.has_flag(FLAG_PRIORITY) && self.frame_type == FrameType::Headers {
+            content = content.get(5..).ok_or(ErrorCode::FrameSizeError)?;
+        }
+        Ok(content)
+    }
+}
+
+/// Appends a frame to the bytes to be sent
+pub fn write_frame(out: &mut Vec<u8>, frame_type: FrameType, flags: u8, stream_id: u32, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(frame_type.as_byte());
+    out.push(flags);
+    out.extend_from_slice(&stream_id.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// Appends a header block as HEADERS frame, followed by CONTINUATION frames if it is larger than a frame
+pub fn write_headers(out: &mut Vec<u8>, stream_id: u32, block: &[u8], end_stream: bool, max_frame_size: usize) {
+    let mut fragments = block.chunks(max_frame_size).peekable();
+    let mut frame_type = FrameType::Headers;
+    let mut flags = if end_stream { FLAG_END_STREAM } else { 0 };
+    loop {
+        let fragment = fragments.next().unwrap_or_default();
+        let is_last = fragments.peek().is_none();
+        if is_last {
+            flags |= FLAG_END_HEADERS;
+        }
+        write_frame(out, frame_type, flags, stream_id, fragment);
+        if is_last {
+            return;
+        }
+        frame_type = FrameType::Continuation;
+        flags = 0;
+    }
+}
+
+/// Appends a RST_STREAM frame closing the stream
+pub fn write_rst_stream(out: &mut Vec<u8>, stream_id: u32, error: ErrorCode) {
+    write_frame(out, FrameType::RstStream, 0, stream_id, &(error as u32).to_be_bytes());
+}
+
+/// Appends a GOAWAY frame, the streams up to the last one are still processed
+pub fn write_goaway(out: &mut Vec<u8>, last_stream_id: u32, error: ErrorCode) {
+    let mut payload = last_stream_id.to_be_bytes().to_vec();
+    payload.extend_from_slice(&(error as u32).to_be_bytes());
+    write_frame(out, FrameType::GoAway, 0, 0, &payload);
+}
+
+/// Appends a WINDOW_UPDATE frame granting the peer more bytes to send
+pub fn write_window_update(out: &mut Vec<u8>, stream_id: u32, increment: u32) {
+    write_frame(out, FrameType::WindowUpdate, 0, stream_id, &increment.to_be_bytes());
+}
+
+/// Reads a 32 bit integer in network byte order
+pub fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// The settings a peer announces, only the ones the server acts upon
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Settings {
+    pub initial_window_size: i64,
+    pub max_frame_size: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self { initial_window_size: DEFAULT_WINDOW_SIZE, max_frame_size: DEFAULT_MAX_FRAME_SIZE }
+    }
+}
+
+pub const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+pub const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+impl Settings {
+    /// Applies the parameters of a SETTINGS payload, unknown parameters are ignored
+    pub fn apply(&mut self, payload: &[u8]) -> Result<(), ErrorCode> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(ErrorCode::FrameSizeError);
+        }
+        for parameter in payload.chunks(6) {
+            let value = read_u32(&parameter[2..]);
+            match u16::from_be_bytes([parameter[0], parameter[1]]) {
+                SETTINGS_ENABLE_PUSH if value > 1 => return Err(ErrorCode::ProtocolError),
+                SETTINGS_INITIAL_WINDOW_SIZE if value as i64 > MAX_WINDOW_SIZE => return Err(ErrorCode::FlowControlError),
+                SETTINGS_INITIAL_WINDOW_SIZE => self.initial_window_size = value as i64,
+                SETTINGS_MAX_FRAME_SIZE if !(DEFAULT_MAX_FRAME_SIZE..1 << 24).contains(&(value as usize)) => {
+                    return Err(ErrorCode::ProtocolError);
+                }
+                SETTINGS_MAX_FRAME_SIZE => self.max_frame_size = value as usize,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Appends a SETTINGS frame with the parameters
+pub fn write_settings(out: &mut Vec<u8>, parameters: &[(u16, u32)]) {
+    let mut payload = Vec::with_capacity(parameters.len() * 6);
+    for (identifier, value) in parameters {
+        payload.extend_from_slice(&identifier.to_be_bytes());
+        payload.extend_from_slice(&value.to_be_bytes());
+    }
+    write_frame(out, FrameType::Settings, 0, 0, &payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_test() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, FrameType::Headers, FLAG_END_HEADERS, 3, b"block");
+        write_frame(&mut buffer, FrameType::Unknown(0xfa), 0, 0, b"");
+        buffer.extend_from_slice(&[0, 0]);
+
+        let frame = Frame::take(&mut buffer, DEFAULT_MAX_FRAME_SIZE).unwrap().unwrap();
+        assert_eq!(frame, Frame { frame_type: FrameType::Headers, flags: FLAG_END_HEADERS, stream_id: 3, payload: b"block".to_vec() });
+        assert_eq!(Frame::take(&mut buffer, DEFAULT_MAX_FRAME_SIZE).unwrap().unwrap().frame_type, FrameType::Unknown(0xfa));
+        assert_eq!(Frame::take(&mut buffer, DEFAULT_MAX_FRAME_SIZE), Ok(None));
+        assert_eq!(buffer, [0, 0]);
+
+        let mut too_large = Vec::new();
+        write_frame(&mut too_large, FrameType::Data, 0, 1, &[0; 20]);
+        assert_eq!(Frame::take(&mut too_large, 16), Err(ErrorCode::FrameSizeError));
+    }
+
+    #[test]
+    fn content_test() {
+        let frame = Frame { frame_type: FrameType::Headers, flags: FLAG_PADDED | FLAG_PRIORITY, stream_id: 1, payload: b"\x02\0\0\0\x03\x10block\0\0".to_vec() };
+        assert_eq!(frame.content(), Ok(&b"block"[..]));
+        let frame = Frame { frame_type: FrameType::Data, flags: FLAG_PADDED, stream_id: 1, payload: b"\x05data".to_vec() };
+        assert_eq!(frame.content(), Err(ErrorCode::ProtocolError));
+    }
+
+    #[test]
+    fn write_headers_test() {
+        let mut out = Vec::new();
+        write_headers(&mut out, 1, b"0123456789", true, 4);
+        let frames: Vec<Frame> = std::iter::from_fn(|| Frame::take(&mut out, 4).unwrap()).collect();
+        assert_eq!(frames.iter().map(|frame| frame.frame_type).collect::<Vec<_>>(),
+                   vec![FrameType::Headers, FrameType::Continuation, FrameType::Continuation]);
+        assert_eq!(frames.iter().map(|frame| frame.flags).collect::<Vec<_>>(), vec![FLAG_END_STREAM, 0, FLAG_END_HEADERS]);
+        assert_eq!(frames.iter().flat_map(|frame| frame.payload.clone()).collect::<Vec<_>>(), b"0123456789");
+    }
+
+    #[test]
+    fn settings_test() {
+        let mut out = Vec::new();
+        write_settings(&mut out, &[(SETTINGS_INITIAL_WINDOW_SIZE, 1024), (SETTINGS_MAX_FRAME_SIZE, 32_768), (0xff, 7)]);
+        let frame = Frame::take(&mut out, DEFAULT_MAX_FRAME_SIZE).unwrap().unwrap();
+        let mut settings = Settings::default();
+        settings.apply(&frame.payload).unwrap();
+        assert_eq!(settings, Settings { initial_window_size: 1024, max_frame_size: 32_768 });
+
+        assert_eq!(settings.apply(&[0, 5, 0, 0, 0, 1]), Err(ErrorCode::ProtocolError));
+        assert_eq!(settings.apply(&[0, 4, 0x80, 0, 0, 0]), Err(ErrorCode::FlowControlError));
+        assert_eq!(settings.apply(&[0, 4, 0]), Err(ErrorCode::FrameSizeError));
+    }
+}
@@ -0,0 +1,285 @@
+//! Module compressing the header fields of HTTP/2 with HPACK (RFC 7541)
+
+use std::collections::VecDeque;
+use crate::threaded::http2::huffman;
+
+/// A header field as name and value, the names are lowercase
+pub type HeaderField = (String, String);
+
+/// The table every connection starts with, its entries are indexed from 1
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""), (":method", "GET"), (":method", "POST"), (":path", "/"),
+    (":path", "/index.html"), (":scheme", "http"), (":scheme", "https"), (":status", "200"),
+    (":status", "204"), (":status", "206"), (":status", "304"), (":status", "400"),
+    (":status", "404"), (":status", "500"), ("accept-charset", ""), ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""), ("accept-ranges", ""), ("accept", ""), ("access-control-allow-origin", ""),
+    ("age", ""), ("allow", ""), ("authorization", ""), ("cache-control", ""),
+    ("content-disposition", ""), ("content-encoding", ""), ("content-language", ""), ("content-length", ""),
+    ("content-location", ""), ("content-range", ""), ("content-type", ""), ("cookie", ""),
+    ("date", ""), ("etag", ""), ("expect", ""), ("expires", ""),
+    ("from", ""), ("host", ""), ("if-match", ""), ("if-modified-since", ""),
+    ("if-none-match", ""), ("if-range", ""), ("if-unmodified-since", ""), ("last-modified", ""),
+    ("link", ""), ("location", ""), ("max-forwards", ""), ("proxy-authenticate", ""),
+    ("proxy-authorization", ""), ("range", ""), ("referer", ""), ("refresh", ""),
+    ("retry-after", ""), ("server", ""), ("set-cookie", ""), ("strict-transport-security", ""),
+    ("transfer-encoding", ""), ("user-agent", ""), ("vary", ""), ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// The size an entry takes in the dynamic table, including the overhead every entry is charged with
+fn entry_size(name: &str, value: &str) -> usize {
+    name.len() + value.len() + 32
+}
+
+/// Decodes the header blocks of one connection, keeping the dynamic table the peer builds up
+#[derive(Debug)]
+pub struct Decoder {
+    /// the newest entry first
+    dynamic_table: VecDeque<HeaderField>,
+    size: usize,
+    max_size: usize,
+    /// the largest table size the peer may choose, as announced in our settings
+    size_limit: usize,
+}
+
+impl Decoder {
+    /// Creates a decoder whose dynamic table may grow up to the size limit
+    pub fn new(size_limit: usize) -> Self {
+        Self { dynamic_table: VecDeque::new(), size: 0, max_size: size_limit, size_limit }
+    }
+
+    /// Decodes the complete header block into its fields, in the order they were sent
+    pub fn decode(&mut self, mut block: &[u8]) -> Result<Vec<HeaderField>, String> {
+        let mut fields = Vec::new();
+        let mut field_seen = false;
+        while let Some(&first) = block.first() {
+            if first & 0x80 != 0 {
+                let index = decode_integer(&mut block, 7)?;
+                fields.push(self.entry(index)?);
+            } else if first & 0x40 != 0 {
+                let field = self.decode_literal(&mut block, 6)?;
+                self.insert(field.clone());
+                fields.push(field);
+            } else if first & 0x20 != 0 {
+                if field_seen {
+                    return Err("Dynamic table size update after a header field".to_string());
+                }
+                let max_size = decode_integer(&mut block, 5)?;
+                if max_size > self.size_limit {
+                    return Err(format!("Dynamic table size {} exceeds the limit of {}", max_size, self.size_limit));
+                }
+                self.max_size = max_size;
+                self.evict(0);
+                continue;
+            } else {
+                // literals without indexing and never indexed ones only differ for intermediaries
+                fields.push(self.decode_literal(&mut block, 4)?);
+            }
+            field_seen = true;
+        }
+        Ok(fields)
+    }
+
+    /// The entry of the static or dynamic table
+    fn entry(&self, index: usize) -> Result<HeaderField, String> {
+        match index {
+            0 => Err("Header field index 0 is invalid".to_string()),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.to_string(), value.to_string()))
+            }
+            _ => self.dynamic_table.get(index - 62).cloned()
+                .ok_or_else(|| format!("Header field index {} is not in the table", index))
+        }
+    }
+
+    /// Decodes a literal field, whose name is either indexed or follows as string
+    fn decode_literal(&self, block: &mut &[u8], prefix: u8) -> Result<HeaderField, String> {
+        let name = match decode_integer(block, prefix)? {
+            0 => decode_string(block)?,
+            index => self.entry(index)?.0,
+        };
+        let value = decode_string(block)?;
+        Ok((name, value))
+    }
+
+    /// Adds the field as newest entry, evicting the oldest entries until it fits
+    fn insert(&mut self, field: HeaderField) {
+        let size = entry_size(&field.0, &field.1);
+        self.evict(size);
+        if size <= self.max_size {
+            self.size += size;
+            self.dynamic_table.push_front(field);
+        }
+    }
+
+    /// Evicts the oldest entries until the table has room for the size within its maximum
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            match self.dynamic_table.pop_back() {
+                Some((name, value)) => self.size -= entry_size(&name, &value),
+                None => break
+            }
+        }
+    }
+}
+
+/// Encodes header blocks, referring to the static table only
+///
+/// Responses are encoded without dynamic table, so the peer's table size never matters.
+/// Values are Huffman coded whenever this makes them shorter.
+pub fn encode(fields: &[HeaderField]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        if let Some(index) = STATIC_TABLE.iter().position(|entry| entry == &(&name[..], &value[..])) {
+            encode_integer(&mut block, 0x80, 7, index + 1);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|(entry_name, _)| entry_name == name) {
+            Some(index) => encode_integer(&mut block, 0x00, 4, index + 1),
+            None => {
+                block.push(0x00);
+                encode_string(&mut block, name.as_bytes());
+            }
+        }
+        encode_string(&mut block, value.as_bytes());
+    }
+    block
+}
+
+/// Decodes an integer whose first byte shares its high bits with flags
+fn decode_integer(block: &mut &[u8], prefix: u8) -> Result<usize, String> {
+    let max_prefix = (1usize << prefix) - 1;
+    let (&first, rest) = block.split_first().ok_or("Header block ends within an integer")?;
+    *block = rest;
+    let mut value = first as usize & max_prefix;
+    if value < max_prefix {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = block.split_first().ok_or("Header block ends within an integer")?;
+        *block = rest;
+        if shift > 28 {
+            return Err("Integer in header block is too large".to_string());
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+/// Encodes an integer into the prefix bits of the first byte, which carries the flags
+fn encode_integer(block: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let max_prefix = (1usize << prefix) - 1;
+    if value < max_prefix {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max_prefix as u8);
+    value -= max_prefix;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+/// Decodes a string literal, which is either Huffman coded or raw
+fn decode_string(block: &mut &[u8]) -> Result<String, String> {
+    let huffman_coded = block.first().is_some_and(|first| first & 0x80 != 0);
+    let length = decode_integer(block, 7)?;
+    if length > block.len() {
+        return Err("Header block ends within a string".to_string());
+    }
+    let (raw, rest) = block.split_at(length);
+    *block = rest;
+    let bytes = if huffman_coded { huffman::decode(raw)? } else { raw.to_vec() };
+    String::from_utf8(bytes).map_err(|_| "Header field is not valid utf-8".to_string())
+}
+
+/// Encodes a string literal, Huffman coded if this is shorter
+fn encode_string(block: &mut Vec<u8>, string: &[u8]) {
+    if huffman::encoded_len(string) < string.len() {
+        let encoded = huffman::encode(string);
+        encode_integer(block, 0x80, 7, encoded.len());
+        block.extend_from_slice(&encoded);
+    } else {
+        encode_integer(block, 0x00, 7, string.len());
+        block.extend_from_slice(string);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(fields: &[(&str, &str)]) -> Vec<HeaderField> {
+        fields.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn integer_test() {
+        // RFC 7541, C.1
+        let mut block = Vec::new();
+        encode_integer(&mut block, 0x00, 5, 10);
+        encode_integer(&mut block, 0x00, 5, 1337);
+        encode_integer(&mut block, 0x00, 8, 42);
+        assert_eq!(block, [0x0a, 0x1f, 0x9a, 0x0a, 0x2a]);
+
+        let mut rest = &block[..];
+        assert_eq!(decode_integer(&mut rest, 5), Ok(10));
+        assert_eq!(decode_integer(&mut rest, 5), Ok(1337));
+        assert_eq!(decode_integer(&mut rest, 8), Ok(42));
+        assert!(rest.is_empty());
+        assert!(decode_integer(&mut &[0x1f, 0x9a][..], 5).is_err());
+        assert!(decode_integer(&mut &[0x1f, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01][..], 5).is_err());
+    }
+
+    #[test]
+    fn decode_requests_test() {
+        // RFC 7541, C.4, the same requests with Huffman coding sharing one dynamic table
+        let mut decoder = Decoder::new(4096);
+        let first = [0x82, 0x86, 0x84, 0x41, 0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff];
+        assert_eq!(decoder.decode(&first).unwrap(),
+                   fields(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")]));
+        assert_eq!(decoder.size, 57);
+
+        let second = [0x82, 0x86, 0x84, 0xbe, 0x58, 0x86, 0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf];
+        assert_eq!(decoder.decode(&second).unwrap(),
+                   fields(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com"), ("cache-control", "no-cache")]));
+
+        let third = [0x82, 0x87, 0x85, 0xbf, 0x40, 0x88, 0x25, 0xa8, 0x49, 0xe9, 0x5b, 0xa9, 0x7d, 0x7f, 0x89, 0x25, 0xa8, 0x49, 0xe9, 0x5b, 0xb8, 0xe8, 0xb4, 0xbf];
+        assert_eq!(decoder.decode(&third).unwrap(),
+                   fields(&[(":method", "GET"), (":scheme", "https"), (":path", "/index.html"), (":authority", "www.example.com"), ("custom-key", "custom-value")]));
+        assert_eq!(decoder.dynamic_table.len(), 3);
+        assert_eq!(decoder.size, 164);
+    }
+
+    #[test]
+    fn decode_eviction_test() {
+        let mut decoder = Decoder::new(4096);
+        // table size update to 64 bytes, then two literals with incremental indexing of 42 bytes each
+        let block = [0x3f, 0x21, 0x40, 0x01, b'a', 0x09, b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9',
+            0x40, 0x01, b'b', 0x01, b'2'];
+        assert_eq!(decoder.decode(&block).unwrap(), fields(&[("a", "123456789"), ("b", "2")]));
+        assert_eq!(decoder.dynamic_table.iter().cloned().collect::<Vec<_>>(), fields(&[("b", "2")]));
+        assert_eq!(decoder.decode(&[0xbe]).unwrap(), fields(&[("b", "2")]));
+        assert!(decoder.decode(&[0xbf]).is_err());
+        assert!(decoder.decode(&[0x80]).is_err());
+        // larger than the size we announced
+        assert!(decoder.decode(&[0x3f, 0xe2, 0x1f]).is_err());
+        // size update after a field
+        assert!(decoder.decode(&[0x82, 0x20]).is_err());
+    }
+
+    #[test]
+    fn encode_test() {
+        let response = fields(&[(":status", "200"), (":status", "203"), ("content-length", "5"), ("x-custom", "value")]);
+        let block = encode(&response);
+        assert_eq!(&block[..2], &[0x88, 0x08]);
+        assert_eq!(Decoder::new(4096).decode(&block).unwrap(), response);
+    }
+}
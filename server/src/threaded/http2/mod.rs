@@ -0,0 +1,723 @@
+//! Module serving HTTP/2 connections of the threaded server (RFC 9113)
+//!
+//! A connection switches to HTTP/2 when the client starts with the connection preface,
+//! either with prior knowledge, after ALPN chose h2 during the TLS handshake,
+//! or after upgrading an HTTP/1.1 request with `Upgrade: h2c`.
+//! All streams of a connection are answered by its worker thread: the requests are handled
+//! as soon as they arrived completely, the bodies of the responses are interleaved frame by frame
+//! within the flow control windows of the client.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{ErrorKind, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use crate::body::Body;
+use crate::connection::{Connection, TlsConfig};
+use crate::file::ServerFiles;
+use crate::request::{InvalidRequest, KeepAlive, Request, RequestLimits};
+use crate::response::Response;
+use crate::shutdown;
+use crate::threaded::controller::error_controller::error_response_invalid_request;
+use crate::threaded::request_handler::handle_request;
+use crate::threaded::server::{RequestResult, ServerStats};
+use self::frame::*;
+use self::hpack::{Decoder, HeaderField};
+
+mod frame;
+mod hpack;
+mod huffman;
+
+/// The first bytes a client sends on an HTTP/2 connection
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// The part of the preface which looks like the head of an HTTP/1 request
+pub const PREFACE_HEAD: &[u8] = b"PRI * HTTP/2.0\r\n\r\n";
+
+/// The most streams a client may have open at the same time
+const MAX_CONCURRENT_STREAMS: usize = 100;
+/// The size of the dynamic table the client may use for its header blocks, the default of HPACK
+const HEADER_TABLE_SIZE: usize = 4096;
+/// The most DATA frames written before looking for new frames of the client
+const DATA_FRAMES_PER_ROUND: usize = 16;
+/// The amount of bytes read from the connection at once
+const READ_CHUNK_SIZE: usize = 16_384;
+
+/// Offers h2 during the TLS handshake, preferred over HTTP/1.1
+pub fn with_alpn(tls: TlsConfig) -> TlsConfig {
+    let mut config = (*tls).clone();
+    config.alpn_protocols.insert(0, b"h2".to_vec());
+    Arc::new(config)
+}
+
+/// The settings of the client, if the request asks to upgrade the connection to h2c
+///
+/// Only requests without body are upgraded, so the request can be answered on the first stream.
+pub fn upgrade_settings(request: &Request) -> Option<Vec<u8>> {
+    let has_token = |header: &str, token: &str| request.get_header(header)
+        .is_some_and(|value| value.split(',').any(|option| option.trim().eq_ignore_ascii_case(token)));
+    if request.request_identifiers.version != "HTTP/1.1" || !request.body.is_empty()
+        || !has_token("upgrade", "h2c") || !has_token("connection", "http2-settings") {
+        return None;
+    }
+    decode_base64url(request.get_header("http2-settings")?)
+}
+
+/// Decodes the URL safe base64 without padding of the HTTP2-Settings header
+fn decode_base64url(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+    for byte in encoded.trim_end_matches('=').bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None
+        };
+        bits = (bits << 6) | value as u32;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+/// Serves the connection with HTTP/2 until the client or the timeouts close it
+///
+/// received are the bytes read before HTTP/2 was detected, starting with the preface.
+/// An upgraded request is answered as first stream after switching the protocols.
+pub fn serve(stream: &mut Connection, received: Vec<u8>, upgrade: Option<(Request, Vec<u8>)>, server_files: ServerFiles, stats: Arc<ServerStats>, limits: RequestLimits, keep_alive: KeepAlive) {
+    let mut connection = Http2Connection {
+        stream,
+        server_files,
+        stats,
+        limits,
+        keep_alive,
+        received,
+        out: Vec::new(),
+        decoder: Decoder::new(HEADER_TABLE_SIZE),
+        peer: Settings::default(),
+        send_window: DEFAULT_WINDOW_SIZE,
+        streams: BTreeMap::new(),
+        last_stream_id: 0,
+        streams_opened: 0,
+        continuation: None,
+        going_away: false,
+    };
+    let error = match connection.run(upgrade) {
+        Ok(()) => ErrorCode::NoError,
+        Err(ConnectionError::Closed) => return,
+        Err(ConnectionError::Protocol(error)) => error,
+    };
+    write_goaway(&mut connection.out, connection.last_stream_id, error);
+    let _ = connection.flush();
+}
+
+/// The reason the connection ends early
+#[derive(Debug)]
+enum ConnectionError {
+    /// the connection broke or timed out, nothing more can be sent
+    Closed,
+    /// the client violated the protocol, which is reported in a GOAWAY frame
+    Protocol(ErrorCode),
+}
+
+impl From<ErrorCode> for ConnectionError {
+    fn from(error: ErrorCode) -> Self {
+        ConnectionError::Protocol(error)
+    }
+}
+
+impl From<std::io::Error> for ConnectionError {
+    fn from(_: std::io::Error) -> Self {
+        ConnectionError::Closed
+    }
+}
+
+/// A stream opened by a request of the client
+#[derive(Debug)]
+enum StreamState {
+    /// the request is still arriving, the body is only kept within the size limit
+    Receiving { fields: Vec<HeaderField>, body: Vec<u8>, body_length: usize },
+    /// the request was answered, the body of the response is still being sent
+    Sending(OutgoingBody),
+}
+
+#[derive(Debug)]
+struct Stream {
+    state: StreamState,
+    /// how many bytes the client still accepts on this stream
+    send_window: i64,
+    started: Instant,
+}
+
+/// The body of a response, sent in DATA frames
+#[derive(Debug)]
+struct OutgoingBody {
+    body: Body,
+    /// the bytes of the body sent so far
+    offset: usize,
+    /// the rest of the last chunk of a streamed body
+    pending: Vec<u8>,
+    result: RequestResult,
+}
+
+impl OutgoingBody {
+    /// The next bytes of the body up to the length, and whether they are the last ones
+    fn next(&mut self, max_length: usize) -> std::io::Result<(Vec<u8>, bool)> {
+        if self.body.is_stream() {
+            while self.pending.is_empty() {
+                match self.body.next_chunk() {
+                    Some(chunk) => self.pending = chunk,
+                    None => return Ok((Vec::new(), true))
+                }
+            }
+            let length = max_length.min(self.pending.len());
+            return Ok((self.pending.drain(..length).collect(), false));
+        }
+        let end = (self.offset + max_length).min(self.body.len());
+        let data = self.body.slice(self.offset..end).to_vec()?;
+        self.offset = end;
+        Ok((data, end == self.body.len()))
+    }
+}
+
+/// The state of one HTTP/2 connection
+struct Http2Connection<'a> {
+    stream: &'a mut Connection,
+    server_files: ServerFiles,
+    stats: Arc<ServerStats>,
+    limits: RequestLimits,
+    keep_alive: KeepAlive,
+    /// the bytes received but not yet taken as frames
+    received: Vec<u8>,
+    /// the frames to be written with the next flush
+    out: Vec<u8>,
+    decoder: Decoder,
+    peer: Settings,
+    /// how many bytes the client still accepts on the connection
+    send_window: i64,
+    streams: BTreeMap<u32, Stream>,
+    last_stream_id: u32,
+    streams_opened: usize,
+    /// the stream, end stream flag and fragments of a header block continued in CONTINUATION frames
+    continuation: Option<(u32, bool, Vec<u8>)>,
+    /// whether no new streams are accepted, the open ones are still answered
+    going_away: bool,
+}
+
+impl Http2Connection<'_> {
+    /// Exchanges the prefaces and serves the streams, until the connection is idle for too long
+    fn run(&mut self, upgrade: Option<(Request, Vec<u8>)>) -> Result<(), ConnectionError> {
+        if upgrade.is_some() {
+            self.out.extend(Response::default_switching_protocols("h2c").make_head_sendable());
+        }
+        write_settings(&mut self.out, &[
+            (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_CONCURRENT_STREAMS as u32),
+            (SETTINGS_MAX_HEADER_LIST_SIZE, self.limits.max_header_size as u32),
+        ]);
+        if let Some((mut request, settings)) = upgrade {
+            self.peer.apply(&settings)?;
+            request.request_identifiers.version = "HTTP/2.0".to_string();
+            self.open_stream(1, Vec::new());
+            self.respond(1, Ok(request));
+        }
+        self.flush()?;
+
+        while self.received.len() < PREFACE.len() {
+            self.read(Some(self.limits.read_timeout))?;
+        }
+        if !self.received.starts_with(PREFACE) {
+            return Err(ErrorCode::ProtocolError.into());
+        }
+        self.received.drain(..PREFACE.len());
+
+        loop {
+            while let Some(frame) = Frame::take(&mut self.received, DEFAULT_MAX_FRAME_SIZE)? {
+                self.handle_frame(frame)?;
+            }
+            if shutdown::is_requested() && !self.going_away {
+                self.going_away = true;
+                write_goaway(&mut self.out, self.last_stream_id, ErrorCode::NoError);
+            }
+            let more_to_send = self.send_data()?;
+            self.flush()?;
+
+            let idle = self.streams.is_empty() && self.continuation.is_none();
+            if idle && self.going_away {
+                return Ok(());
+            }
+            let wait = match (more_to_send, idle) {
+                (true, _) => None,
+                (false, true) if self.keep_alive.timeout.is_zero() => return Ok(()),
+                (false, true) => Some(self.keep_alive.timeout),
+                (false, false) => Some(self.limits.read_timeout),
+            };
+            match self.read(wait) {
+                Err(ConnectionError::Closed) if idle && wait.is_some() => return Ok(()),
+                result => result?,
+            }
+        }
+    }
+
+    /// Reads the available bytes, waiting up to the timeout for them or only taking what already arrived
+    fn read(&mut self, wait: Option<Duration>) -> Result<(), ConnectionError> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let read = match wait {
+            Some(timeout) => {
+                self.stream.set_read_timeout(Some(timeout))?;
+                self.stream.read(&mut chunk)
+            }
+            None => {
+                self.stream.socket().set_nonblocking(true)?;
+                let read = self.stream.read(&mut chunk);
+                self.stream.socket().set_nonblocking(false)?;
+                match read {
+                    Err(error) if error.kind() == ErrorKind::WouldBlock => return Ok(()),
+                    read => read
+                }
+            }
+        };
+        match read {
+            Ok(0) => Err(ConnectionError::Closed),
+            Ok(length) => {
+                self.received.extend_from_slice(&chunk[..length]);
+                Ok(())
+            }
+            Err(error) if error.kind() == ErrorKind::Interrupted => Ok(()),
+            Err(error) => Err(error.into())
+        }
+    }
+
+    /// Writes the collected frames into the connection
+    fn flush(&mut self) -> Result<(), ConnectionError> {
+        if !self.out.is_empty() {
+            self.stream.write_all(&self.out)?;
+            self.stream.flush()?;
+            self.out.clear();
+        }
+        Ok(())
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        if let Some((stream_id, ..)) = &self.continuation {
+            if frame.frame_type != FrameType::Continuation || frame.stream_id != *stream_id {
+                return Err(ErrorCode::ProtocolError.into());
+            }
+        }
+        let is_connection_frame = matches!(frame.frame_type, FrameType::Settings | FrameType::Ping | FrameType::GoAway);
+        if is_connection_frame != (frame.stream_id == 0) && frame.frame_type != FrameType::WindowUpdate {
+            return Err(ErrorCode::ProtocolError.into());
+        }
+        match frame.frame_type {
+            FrameType::Data => self.on_data(frame),
+            FrameType::Headers => self.on_headers(frame),
+            FrameType::Continuation => self.on_continuation(frame),
+            FrameType::Settings => self.on_settings(frame),
+            FrameType::WindowUpdate => self.on_window_update(frame),
+            FrameType::RstStream => {
+                if frame.payload.len() != 4 {
+                    return Err(ErrorCode::FrameSizeError.into());
+                }
+                self.check_not_idle(frame.stream_id)?;
+                self.streams.remove(&frame.stream_id);
+                Ok(())
+            }
+            FrameType::Ping => {
+                if frame.payload.len() != 8 {
+                    return Err(ErrorCode::FrameSizeError.into());
+                }
+                if !frame.has_flag(FLAG_ACK) {
+                    write_frame(&mut self.out, FrameType::Ping, FLAG_ACK, 0, &frame.payload);
+                }
+                Ok(())
+            }
+            FrameType::GoAway => {
+                self.going_away = true;
+                Ok(())
+            }
+            FrameType::PushPromise => Err(ErrorCode::ProtocolError.into()),
+            FrameType::Priority | FrameType::Unknown(_) => Ok(()),
+        }
+    }
+
+    /// Frames other than HEADERS must not refer to streams the client has not opened yet
+    fn check_not_idle(&self, stream_id: u32) -> Result<(), ErrorCode> {
+        if stream_id > self.last_stream_id {
+            return Err(ErrorCode::ProtocolError);
+        }
+        Ok(())
+    }
+
+    fn on_data(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        self.check_not_idle(frame.stream_id)?;
+        let content = frame.content()?;
+        let end_stream = frame.has_flag(FLAG_END_STREAM);
+        // the whole payload counts against the window, which is granted again right away
+        if !frame.payload.is_empty() {
+            write_window_update(&mut self.out, 0, frame.payload.len() as u32);
+        }
+        match self.streams.get_mut(&frame.stream_id).map(|stream| &mut stream.state) {
+            Some(StreamState::Receiving { body, body_length, .. }) => {
+                *body_length += content.len();
+                if *body_length <= self.limits.max_body_size {
+                    body.extend_from_slice(content);
+                }
+                if !end_stream && !frame.payload.is_empty() {
+                    write_window_update(&mut self.out, frame.stream_id, frame.payload.len() as u32);
+                }
+            }
+            _ => {
+                self.reset(frame.stream_id, ErrorCode::StreamClosed);
+                return Ok(());
+            }
+        }
+        if end_stream {
+            self.complete_request(frame.stream_id);
+        }
+        Ok(())
+    }
+
+    fn on_headers(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        let block = frame.content()?.to_vec();
+        let end_stream = frame.has_flag(FLAG_END_STREAM);
+        if frame.has_flag(FLAG_END_HEADERS) {
+            return self.on_header_block(frame.stream_id, &block, end_stream);
+        }
+        self.check_header_block_size(block.len())?;
+        self.continuation = Some((frame.stream_id, end_stream, block));
+        Ok(())
+    }
+
+    fn on_continuation(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        let (stream_id, end_stream, mut block) = self.continuation.take().ok_or(ErrorCode::ProtocolError)?;
+        block.extend_from_slice(&frame.payload);
+        self.check_header_block_size(block.len())?;
+        if frame.has_flag(FLAG_END_HEADERS) {
+            return self.on_header_block(stream_id, &block, end_stream);
+        }
+        self.continuation = Some((stream_id, end_stream, block));
+        Ok(())
+    }
+
+    /// Header blocks larger than the limit are not even decoded, which ends the connection
+    fn check_header_block_size(&self, size: usize) -> Result<(), ErrorCode> {
+        if size > self.limits.max_header_size {
+            return Err(ErrorCode::EnhanceYourCalm);
+        }
+        Ok(())
+    }
+
+    /// Opens a new stream with the request head, or ends the request of an open stream with trailers
+    fn on_header_block(&mut self, stream_id: u32, block: &[u8], end_stream: bool) -> Result<(), ConnectionError> {
+        self.check_header_block_size(block.len())?;
+        let fields = self.decoder.decode(block).map_err(|_| ErrorCode::CompressionError)?;
+
+        if let Some(stream) = self.streams.get(&stream_id) {
+            match stream.state {
+                StreamState::Receiving { .. } if end_stream => self.complete_request(stream_id),
+                StreamState::Receiving { .. } => self.reset(stream_id, ErrorCode::ProtocolError),
+                StreamState::Sending(_) => self.reset(stream_id, ErrorCode::StreamClosed),
+            }
+            return Ok(());
+        }
+        if stream_id.is_multiple_of(2) || stream_id <= self.last_stream_id {
+            return Err(ErrorCode::ProtocolError.into());
+        }
+        self.last_stream_id = stream_id;
+        if self.going_away {
+            return Ok(());
+        }
+        if self.streams.len() >= MAX_CONCURRENT_STREAMS {
+            write_rst_stream(&mut self.out, stream_id, ErrorCode::RefusedStream);
+            return Ok(());
+        }
+        self.open_stream(stream_id, fields);
+        if self.streams_opened >= self.keep_alive.max_requests {
+            self.going_away = true;
+            write_goaway(&mut self.out, stream_id, ErrorCode::NoError);
+        }
+        if end_stream {
+            self.complete_request(stream_id);
+        }
+        Ok(())
+    }
+
+    fn open_stream(&mut self, stream_id: u32, fields: Vec<HeaderField>) {
+        self.last_stream_id = self.last_stream_id.max(stream_id);
+        self.streams_opened += 1;
+        let state = StreamState::Receiving { fields, body: Vec::new(), body_length: 0 };
+        self.streams.insert(stream_id, Stream { state, send_window: self.peer.initial_window_size, started: Instant::now() });
+    }
+
+    /// Answers the request of the stream, once it arrived completely
+    fn complete_request(&mut self, stream_id: u32) {
+        let (fields, body, body_length) = match self.streams.get_mut(&stream_id).map(|stream| &mut stream.state) {
+            Some(StreamState::Receiving { fields, body, body_length }) => (std::mem::take(fields), std::mem::take(body), *body_length),
+            _ => return
+        };
+        let request = match request_from_fields(fields, body) {
+            Ok(_) if body_length > self.limits.max_body_size => Err(InvalidRequest::payload_too_large(self.limits.max_body_size)),
+            Ok(request) => request,
+            Err(error) => {
+                self.reset(stream_id, error);
+                return;
+            }
+        };
+        self.respond(stream_id, request);
+    }
+
+    /// Sends the head of the response, its body follows in DATA frames
+    fn respond(&mut self, stream_id: u32, request: Result<Request, InvalidRequest>) {
+        let time = Utc::now().naive_local();
+        let (requested_resource, mut response) = match request {
+            Ok(request) => {
+                let response = handle_request(&request, self.server_files.clone(), self.stats.clone());
+                (request.request_identifiers.path, response)
+            }
+            Err(error) => (String::new(), error_response_invalid_request(&error, self.server_files.dynamic_files.clone()))
+        };
+        let block = hpack::encode(&response.make_header_fields());
+        let end_stream = !response.body.is_stream() && response.body.len() == 0;
+        write_headers(&mut self.out, stream_id, &block, end_stream, self.peer.max_frame_size);
+
+        let result = RequestResult { response_code: response.response_identifiers.method.id, requested_resource, time, duration: 0 };
+        let outgoing = OutgoingBody { body: response.body, offset: 0, pending: Vec::new(), result };
+        if !end_stream {
+            if let Some(stream) = self.streams.get_mut(&stream_id) {
+                stream.state = StreamState::Sending(outgoing);
+                return;
+            }
+        }
+        let started = self.streams.remove(&stream_id).map_or_else(Instant::now, |stream| stream.started);
+        self.record(outgoing.result, started);
+    }
+
+    /// Records the response of a stream in the stats, once it was sent completely
+    fn record(&self, mut result: RequestResult, started: Instant) {
+        result.duration = started.elapsed().as_millis();
+        self.stats.record(result);
+    }
+
+    /// Closes the stream with the error
+    fn reset(&mut self, stream_id: u32, error: ErrorCode) {
+        self.streams.remove(&stream_id);
+        write_rst_stream(&mut self.out, stream_id, error);
+    }
+
+    /// Writes DATA frames of all responses in turn, as far as the windows of the client allow
+    ///
+    /// Returns whether more frames could be sent right away, after a look for new frames of the client.
+    fn send_data(&mut self) -> Result<bool, ConnectionError> {
+        let mut frames = 0;
+        loop {
+            let sending: Vec<u32> = self.streams.iter()
+                .filter(|(_, stream)| matches!(stream.state, StreamState::Sending(_)))
+                .map(|(stream_id, _)| *stream_id)
+                .collect();
+            let mut progressed = false;
+            for stream_id in sending {
+                if frames == DATA_FRAMES_PER_ROUND {
+                    return Ok(true);
+                }
+                if self.send_data_frame(stream_id) {
+                    frames += 1;
+                    progressed = true;
+                }
+            }
+            if !progressed {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Writes the next DATA frame of the stream, returns whether there was anything to send
+    fn send_data_frame(&mut self, stream_id: u32) -> bool {
+        let max_frame_size = self.peer.max_frame_size as i64;
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => return false
+        };
+        let outgoing = match &mut stream.state {
+            StreamState::Sending(outgoing) => outgoing,
+            StreamState::Receiving { .. } => return false
+        };
+        let max_length = self.send_window.min(stream.send_window).min(max_frame_size).max(0) as usize;
+        let (data, end_stream) = match outgoing.next(max_length) {
+            Ok(next) => next,
+            Err(error) => {
+                println!("Error while sending response: {}", error);
+                self.reset(stream_id, ErrorCode::InternalError);
+                return true;
+            }
+        };
+        if data.is_empty() && !end_stream {
+            return false;
+        }
+        stream.send_window -= data.len() as i64;
+        self.send_window -= data.len() as i64;
+        write_frame(&mut self.out, FrameType::Data, if end_stream { FLAG_END_STREAM } else { 0 }, stream_id, &data);
+        if end_stream {
+            if let Some(Stream { state: StreamState::Sending(outgoing), started, .. }) = self.streams.remove(&stream_id) {
+                self.record(outgoing.result, started);
+            }
+        }
+        true
+    }
+
+    fn on_settings(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        if frame.has_flag(FLAG_ACK) {
+            if !frame.payload.is_empty() {
+                return Err(ErrorCode::FrameSizeError.into());
+            }
+            return Ok(());
+        }
+        let initial_window_size = self.peer.initial_window_size;
+        self.peer.apply(&frame.payload)?;
+        let change = self.peer.initial_window_size - initial_window_size;
+        for stream in self.streams.values_mut() {
+            stream.send_window += change;
+            if stream.send_window > MAX_WINDOW_SIZE {
+                return Err(ErrorCode::FlowControlError.into());
+            }
+        }
+        write_frame(&mut self.out, FrameType::Settings, FLAG_ACK, 0, &[]);
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        if frame.payload.len() != 4 {
+            return Err(ErrorCode::FrameSizeError.into());
+        }
+        let increment = (read_u32(&frame.payload) & 0x7fff_ffff) as i64;
+        if frame.stream_id == 0 {
+            if increment == 0 {
+                return Err(ErrorCode::ProtocolError.into());
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW_SIZE {
+                return Err(ErrorCode::FlowControlError.into());
+            }
+            return Ok(());
+        }
+        self.check_not_idle(frame.stream_id)?;
+        let send_window = match self.streams.get_mut(&frame.stream_id) {
+            Some(stream) => {
+                stream.send_window += increment;
+                stream.send_window
+            }
+            None => return Ok(())
+        };
+        if increment == 0 {
+            self.reset(frame.stream_id, ErrorCode::ProtocolError);
+        } else if send_window > MAX_WINDOW_SIZE {
+            self.reset(frame.stream_id, ErrorCode::FlowControlError);
+        }
+        Ok(())
+    }
+}
+
+/// Builds the request from the header fields of the stream
+///
+/// Malformed requests are a stream error, requests the servers can not answer get an error response.
+fn request_from_fields(fields: Vec<HeaderField>, body: Vec<u8>) -> Result<Result<Request, InvalidRequest>, ErrorCode> {
+    let mut pseudo_fields: HashMap<String, String> = HashMap::new();
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for (name, value) in fields {
+        if let Some(pseudo) = name.strip_prefix(':') {
+            let known = matches!(pseudo, "method" | "scheme" | "path" | "authority");
+            if !known || !headers.is_empty() || pseudo_fields.insert(pseudo.to_string(), value).is_some() {
+                return Err(ErrorCode::ProtocolError);
+            }
+            continue;
+        }
+        let connection_specific = matches!(&name[..], "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade");
+        if connection_specific || name.bytes().any(|byte| byte.is_ascii_uppercase()) || (name == "te" && value != "trailers") {
+            return Err(ErrorCode::ProtocolError);
+        }
+        let separator = if name == "cookie" { "; " } else { ", " };
+        match headers.get_mut(&name) {
+            Some(joined) => {
+                joined.push_str(separator);
+                joined.push_str(&value);
+            }
+            None => {
+                headers.insert(name, value);
+            }
+        }
+    }
+    let (method, path) = match (pseudo_fields.get("method"), pseudo_fields.get("path")) {
+        (Some(method), Some(path)) if !path.is_empty() => (method, path),
+        _ => return Err(ErrorCode::ProtocolError)
+    };
+    if let Some(authority) = pseudo_fields.get("authority") {
+        headers.entry("host".to_string()).or_insert_with(|| authority.clone());
+    }
+    Ok(Request::from_fields(method, path, headers, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(fields: &[(&str, &str)]) -> Vec<HeaderField> {
+        fields.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn decode_base64url_test() {
+        // SETTINGS_MAX_CONCURRENT_STREAMS = 100 and SETTINGS_INITIAL_WINDOW_SIZE = 65535
+        assert_eq!(decode_base64url("AAMAAABkAAQAAP__").unwrap(), [0, 3, 0, 0, 0, 100, 0, 4, 0, 0, 0xff, 0xff]);
+        assert_eq!(decode_base64url("aGk").unwrap(), b"hi");
+        assert_eq!(decode_base64url("aGk=").unwrap(), b"hi");
+        assert_eq!(decode_base64url(""), Some(Vec::new()));
+        assert_eq!(decode_base64url("AA+/"), None);
+    }
+
+    #[test]
+    fn request_from_fields_test() {
+        let request = request_from_fields(fields(&[
+            (":method", "GET"), (":scheme", "https"), (":path", "/stats?limit=5"), (":authority", "localhost"),
+            ("cookie", "a=1"), ("accept", "text/html"), ("cookie", "b=2"),
+        ]), Vec::new()).unwrap().unwrap();
+        assert_eq!(request.request_identifiers.path, "/stats");
+        assert_eq!(request.get_header("host"), Some("localhost"));
+        assert_eq!(request.get_header("cookie"), Some("a=1; b=2"));
+
+        let invalid = request_from_fields(fields(&[(":method", "BREW"), (":path", "/")]), Vec::new()).unwrap();
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn malformed_request_test() {
+        let malformed = [
+            fields(&[(":method", "GET")]),
+            fields(&[(":method", "GET"), (":path", "")]),
+            fields(&[(":method", "GET"), ("accept", "*/*"), (":path", "/")]),
+            fields(&[(":method", "GET"), (":method", "GET"), (":path", "/")]),
+            fields(&[(":method", "GET"), (":path", "/"), (":status", "200")]),
+            fields(&[(":method", "GET"), (":path", "/"), ("Accept", "*/*")]),
+            fields(&[(":method", "GET"), (":path", "/"), ("connection", "keep-alive")]),
+            fields(&[(":method", "GET"), (":path", "/"), ("te", "gzip")]),
+        ];
+        for fields in malformed {
+            assert_eq!(request_from_fields(fields, Vec::new()).err(), Some(ErrorCode::ProtocolError));
+        }
+    }
+
+    #[test]
+    fn upgrade_settings_test() {
+        let limits = RequestLimits { max_body_size: 16, max_header_size: 1024, read_timeout: Duration::from_secs(1) };
+        let parse = |head: &str| crate::request::parse_request(head.as_bytes(), limits).unwrap().unwrap().0;
+        let upgrade = parse("GET / HTTP/1.1\r\nHost: a\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: aGk\r\n\r\n");
+        assert_eq!(upgrade_settings(&upgrade), Some(b"hi".to_vec()));
+        let no_settings = parse("GET / HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n");
+        assert_eq!(upgrade_settings(&no_settings), None);
+        let websocket = parse("GET / HTTP/1.1\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: websocket\r\nHTTP2-Settings: aGk\r\n\r\n");
+        assert_eq!(upgrade_settings(&websocket), None);
+    }
+}
@@ -1,29 +1,49 @@
-use std::sync::{Arc, Mutex};
-use std::net::{TcpListener, TcpStream};
-use server::ThreadPool;
-use std::io::Read;
-use crate::response::send_response;
-use crate::{StaticFiles, DynamicFiles};
-use crate::threaded::server::{ServerStats, RequestResult, ServerFiles};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::io::Write;
+use std::net::{Shutdown, TcpListener};
+use std::thread;
+use server::{Admission, PoolConfig, ThreadPool};
+use crate::response::{send_response, Response};
+use crate::connection::{Connection, TlsConfig};
+use crate::file::ServerFiles;
+use crate::threaded::server::{ServerStats, RequestResult};
 use std::time::Instant;
 use chrono::Utc;
-use crate::request::parse_request;
+use crate::request::{KeepAlive, Request, RequestLimits};
+use crate::reader::{read_request, ReadError, RequestBuffer};
 use crate::threaded::request_handler::handle_request;
-use crate::threaded::controller::error_controller::error_response_400;
+use crate::threaded::controller::error_controller::error_response_invalid_request;
+use crate::threaded::controller::stats_controller::stats_live_response;
+use crate::shutdown;
 
 pub mod server;
 mod request_handler;
 mod controller;
+mod http2;
+mod websocket;
 
-/// Starts the threaded server listening on the address,
-/// with the amount of threads provided by thread_pool_size.
-pub fn start_server(ip: String, port: i32, thread_pool_size: i32, static_files: StaticFiles, dynamic_files: DynamicFiles) {
-    let pool = ThreadPool::new(thread_pool_size as usize);
+/// The most bytes read from a rejected connection, to take its request line into the stats
+const REJECTED_READ_LIMIT: usize = 16 * 1024;
+/// The seconds a rejected client is asked to wait before retrying
+const RETRY_AFTER: u64 = 1;
+/// How many clients may stream the live stats at the same time, each of them holds a thread
+const MAX_LIVE_STREAMS: usize = 16;
 
-    let address = format!("{}:{}", ip, port);
-    let stats = Arc::new(ServerStats { request_results: Mutex::new(vec![]) });
+/// The live stats streams currently open
+static LIVE_STREAMS: AtomicUsize = AtomicUsize::new(0);
 
-    let server_files = ServerFiles{static_files, dynamic_files};
+/// Starts the threaded server listening on the address,
+/// with the amount of threads and the bounds of their queue provided by the pool config.
+/// Connections rejected by a full queue are answered with 503 by the accepting thread.
+/// With a TLS config the connections are encrypted, the handshake is done by the worker threads.
+/// Besides HTTP/1 the connections may speak HTTP/2, which clients choose with ALPN,
+/// prior knowledge or an upgrade of their first request.
+pub fn start_server(ip: String, port: i32, pool: PoolConfig, server_files: ServerFiles, limits: RequestLimits, keep_alive: KeepAlive, tls: Option<TlsConfig>) {
+    let tls = tls.map(http2::with_alpn);
+    let address = format!("{}:{}", ip, port);
+    let stats = Arc::new(ServerStats::new(vec![]));
+    let pool = ThreadPool::new(pool, stats.pool.clone());
 
     let listener = match TcpListener::bind(address) {
         Ok(listener) => listener,
@@ -32,9 +52,15 @@ pub fn start_server(ip: String, port: i32, thread_pool_size: i32, static_files:
             return;
         }
     };
+    if shutdown::wake_on_shutdown(&listener) {
+        return;
+    }
     for stream in listener.incoming() {
-        let connection = match stream {
-            Ok(stream) => stream,
+        if shutdown::is_requested() {
+            break;
+        }
+        let connection = match stream.and_then(|stream| Connection::new(stream, tls.as_ref())) {
+            Ok(connection) => connection,
             Err(_) => {
                 println!("Threaded: Connection error. Ignoring request");
                 continue;
@@ -42,52 +68,285 @@ pub fn start_server(ip: String, port: i32, thread_pool_size: i32, static_files:
         };
 
         let server_files = server_files.clone();
-        let stats_change = stats.clone();
-        let stats_view = stats.clone();
-        pool.execute(move|| {
-            let result = stat_wrapper(handle_connection, connection, server_files, stats_view);
-            let mut results = stats_change.request_results.lock().unwrap();
-            match result {
-                Some(res) => results.push(res),
-                _ => ()
-            }
+        let stats = stats.clone();
+        pool.execute(move |admission| match admission {
+            Admission::Accepted => handle_connection(connection, server_files, stats, limits, keep_alive),
+            Admission::Rejected => reject_connection(connection, stats, limits),
         });
     }
+    // no more connections are accepted, the accepted ones are finished before the pool is dropped
+    drop(listener);
+    drop(pool);
 }
 
+/// The function handling a single request, returning the sent response
+type RequestHandler = fn(&mut Connection, &Request, ServerFiles, Arc<ServerStats>, bool) -> Option<Response>;
+
 /// Wraps the functionality to handle the request to retrieve its stats
-fn stat_wrapper(f: fn(TcpStream, ServerFiles, Arc<ServerStats>) -> Option<(u32, String)>, stream: TcpStream, server_files: ServerFiles, stats: Arc<ServerStats>) -> Option<RequestResult> {
+///
+/// Returns the stats and whether the connection stays open, if the response was sent.
+fn stat_wrapper(f: RequestHandler, stream: &mut Connection, request: &Request, server_files: ServerFiles, stats: Arc<ServerStats>, keep_alive: bool) -> Option<(RequestResult, bool)> {
     let date = Utc::now().naive_local();
     let start = Instant::now();
-    let connection_result = f(stream, server_files, stats);
+    let response = f(stream, request, server_files, stats, keep_alive)?;
     let duration = start.elapsed().as_millis();
 
-    match connection_result {
-        Some(res) => Some(RequestResult { response_code: res.0, duration: duration, time: date, requested_resource: res.1}),
-        None => None
-    }
+    let result = RequestResult { response_code: response.response_identifiers.method.id, duration, time: date, requested_resource: request.request_identifiers.path.to_string() };
+    Some((result, response.keep_alive))
 }
 
 /// Handles a single connection.
-/// Checking the request of correctness and returning the requested file
-fn handle_connection(mut stream: TcpStream, server_files: ServerFiles, stats: Arc<ServerStats>) -> Option<(u32, String)> {
-    let mut buffer = [0; 2048];
+/// Checking the requests of correctness and returning the requested files,
+/// as long as the connection is kept alive.
+fn handle_connection(mut stream: Connection, server_files: ServerFiles, stats: Arc<ServerStats>, limits: RequestLimits, keep_alive: KeepAlive) {
+    let mut buffer = RequestBuffer::new(limits);
+
+    loop {
+        let request = match read_request(&mut stream, &mut buffer) {
+            Ok(req) => req,
+            Err(ReadError::Invalid(_)) if buffer.requests_taken() == 0 && buffer.starts_with(http2::PREFACE_HEAD) => {
+                http2::serve(&mut stream, buffer.into_unparsed(), None, server_files, stats, limits, keep_alive);
+                return
+            }
+            Err(ReadError::Invalid(e)) => {
+                let _ = send_response(&mut stream, &mut error_response_invalid_request(&e, server_files.dynamic_files));
+                return
+            }
+            Err(ReadError::Closed) => return
+        };
+
+        if request.request_identifiers.path == "/stats/live" && websocket::upgrade_key(&request).is_some() {
+            serve_live_stats(stream, request, server_files, stats);
+            return
+        }
+        if let (Connection::Plain(_), Some(settings)) = (&stream, http2::upgrade_settings(&request)) {
+            http2::serve(&mut stream, buffer.into_unparsed(), Some((request, settings)), server_files, stats, limits, keep_alive);
+            return
+        }
+
+        let keep_open = keep_alive.allows(&request, buffer.requests_taken()) && !shutdown::is_requested();
+        let kept_open = match stat_wrapper(respond, &mut stream, &request, server_files.clone(), stats.clone(), keep_open) {
+            Some((result, kept_open)) => {
+                stats.record(result);
+                kept_open
+            }
+            None => false
+        };
 
-    if let Err(_) = stream.read(&mut buffer) {
-        println!("Threaded: Error while processing request. Ignoring request");
-        return None
+        if !kept_open {
+            return
+        }
+        buffer.wait_for_next(keep_alive.timeout);
     }
+}
 
-    let request = match parse_request(buffer.to_vec()) {
-        Ok(req) => req,
-        Err(e) => {
-            send_response(stream, &mut error_response_400(format!("{}", e), server_files.dynamic_files));
-            return None
+/// Streams the live stats on a thread of its own, so the open WebSocket does not hold a worker of the pool
+///
+/// Clients beyond the limit of open streams are answered with 503.
+fn serve_live_stats(mut stream: Connection, request: Request, server_files: ServerFiles, stats: Arc<ServerStats>) {
+    let slot = match LiveStreamSlot::take() {
+        Some(slot) => slot,
+        None => {
+            if let Some((result, _)) = stat_wrapper(respond_unavailable, &mut stream, &request, server_files, stats.clone(), false) {
+                stats.record(result);
+            }
+            return
         }
     };
+    let spawned = thread::Builder::new().name("live stats".to_string()).spawn(move || {
+        if let Some((result, _)) = stat_wrapper(respond_live_stats, &mut stream, &request, server_files, stats.clone(), false) {
+            stats.record(result);
+        }
+        drop(slot);
+    });
+    if spawned.is_err() {
+        println!("No more resources for creating thread");
+    }
+}
+
+/// One of the limited open live stats streams, given back when dropped
+struct LiveStreamSlot;
+
+impl LiveStreamSlot {
+    fn take() -> Option<Self> {
+        if LIVE_STREAMS.fetch_add(1, Ordering::SeqCst) >= MAX_LIVE_STREAMS {
+            LIVE_STREAMS.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(LiveStreamSlot)
+    }
+}
 
-    let mut response = handle_request(&request, server_files, stats);
+impl Drop for LiveStreamSlot {
+    fn drop(&mut self) {
+        LIVE_STREAMS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
-    send_response(stream, &mut response);
-    Some((response.response_identifiers.method.id, request.request_identifiers.path))
+/// Answers a connection rejected by the full queue with 503, without ever waiting for the client
+///
+/// Runs on the accepting thread, so only the bytes of the request that already arrived are read,
+/// which keeps the closing socket from resetting the connection before the client read the response.
+/// The response is written once without blocking. Encrypted connections are closed right away,
+/// as their handshake would have to wait for the client.
+fn reject_connection(mut stream: Connection, stats: Arc<ServerStats>, limits: RequestLimits) {
+    if let Connection::Tls(_) = stream {
+        let _ = stream.socket().shutdown(Shutdown::Both);
+        return;
+    }
+    if stream.socket().set_nonblocking(true).is_err() {
+        return;
+    }
+    let time = Utc::now().naive_local();
+    let start = Instant::now();
+    let mut buffer = RequestBuffer::new(limits);
+    let mut received = 0;
+    while received < REJECTED_READ_LIMIT {
+        match buffer.fill_from(&mut stream) {
+            Ok(bytes_read) if bytes_read > 0 => received += bytes_read,
+            _ => break
+        }
+    }
+    let path = match buffer.next_request() {
+        Ok(Some(request)) => request.request_identifiers.path,
+        _ => String::new()
+    };
+
+    let mut response = Response::default_service_unavailable(RETRY_AFTER);
+    let head = response.make_head_sendable();
+    if matches!(stream.write(&head), Ok(written) if written == head.len()) {
+        let duration = start.elapsed().as_millis();
+        stats.record(RequestResult { response_code: response.response_identifiers.method.id, duration, time, requested_resource: path });
+    }
+    let _ = stream.socket().shutdown(Shutdown::Write);
+}
+
+/// Handles a single request of the connection
+fn respond(stream: &mut Connection, request: &Request, server_files: ServerFiles, stats: Arc<ServerStats>, keep_alive: bool) -> Option<Response> {
+    let mut response = handle_request(request, server_files, stats);
+    response.keep_alive = keep_alive;
+
+    send_response(stream, &mut response).ok()?;
+    Some(response)
+}
+
+/// Answers a request that can not be served right now with 503
+fn respond_unavailable(stream: &mut Connection, _request: &Request, _server_files: ServerFiles, _stats: Arc<ServerStats>, keep_alive: bool) -> Option<Response> {
+    let mut response = Response::default_service_unavailable(RETRY_AFTER);
+    response.keep_alive = keep_alive;
+    send_response(stream, &mut response).ok()?;
+    Some(response)
+}
+
+/// Handles a request opening the WebSocket of the live stats, which is served until either side closes it
+fn respond_live_stats(stream: &mut Connection, request: &Request, server_files: ServerFiles, stats: Arc<ServerStats>, keep_alive: bool) -> Option<Response> {
+    let key = websocket::upgrade_key(request)?;
+    match stats_live_response(stream, request, key, stats, server_files.dynamic_files) {
+        Ok(response) => Some(response),
+        Err(mut response) => {
+            response.keep_alive = keep_alive;
+            send_response(stream, &mut response).ok()?;
+            Some(response)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::sync::{Mutex, mpsc::channel};
+    use std::time::Duration;
+    use crate::threaded::server::{PoolKind, QueuePolicy};
+    use crate::test_client::{check_connection_loop, server_files};
+
+    const LIMITS: RequestLimits = RequestLimits { max_body_size: 1024, max_header_size: 1024, read_timeout: Duration::from_secs(5) };
+
+    /// Connects a client to a loopback listener, returning both ends
+    fn connect() -> (TcpStream, Connection) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, Connection::new(server, None).unwrap())
+    }
+
+    fn read_to_string(mut client: TcpStream) -> String {
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn rejecting_pool_does_not_stall_test() {
+        let stats = Arc::new(ServerStats::new(vec![]));
+        let config = PoolConfig { size: 3, kind: PoolKind::Shared, queue_capacity: Some(1), queue_policy: QueuePolicy::Reject };
+        let pool = ThreadPool::new(config, stats.pool.clone());
+        let gate = Arc::new(Mutex::new(()));
+        let closed = gate.lock().unwrap();
+        let (started, start) = channel();
+        for _ in 0..3 {
+            let gate = gate.clone();
+            let started = started.clone();
+            pool.execute(move |_| {
+                started.send(()).unwrap();
+                drop(gate.lock());
+            });
+            start.recv().unwrap();
+        }
+        pool.execute(|_| {});
+
+        // the clients never send their requests, the accepting thread still does not wait for them
+        let begin = Instant::now();
+        let mut clients = Vec::new();
+        for _ in 0..5 {
+            let (client, connection) = connect();
+            let stats = stats.clone();
+            pool.execute(move |admission| {
+                assert_eq!(admission, Admission::Rejected);
+                reject_connection(connection, stats, LIMITS);
+            });
+            clients.push(client);
+        }
+        assert!(begin.elapsed() < Duration::from_millis(500));
+        drop(closed);
+
+        for client in clients {
+            let response = read_to_string(client);
+            assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", response);
+            assert!(response.contains("retry-after: 1\r\n"));
+            assert!(response.contains("connection: close\r\n"));
+        }
+        assert_eq!(stats.pool.rejected.load(std::sync::atomic::Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn reject_connection_test() {
+        let stats = Arc::new(ServerStats::new(vec![]));
+        let (mut client, connection) = connect();
+        client.write_all(b"GET /hello.html HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(20));
+        reject_connection(connection, stats.clone(), LIMITS);
+
+        assert!(read_to_string(client).starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert_eq!(stats.results()[0].requested_resource, "/hello.html");
+        assert_eq!(stats.results()[0].response_code, 503);
+    }
+
+    #[test]
+    fn live_stream_slot_test() {
+        let slots: Vec<LiveStreamSlot> = (0..MAX_LIVE_STREAMS).map(|_| LiveStreamSlot::take().unwrap()).collect();
+        assert!(LiveStreamSlot::take().is_none());
+        drop(slots);
+        assert!(LiveStreamSlot::take().is_some());
+        assert_eq!(LIVE_STREAMS.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn connection_loop_test() {
+        check_connection_loop(|connection, keep_alive| {
+            handle_connection(connection, server_files(), Arc::new(ServerStats::new(vec![])), LIMITS, keep_alive)
+        });
+    }
 }
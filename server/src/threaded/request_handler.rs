@@ -1,21 +1,98 @@
-use crate::request::Request;
-use crate::response::Response;
-use crate::threaded::controller::stats_controller::stats_response;
+use crate::request::{Request, RequestType};
+use crate::response::{compress_response, Response};
+use crate::threaded::controller::stats_controller::{stats_export_response, stats_response};
 use crate::threaded::controller::file_controller::file_response;
-use crate::threaded::server::{ServerStats, ServerFiles};
+use crate::threaded::controller::error_controller::{error_response_405, error_response_426, error_response_500};
+use crate::threaded::websocket;
+use crate::threaded::server::ServerStats;
+use crate::file::ServerFiles;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 
-/// Mapping endpoints to the corresponding controller actions
+/// Handles the request, a panic while handling it is answered with 500 and counted in the stats
+///
+/// The panic is caught before anything was sent, so the connection stays usable.
 pub fn handle_request(request: &Request, server_files: ServerFiles, stats: Arc<ServerStats>) -> Response {
+    let dynamic_files = server_files.dynamic_files.clone();
+    let pool = stats.pool.clone();
+    match panic::catch_unwind(AssertUnwindSafe(|| handle_method(request, server_files, stats))) {
+        Ok(response) => response,
+        Err(_) => {
+            pool.panicked.fetch_add(1, Ordering::Relaxed);
+            error_response_500("The request could not be handled.".to_string(), dynamic_files)
+        }
+    }
+}
+
+/// Mapping the request methods to the corresponding handling
+///
+/// HEAD requests are handled like GET requests, but only the head of the response is sent.
+fn handle_method(request: &Request, server_files: ServerFiles, stats: Arc<ServerStats>) -> Response {
+    let method = request.request_identifiers.method;
+    let compression = server_files.compression;
+
+    let mut response = match method {
+        RequestType::Get | RequestType::Head => handle_endpoint(request, server_files, stats),
+        RequestType::Options => Response::default_options(),
+        _ => error_response_405(format!("Request method {} is not allowed.", method.as_str()), server_files.dynamic_files)
+    };
+    response.omit_body = method == RequestType::Head;
+    compress_response(request, &mut response, compression);
+    response
+}
+
+/// Mapping endpoints to the corresponding controller actions
+fn handle_endpoint(request: &Request, server_files: ServerFiles, stats: Arc<ServerStats>) -> Response {
     let path = &request.request_identifiers.path;
 
     let response = match &path[..] {
-        "/stats" => stats_response(stats, server_files.dynamic_files),
-        _ => file_response(server_files, path.to_string())
+        "/stats" => stats_response(stats, server_files.dynamic_files, &request.query_params),
+        "/stats/export" => stats_export_response(stats, server_files.dynamic_files, request),
+        // opening handshakes are answered by the connection handler, as they take over the connection
+        "/stats/live" => {
+            let mut response = error_response_426("The live stats are only served over a WebSocket.".to_string(), "websocket", server_files.dynamic_files);
+            response.add_header("sec-websocket-version", websocket::VERSION);
+            Err(response)
+        }
+        _ => file_response(server_files, request)
     };
 
     match response {
         Ok(res) => res,
         Err(res) => res
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use chrono::Utc;
+    use crate::compression::CompressionMode;
+    use crate::request::{parse_request, RequestLimits};
+    use crate::threaded::server::RequestResult;
+
+    #[test]
+    fn panic_answered_with_500_test() {
+        // the stats page panics without its template
+        let files = ServerFiles {
+            static_files: Arc::new(std::sync::RwLock::new(Arc::new(HashMap::new()))),
+            dynamic_files: Arc::new(HashMap::new()),
+            directory_listing: false,
+            compression: CompressionMode::Off,
+            disk_files: None,
+        };
+        let stats = Arc::new(ServerStats::new(vec![]));
+        let limits = RequestLimits { max_body_size: 0, max_header_size: 1024, read_timeout: Duration::from_secs(1) };
+        let request = parse_request(b"GET /stats HTTP/1.1\r\n\r\n", limits).unwrap().unwrap().0;
+
+        let response = handle_request(&request, files, stats.clone());
+        assert_eq!(response.response_identifiers.method.id, 500);
+        assert_eq!(stats.pool.panicked.load(Ordering::SeqCst), 1);
+
+        // the results locked while panicking are still usable
+        stats.record(RequestResult { response_code: 500, requested_resource: "/stats".to_string(), time: Utc::now().naive_local(), duration: 0 });
+        assert_eq!(stats.results().len(), 1);
+    }
+}
@@ -1,20 +1,159 @@
-use std::sync::{mpsc::Sender, mpsc::channel, mpsc::Receiver, Arc, Mutex};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
+use std::time::Duration;
 use chrono::NaiveDateTime;
-use crate::{StaticFiles, DynamicFiles};
+use crossbeam_deque::{Injector, Stealer, Steal};
+use crate::shutdown;
 
 
 /// The threadpool struct that manages the threads
+///
+/// The jobs wait in a queue for the next free worker, either shared by all workers or split into a deque per worker.
+/// With a capacity the queue is bounded, what happens to jobs arriving while it is full is decided by the policy.
+/// Panicking jobs are caught and counted, and a worker whose thread died anyway is respawned.
+/// Dropping the pool shuts it down: the queued jobs are still executed,
+/// and the workers are joined until the deadline of the shutdown.
 #[derive(Debug)]
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    transmitter: Sender<Job>,
+    inner: Arc<PoolInner>,
+}
+
+/// The parts of the pool shared with its workers
+#[derive(Debug)]
+struct PoolInner {
+    queue: Box<dyn JobQueue>,
+    /// the started workers, including the respawned ones
+    workers: Mutex<Vec<Worker>>,
+    metrics: Arc<PoolMetrics>,
+}
+
+/// The size of the thread pool, how its workers share the jobs and the bounds of its queue
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PoolConfig {
+    /// the amount of worker threads
+    pub size: usize,
+    /// how the workers take the jobs from the queue
+    pub kind: PoolKind,
+    /// the most jobs waiting for a worker, unbounded if None
+    pub queue_capacity: Option<usize>,
+    /// what happens to new jobs while the queue is full
+    pub queue_policy: QueuePolicy,
+}
+
+/// How the workers of the pool take their jobs
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PoolKind {
+    /// all workers take the jobs from a single queue behind a lock
+    Shared,
+    /// every worker takes batches of jobs into its own deque, idle workers steal from the others
+    Stealing,
+}
+
+impl PoolKind {
+    /// Reads the kind of the pool from its name on the command line
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "shared" => Some(PoolKind::Shared),
+            "stealing" => Some(PoolKind::Stealing),
+            _ => None
+        }
+    }
+}
+
+/// What happens to a new job while the queue of the pool is full
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum QueuePolicy {
+    /// the thread adding the job waits until a worker took a job from the queue, e.g. the accept loop
+    Block,
+    /// the new job is rejected
+    Reject,
+    /// the oldest waiting job is rejected to make room for the new one
+    DropOldest,
+}
+
+impl QueuePolicy {
+    /// Reads the policy from its name on the command line
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "block" => Some(QueuePolicy::Block),
+            "reject" => Some(QueuePolicy::Reject),
+            "drop_oldest" => Some(QueuePolicy::DropOldest),
+            _ => None
+        }
+    }
+}
+
+/// Whether a job is run by a worker, or was rejected because the queue was full
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Admission {
+    Accepted,
+    Rejected,
+}
+
+/// The load of the queue of the thread pool and the failures of its workers
+#[derive(Debug, Default)]
+pub struct PoolMetrics {
+    /// the jobs currently waiting for a worker
+    pub depth: AtomicUsize,
+    /// the most jobs that were waiting at the same time
+    pub max_depth: AtomicUsize,
+    /// the jobs rejected because the queue was full, including the dropped oldest ones
+    pub rejected: AtomicUsize,
+    /// the panics caught while handling connections
+    pub panicked: AtomicUsize,
+    /// the workers started again after their thread died
+    pub respawned: AtomicUsize,
+}
+
+impl PoolMetrics {
+    fn set_depth(&self, depth: usize) {
+        self.depth.store(depth, Ordering::Relaxed);
+        self.max_depth.fetch_max(depth, Ordering::Relaxed);
+    }
 }
 
 /// The struct that manages Stats for the server
 #[derive(Debug)]
 pub struct ServerStats {
-    pub request_results: Mutex<Vec<RequestResult>>
+    pub request_results: Mutex<Vec<RequestResult>>,
+    /// the load and failures of the thread pool, shared with the pool
+    pub pool: Arc<PoolMetrics>,
+    /// notified whenever a result is recorded, e.g. to stream the stats live
+    recorded: Condvar,
+}
+
+impl ServerStats {
+    pub fn new(request_results: Vec<RequestResult>) -> Self {
+        Self { request_results: Mutex::new(request_results), pool: Arc::default(), recorded: Condvar::new() }
+    }
+
+    /// Locks the results, also if a thread panicked while holding them
+    pub fn results(&self) -> MutexGuard<'_, Vec<RequestResult>> {
+        match self.request_results.lock() {
+            Ok(results) => results,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+
+    /// Records the result of a handled request, waking up everyone waiting for new results
+    pub fn record(&self, result: RequestResult) {
+        self.results().push(result);
+        self.recorded.notify_all();
+    }
+
+    /// Waits until more than the seen amount of results were recorded or the timeout expired, returning all results
+    pub fn wait_for_results(&self, seen: usize, timeout: Duration) -> MutexGuard<'_, Vec<RequestResult>> {
+        let results = self.results();
+        match self.recorded.wait_timeout_while(results, timeout, |results| results.len() <= seen) {
+            Ok((results, _)) => results,
+            Err(poisoned) => poisoned.into_inner().0
+        }
+    }
 }
 
 /// The struct that manages a single stat about a specific request
@@ -32,36 +171,318 @@ impl RequestResult {
     }
 }
 
-/// Wrapper for both static and dynamic files the server is able to serve
-#[derive(Clone)]
-pub struct ServerFiles {
-    pub static_files: StaticFiles,
-    pub dynamic_files: DynamicFiles
+impl ThreadPool {
+    /// Starts the workers of the pool, the load of its queue and the failures are tracked in the metrics
+    pub fn new(config: PoolConfig, metrics: Arc<PoolMetrics>) -> ThreadPool {
+        let queue: Box<dyn JobQueue> = match config.kind {
+            PoolKind::Shared => Box::new(SharedQueue::new(config, metrics.clone())),
+            PoolKind::Stealing => Box::new(StealingQueue::new(config, metrics.clone())),
+        };
+        let inner = Arc::new(PoolInner { queue, workers: Mutex::new(Vec::with_capacity(config.size)), metrics });
+
+        for id in 0..config.size {
+            let worker = Worker::spawn(id, Arc::clone(&inner));
+            inner.lock_workers().push(worker);
+        }
+        ThreadPool { inner }
+    }
+
+    /// Queues the function for the next free worker
+    ///
+    /// A rejected function is called right away on this thread with `Admission::Rejected`,
+    /// so it can still answer its connection.
+    pub fn execute<F>(&self, function: F)
+        where F: FnOnce(Admission) + Send + 'static {
+        if let Some(rejected) = self.inner.queue.push(Box::new(function)) {
+            run(rejected, Admission::Rejected, &self.inner.metrics);
+        }
+    }
 }
 
-impl ThreadPool {
-    pub fn new(size: usize) -> ThreadPool {
-        let (tx, rx) = channel();
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.inner.queue.close();
+        let deadline = shutdown::deadline();
+        let mut running = 0;
+        // workers respawned meanwhile are joined in the next round
+        while running == 0 {
+            let threads: Vec<thread::JoinHandle<()>> = self.inner.lock_workers().drain(..).map(|worker| worker.thread).collect();
+            if threads.is_empty() {
+                break;
+            }
+            running = shutdown::join_until(threads, deadline);
+        }
+        if running > 0 {
+            println!("{} workers did not finish their connections in time", running);
+        }
+    }
+}
+
+impl PoolInner {
+    /// Locks the workers, also if a thread panicked while holding them
+    fn lock_workers(&self) -> MutexGuard<'_, Vec<Worker>> {
+        match self.workers.lock() {
+            Ok(workers) => workers,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+}
+
+/// Runs the job, a panic is counted instead of taking down the thread running it
+fn run(job: Job, admission: Admission, metrics: &PoolMetrics) {
+    if panic::catch_unwind(AssertUnwindSafe(|| job.call_box(admission))).is_err() {
+        metrics.panicked.fetch_add(1, Ordering::Relaxed);
+    }
+}
 
-        let receiver = Arc::new(Mutex::new(rx));
+/// The jobs waiting for a worker, shared by the pool and its workers
+trait JobQueue: Debug + Send + Sync {
+    /// Adds the job to the queue, returning the job rejected by the policy if the queue is full
+    fn push(&self, job: Job) -> Option<Job>;
 
-        let mut workers = Vec::with_capacity(size);
+    /// Runs the jobs for the worker, returning once the queue is closed and empty
+    fn work(&self, worker: usize);
 
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+    /// Lets the workers stop once they took the remaining jobs
+    fn close(&self);
+}
+
+/// A single queue all workers take their jobs from
+#[derive(Debug)]
+struct SharedQueue {
+    state: Mutex<QueueState>,
+    /// notified when a job was queued or the queue was closed
+    available: Condvar,
+    /// notified when a worker took a job, for the threads waiting for room in a full queue
+    space: Condvar,
+    capacity: Option<usize>,
+    policy: QueuePolicy,
+    metrics: Arc<PoolMetrics>,
+}
+
+struct QueueState {
+    jobs: VecDeque<Job>,
+    /// whether the pool was dropped, the workers stop once the queue is empty
+    closed: bool,
+}
+
+impl Debug for QueueState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueueState").field("jobs", &self.jobs.len()).field("closed", &self.closed).finish()
+    }
+}
+
+impl SharedQueue {
+    fn new(config: PoolConfig, metrics: Arc<PoolMetrics>) -> Self {
+        SharedQueue {
+            state: Mutex::new(QueueState { jobs: VecDeque::new(), closed: false }),
+            available: Condvar::new(),
+            space: Condvar::new(),
+            capacity: config.queue_capacity,
+            policy: config.queue_policy,
+            metrics,
         }
-        ThreadPool { workers, transmitter: tx }
     }
 
-    pub fn execute<F>(&self, function: F)
-        where F: FnOnce() + Send + 'static {
-        let job = Box::new(function);
-        self.transmitter.send(job).unwrap();
+    /// Waits for the next job, returning None once the queue is closed and empty
+    fn take(&self) -> Option<Job> {
+        let state = self.state.lock().unwrap();
+        let mut state = self.available.wait_while(state, |state| state.jobs.is_empty() && !state.closed).unwrap();
+        let job = state.jobs.pop_front()?;
+        self.metrics.set_depth(state.jobs.len());
+        self.space.notify_one();
+        Some(job)
+    }
+}
+
+impl JobQueue for SharedQueue {
+    fn push(&self, job: Job) -> Option<Job> {
+        let mut state = self.state.lock().unwrap();
+        let mut rejected = None;
+        if let Some(capacity) = self.capacity.filter(|capacity| state.jobs.len() >= *capacity) {
+            match self.policy {
+                QueuePolicy::Block => {
+                    state = self.space.wait_while(state, |state| state.jobs.len() >= capacity).unwrap();
+                }
+                QueuePolicy::Reject => {
+                    self.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Some(job);
+                }
+                QueuePolicy::DropOldest => {
+                    self.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                    rejected = state.jobs.pop_front();
+                }
+            }
+        }
+        state.jobs.push_back(job);
+        self.metrics.set_depth(state.jobs.len());
+        self.available.notify_one();
+        rejected
+    }
+
+    fn work(&self, _worker: usize) {
+        while let Some(job) = self.take() {
+            run(job, Admission::Accepted, &self.metrics);
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.available.notify_all();
+    }
+}
+
+/// New jobs are injected into a global queue, from which every worker takes batches into its own deque
+///
+/// Workers without jobs steal from the global queue and the deques of the others,
+/// so taking a job needs no lock. The lock is only taken by the threads adding jobs,
+/// by idle workers before they sleep, and with the block policy to wake the waiting threads.
+#[derive(Debug)]
+struct StealingQueue {
+    injector: Injector<Job>,
+    /// the other ends of the deques of all workers
+    stealers: Vec<Stealer<Job>>,
+    /// the deques, each taken by its worker when it starts
+    deques: Mutex<Vec<Option<crossbeam_deque::Worker<Job>>>>,
+    /// the jobs waiting in the global queue and the deques
+    pending: AtomicUsize,
+    /// whether the pool was dropped, the workers stop once no jobs are pending
+    closed: Mutex<bool>,
+    /// notified when a job was queued or the queue was closed
+    available: Condvar,
+    /// notified when a worker took a job, for the threads waiting for room in a full queue
+    space: Condvar,
+    capacity: Option<usize>,
+    policy: QueuePolicy,
+    metrics: Arc<PoolMetrics>,
+}
+
+impl StealingQueue {
+    fn new(config: PoolConfig, metrics: Arc<PoolMetrics>) -> Self {
+        let deques: Vec<crossbeam_deque::Worker<Job>> = (0..config.size).map(|_| crossbeam_deque::Worker::new_fifo()).collect();
+        StealingQueue {
+            injector: Injector::new(),
+            stealers: deques.iter().map(|deque| deque.stealer()).collect(),
+            deques: Mutex::new(deques.into_iter().map(Some).collect()),
+            pending: AtomicUsize::new(0),
+            closed: Mutex::new(false),
+            available: Condvar::new(),
+            space: Condvar::new(),
+            capacity: config.queue_capacity,
+            policy: config.queue_policy,
+            metrics,
+        }
+    }
+
+    /// Takes the next job of the deque, refilling it from the global queue or stealing from the other deques
+    fn find(&self, deque: &crossbeam_deque::Worker<Job>) -> Option<Job> {
+        deque.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector.steal_batch_and_pop(deque)
+                    .or_else(|| self.stealers.iter().map(|stealer| stealer.steal()).collect())
+            })
+                .find(|steal| !steal.is_retry())
+                .and_then(Steal::success)
+        })
+    }
+
+    /// Takes the oldest waiting job out of the deques, or the global queue if they are empty
+    ///
+    /// The workers took the jobs in their deques out of the global queue, so these are older than the ones left in it.
+    fn steal_oldest(&self) -> Option<Job> {
+        iter::repeat_with(|| {
+            self.stealers.iter().map(|stealer| stealer.steal()).collect::<Steal<Job>>()
+                .or_else(|| self.injector.steal())
+        })
+            .find(|steal| !steal.is_retry())
+            .and_then(Steal::success)
+    }
+
+    /// Counts the job as taken out of the queue
+    fn taken(&self) {
+        let pending = self.pending.fetch_sub(1, Ordering::SeqCst) - 1;
+        self.metrics.set_depth(pending);
+        if self.capacity.is_some() && self.policy == QueuePolicy::Block {
+            let _closed = self.closed.lock().unwrap();
+            self.space.notify_one();
+        }
+    }
+}
+
+impl JobQueue for StealingQueue {
+    fn push(&self, job: Job) -> Option<Job> {
+        let mut closed = self.closed.lock().unwrap();
+        let mut rejected = None;
+        if let Some(capacity) = self.capacity.filter(|capacity| self.pending.load(Ordering::SeqCst) >= *capacity) {
+            match self.policy {
+                QueuePolicy::Block => {
+                    closed = self.space.wait_while(closed, |_| self.pending.load(Ordering::SeqCst) >= capacity).unwrap();
+                }
+                QueuePolicy::Reject => {
+                    self.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Some(job);
+                }
+                QueuePolicy::DropOldest => {
+                    self.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                    // a worker may have taken the oldest job meanwhile, then there is room again
+                    rejected = self.steal_oldest();
+                    if rejected.is_some() {
+                        self.pending.fetch_sub(1, Ordering::SeqCst);
+                    }
+                }
+            }
+        }
+        self.injector.push(job);
+        let pending = self.pending.fetch_add(1, Ordering::SeqCst) + 1;
+        self.metrics.set_depth(pending);
+        self.available.notify_one();
+        drop(closed);
+        rejected
+    }
+
+    fn work(&self, worker: usize) {
+        let deque = self.deques.lock().unwrap()[worker].take().expect("The deque of the worker is taken once");
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.work_on(&deque)));
+        // a respawned worker continues with the jobs left in the deque
+        self.deques.lock().unwrap()[worker] = Some(deque);
+        if let Err(panic) = result {
+            panic::resume_unwind(panic);
+        }
+    }
+
+    fn close(&self) {
+        *self.closed.lock().unwrap() = true;
+        self.available.notify_all();
+    }
+}
+
+impl StealingQueue {
+    /// Runs the jobs of the deque and the stolen ones, returning once the queue is closed and no jobs are pending
+    fn work_on(&self, deque: &crossbeam_deque::Worker<Job>) {
+        loop {
+            if let Some(job) = self.find(deque) {
+                self.taken();
+                run(job, Admission::Accepted, &self.metrics);
+                continue;
+            }
+            // a pending job may be between being stolen and being counted as taken, which is checked again right away
+            let closed = self.closed.lock().unwrap();
+            if self.pending.load(Ordering::SeqCst) == 0 {
+                if *closed {
+                    return;
+                }
+                drop(self.available.wait_while(closed, |closed| self.pending.load(Ordering::SeqCst) == 0 && !*closed).unwrap());
+            } else {
+                drop(closed);
+                thread::yield_now();
+            }
+        }
     }
 }
 
 /// The worker thread
 #[derive(Debug)]
+#[allow(dead_code)]
 struct Worker {
     id: usize,
     thread: thread::JoinHandle<()>,
@@ -69,27 +490,251 @@ struct Worker {
 
 
 impl Worker {
-    /// Creates a new worker thread
-    fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>) -> Self {
+    /// Starts a new worker thread, which is respawned if it dies
+    fn spawn(id: usize, pool: Arc<PoolInner>) -> Self {
         Worker {
             id,
-            thread: thread::spawn(move || loop {
-                let job = receiver.lock().unwrap().recv().unwrap();
-                job.call_box();
+            thread: thread::spawn(move || {
+                let sentinel = Sentinel { id, pool };
+                sentinel.pool.queue.work(id);
             }),
         }
     }
 }
 
+/// Respawns the worker when its thread dies, e.g. because of a bug in the queue
+///
+/// The panics of the jobs are already caught while running them, so their workers live on.
+struct Sentinel {
+    id: usize,
+    pool: Arc<PoolInner>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            println!("Worker {} died, respawning it", self.id);
+            self.pool.metrics.respawned.fetch_add(1, Ordering::Relaxed);
+            let worker = Worker::spawn(self.id, self.pool.clone());
+            self.pool.lock_workers().push(worker);
+        }
+    }
+}
+
 /// A trait that makes the storing of the function that should be run when the thread runs possible
 trait FnBox {
-    fn call_box(self: Box<Self>);
+    fn call_box(self: Box<Self>, admission: Admission);
 }
 
-impl<F: FnOnce()> FnBox for F {
-    fn call_box(self: Box<F>) {
-        (*self)()
+impl<F: FnOnce(Admission)> FnBox for F {
+    fn call_box(self: Box<F>, admission: Admission) {
+        (*self)(admission)
     }
 }
 
 type Job = Box<dyn FnBox + Send + 'static>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    const KINDS: [PoolKind; 2] = [PoolKind::Shared, PoolKind::Stealing];
+
+    fn config(kind: PoolKind, queue_capacity: Option<usize>, queue_policy: QueuePolicy) -> PoolConfig {
+        PoolConfig { size: 3, kind, queue_capacity, queue_policy }
+    }
+
+    #[test]
+    fn drop_drains_queue_test() {
+        for kind in KINDS {
+            let executed = Arc::new(AtomicUsize::new(0));
+            let pool = ThreadPool::new(config(kind, None, QueuePolicy::Block), Arc::default());
+            for _ in 0..20 {
+                let executed = executed.clone();
+                pool.execute(move |admission| {
+                    assert_eq!(admission, Admission::Accepted);
+                    thread::sleep(Duration::from_millis(5));
+                    executed.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            drop(pool);
+            assert_eq!(executed.load(Ordering::SeqCst), 20, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn stealing_test() {
+        let pool = ThreadPool::new(config(PoolKind::Stealing, None, QueuePolicy::Block), Arc::default());
+        let (release, released) = channel::<()>();
+        let (executed, executions) = channel();
+        pool.execute(move |_| {
+            let _ = released.recv();
+        });
+        // the jobs taken in a batch by the blocked worker are stolen by the others
+        for number in 0..50 {
+            let executed = executed.clone();
+            pool.execute(move |_| executed.send(number).unwrap());
+        }
+        let mut numbers: Vec<usize> = (0..50).map(|_| executions.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        numbers.sort_unstable();
+        assert_eq!(numbers, (0..50).collect::<Vec<usize>>());
+        drop(release);
+    }
+
+    #[test]
+    fn panicking_job_test() {
+        for kind in KINDS {
+            let metrics = Arc::new(PoolMetrics::default());
+            let pool = ThreadPool::new(config(kind, None, QueuePolicy::Block), metrics.clone());
+            for _ in 0..6 {
+                pool.execute(|_| panic!("The job failed"));
+            }
+            // all three workers survived the panics, so three jobs run at the same time
+            let gate = Arc::new(Mutex::new(()));
+            let closed = gate.lock().unwrap();
+            let (started, start) = channel();
+            for _ in 0..3 {
+                let gate = gate.clone();
+                let started = started.clone();
+                pool.execute(move |_| {
+                    started.send(()).unwrap();
+                    drop(gate.lock());
+                });
+            }
+            for _ in 0..3 {
+                start.recv_timeout(Duration::from_secs(5)).unwrap();
+            }
+            drop(closed);
+            drop(pool);
+            assert_eq!(metrics.panicked.load(Ordering::SeqCst), 6, "{:?}", kind);
+            assert_eq!(metrics.respawned.load(Ordering::SeqCst), 0);
+        }
+    }
+
+    #[test]
+    fn respawn_test() {
+        let metrics = Arc::new(PoolMetrics::default());
+        let pool = ThreadPool::new(config(PoolKind::Shared, None, QueuePolicy::Block), metrics.clone());
+        let inner = pool.inner.clone();
+        let died = thread::spawn(move || {
+            let _sentinel = Sentinel { id: 3, pool: inner };
+            panic!("The worker died");
+        });
+        assert!(died.join().is_err());
+        assert_eq!(metrics.respawned.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.inner.lock_workers().len(), 4);
+
+        let executed = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let executed = executed.clone();
+            pool.execute(move |_| {
+                executed.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(pool);
+        assert_eq!(executed.load(Ordering::SeqCst), 10);
+    }
+
+    /// Fills the pool with jobs blocking the workers, then queues numbered jobs reporting their admission
+    fn overload(kind: PoolKind, policy: QueuePolicy) -> (Vec<(usize, Admission)>, Arc<PoolMetrics>) {
+        let metrics = Arc::new(PoolMetrics::default());
+        let pool = ThreadPool::new(config(kind, Some(2), policy), metrics.clone());
+        let (release, released) = channel::<()>();
+        let released = Arc::new(Mutex::new(released));
+        let (started, start) = channel();
+        // each worker is blocked before the next job is queued, so none of them is rejected
+        for _ in 0..3 {
+            let released = released.clone();
+            let started = started.clone();
+            pool.execute(move |_| {
+                started.send(()).unwrap();
+                let _ = released.lock().unwrap().recv();
+            });
+            start.recv().unwrap();
+        }
+
+        let (admitted, admissions) = channel();
+        for number in 0..4 {
+            let admitted = admitted.clone();
+            pool.execute(move |admission| admitted.send((number, admission)).unwrap());
+        }
+        drop(release);
+        drop(pool);
+        drop(admitted);
+        let mut admissions: Vec<(usize, Admission)> = admissions.iter().collect();
+        admissions.sort_by_key(|(number, _)| *number);
+        (admissions, metrics)
+    }
+
+    #[test]
+    fn reject_policy_test() {
+        for kind in KINDS {
+            let (admissions, metrics) = overload(kind, QueuePolicy::Reject);
+            assert_eq!(admissions, [(0, Admission::Accepted), (1, Admission::Accepted), (2, Admission::Rejected), (3, Admission::Rejected)]);
+            assert_eq!(metrics.rejected.load(Ordering::SeqCst), 2);
+            assert_eq!(metrics.max_depth.load(Ordering::SeqCst), 2);
+            assert_eq!(metrics.depth.load(Ordering::SeqCst), 0);
+        }
+    }
+
+    #[test]
+    fn drop_oldest_policy_test() {
+        for kind in KINDS {
+            let (admissions, metrics) = overload(kind, QueuePolicy::DropOldest);
+            assert_eq!(admissions, [(0, Admission::Rejected), (1, Admission::Rejected), (2, Admission::Accepted), (3, Admission::Accepted)]);
+            assert_eq!(metrics.rejected.load(Ordering::SeqCst), 2);
+        }
+    }
+
+    #[test]
+    fn drop_oldest_stealing_order_test() {
+        let queue = StealingQueue::new(config(PoolKind::Stealing, Some(5), QueuePolicy::DropOldest), Arc::new(PoolMetrics::default()));
+        let (admitted, admissions) = channel();
+        let job = |number: usize| -> Job {
+            let admitted = admitted.clone();
+            Box::new(move |admission| admitted.send((number, admission)).unwrap())
+        };
+        for number in 0..5 {
+            assert!(queue.push(job(number)).is_none());
+        }
+
+        // the worker takes a batch of the oldest jobs into its deque, the newer ones stay in the global queue
+        let deque = queue.deques.lock().unwrap()[0].take().unwrap();
+        run(queue.find(&deque).unwrap(), Admission::Accepted, &queue.metrics);
+        queue.taken();
+        assert!(!deque.is_empty());
+        assert!(!queue.injector.is_empty());
+
+        assert!(queue.push(job(5)).is_none());
+        run(queue.push(job(6)).unwrap(), Admission::Rejected, &queue.metrics);
+        assert_eq!(admissions.try_iter().collect::<Vec<_>>(), [(0, Admission::Accepted), (1, Admission::Rejected)]);
+    }
+
+    #[test]
+    fn block_policy_test() {
+        for kind in KINDS {
+            let metrics = Arc::new(PoolMetrics::default());
+            let pool = Arc::new(ThreadPool::new(config(kind, Some(1), QueuePolicy::Block), metrics.clone()));
+            let (release, released) = channel::<()>();
+            let released = Arc::new(Mutex::new(released));
+            for _ in 0..4 {
+                let released = released.clone();
+                pool.execute(move |_| {
+                    let _ = released.lock().unwrap().recv();
+                });
+            }
+            // the three workers are busy and the queue is full, so the next job waits
+            let blocked = {
+                let pool = pool.clone();
+                thread::spawn(move || pool.execute(|_| {}))
+            };
+            thread::sleep(Duration::from_millis(50));
+            assert!(!blocked.is_finished());
+            drop(release);
+            blocked.join().unwrap();
+            assert_eq!(metrics.rejected.load(Ordering::SeqCst), 0);
+            assert_eq!(metrics.max_depth.load(Ordering::SeqCst), 1);
+        }
+    }
+}
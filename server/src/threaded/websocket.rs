@@ -0,0 +1,336 @@
+//! Module speaking the WebSocket protocol (RFC 6455) on connections of the threaded server
+//!
+//! The handshake is an HTTP/1.1 GET request, which is upgraded with a 101 response.
+//! Afterwards both sides exchange frames: the frames of the client have to be masked,
+//! the ones of the server are sent unmasked.
+
+use std::convert::TryInto;
+use std::io::{self, ErrorKind, Read, Write};
+use sha1_smol::Sha1;
+use crate::connection::Connection;
+use crate::request::Request;
+use crate::response::Response;
+
+/// The GUID appended to the key of the client, proving the server understood the handshake
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// The only version of the protocol, older drafts are not supported
+pub const VERSION: &str = "13";
+/// The largest message accepted from the client, larger ones close the connection
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+/// The amount of bytes read from the connection at once
+const READ_CHUNK_SIZE: usize = 4096;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// The status code closing the connection normally
+pub const CLOSE_NORMAL: u16 = 1000;
+/// The status code closing the connection as the server shuts down
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+/// The status code closing the connection after the client violated the protocol
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+/// The status code closing the connection after the client sent a message larger than accepted
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// The key of the client, if the request is a valid opening handshake
+pub fn upgrade_key(request: &Request) -> Option<&str> {
+    let has_token = |header: &str, token: &str| request.get_header(header)
+        .is_some_and(|value| value.split(',').any(|option| option.trim().eq_ignore_ascii_case(token)));
+    let valid = request.request_identifiers.version == "HTTP/1.1"
+        && request.request_identifiers.method == crate::request::RequestType::Get
+        && has_token("upgrade", "websocket")
+        && has_token("connection", "upgrade")
+        && request.get_header("sec-websocket-version") == Some(VERSION);
+    let key = request.get_header("sec-websocket-key")?.trim();
+    // the key is the base64 encoding of 16 random bytes
+    if !valid || key.len() != 24 || !key.ends_with("==") {
+        return None;
+    }
+    Some(key)
+}
+
+/// The response accepting the opening handshake with the key of the client
+pub fn handshake_response(key: &str) -> Response {
+    let mut response = Response::default_switching_protocols("websocket");
+    response.add_header("sec-websocket-accept", &accept_key(key));
+    response
+}
+
+/// The key proving the handshake was understood: the base64 encoded sha1 hash of the key of the client and the GUID
+fn accept_key(key: &str) -> String {
+    let mut hash = Sha1::new();
+    hash.update(key.as_bytes());
+    hash.update(GUID.as_bytes());
+    encode_base64(&hash.digest().bytes())
+}
+
+/// Encodes the bytes in base64 with padding
+fn encode_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for group in bytes.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (index, byte)| bits | (*byte as u32) << (16 - 8 * index));
+        for index in 0..4 {
+            if index <= group.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * index) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// An upgraded connection, sending and receiving WebSocket frames
+pub struct WebSocket<'a> {
+    stream: &'a mut Connection,
+    /// the bytes received but not yet taken as frames
+    received: Vec<u8>,
+    /// the size of the fragmented message still being received
+    message_size: Option<usize>,
+    closed: bool,
+}
+
+/// A frame of the client, with the payload already unmasked
+#[derive(Debug, Eq, PartialEq)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl<'a> WebSocket<'a> {
+    /// Wraps the connection after the handshake response was sent
+    pub fn new(stream: &'a mut Connection) -> Self {
+        Self { stream, received: Vec::new(), message_size: None, closed: false }
+    }
+
+    /// Sends the text in a single message
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.send(OPCODE_TEXT, text.as_bytes())
+    }
+
+    /// Closes the connection with the status code, the client is expected to close the socket afterwards
+    pub fn close(&mut self, code: u16) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        self.send(OPCODE_CLOSE, &code.to_be_bytes())
+    }
+
+    fn send(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        write_frame(&mut frame, opcode, payload);
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+
+    /// Handles the frames the client sent so far, without waiting for more
+    ///
+    /// Pings are answered with pongs, the messages of the client are dropped, as the server only pushes data.
+    /// Returns whether the connection is still open.
+    pub fn receive(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        self.stream.socket().set_nonblocking(true)?;
+        let read = loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => break Ok(false),
+                Ok(length) => self.received.extend_from_slice(&chunk[..length]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break Ok(true),
+                Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                Err(error) => break Err(error)
+            }
+        };
+        self.stream.socket().set_nonblocking(false)?;
+        if !read? {
+            self.closed = true;
+            return Ok(false);
+        }
+
+        while !self.closed {
+            let frame = match take_frame(&mut self.received) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(code) => {
+                    self.close(code)?;
+                    break;
+                }
+            };
+            self.handle_frame(frame)?;
+        }
+        Ok(!self.closed)
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> io::Result<()> {
+        match frame.opcode {
+            OPCODE_PING => self.send(OPCODE_PONG, &frame.payload),
+            OPCODE_PONG => Ok(()),
+            // the close frame is echoed with its status code
+            OPCODE_CLOSE => {
+                let code = match frame.payload.len() {
+                    0 => CLOSE_NORMAL,
+                    1 => CLOSE_PROTOCOL_ERROR,
+                    _ => u16::from_be_bytes([frame.payload[0], frame.payload[1]]),
+                };
+                self.close(code)
+            }
+            OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                let continues = frame.opcode == OPCODE_CONTINUATION;
+                let size = match self.message_size {
+                    Some(size) if continues => size + frame.payload.len(),
+                    None if !continues => frame.payload.len(),
+                    _ => return self.close(CLOSE_PROTOCOL_ERROR),
+                };
+                if size > MAX_MESSAGE_SIZE {
+                    return self.close(CLOSE_TOO_BIG);
+                }
+                self.message_size = if frame.fin { None } else { Some(size) };
+                Ok(())
+            }
+            _ => self.close(CLOSE_PROTOCOL_ERROR),
+        }
+    }
+}
+
+/// Takes the next complete frame of the client from the buffer, unmasking its payload
+///
+/// Frames violating the protocol return the status code the connection is closed with.
+fn take_frame(received: &mut Vec<u8>) -> Result<Option<Frame>, u16> {
+    if received.len() < 2 {
+        return Ok(None);
+    }
+    let fin = received[0] & 0x80 != 0;
+    let opcode = received[0] & 0x0f;
+    let masked = received[1] & 0x80 != 0;
+    if received[0] & 0x70 != 0 || !masked {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+    let (length, mut position) = match received[1] & 0x7f {
+        126 if received.len() >= 4 => (u16::from_be_bytes([received[2], received[3]]) as u64, 4),
+        127 if received.len() >= 10 => (u64::from_be_bytes(received[2..10].try_into().unwrap()), 10),
+        126 | 127 => return Ok(None),
+        length => (length as u64, 2),
+    };
+    let is_control = opcode & 0x08 != 0;
+    if is_control && (!fin || length > 125) {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+    if length > MAX_MESSAGE_SIZE as u64 {
+        return Err(CLOSE_TOO_BIG);
+    }
+    let length = length as usize;
+    if received.len() < position + 4 + length {
+        return Ok(None);
+    }
+    let mask = [received[position], received[position + 1], received[position + 2], received[position + 3]];
+    position += 4;
+    let payload = received[position..position + length].iter().enumerate()
+        .map(|(index, byte)| byte ^ mask[index % 4])
+        .collect();
+    received.drain(..position + length);
+    Ok(Some(Frame { fin, opcode, payload }))
+}
+
+/// Writes an unmasked, final frame of the server
+fn write_frame(out: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    out.push(0x80 | opcode);
+    match payload.len() {
+        length if length < 126 => out.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            out.push(126);
+            out.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            out.push(127);
+            out.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::request::{parse_request, RequestLimits};
+
+    fn request(head: &str) -> Request {
+        let limits = RequestLimits { max_body_size: 0, max_header_size: 1024, read_timeout: Duration::from_secs(1) };
+        parse_request(head.as_bytes(), limits).unwrap().unwrap().0
+    }
+
+    #[test]
+    fn upgrade_key_test() {
+        let handshake = "GET /stats/live HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        assert_eq!(upgrade_key(&request(handshake)), Some("dGhlIHNhbXBsZSBub25jZQ=="));
+        assert_eq!(upgrade_key(&request(&handshake.replace("Version: 13", "Version: 8"))), None);
+        assert_eq!(upgrade_key(&request(&handshake.replace("Upgrade: websocket", "Upgrade: h2c"))), None);
+        assert_eq!(upgrade_key(&request(&handshake.replace("HTTP/1.1", "HTTP/1.0"))), None);
+        assert_eq!(upgrade_key(&request(&handshake.replace("dGhlIHNhbXBsZSBub25jZQ==", "short"))), None);
+    }
+
+    #[test]
+    fn handshake_response_test() {
+        // the example of RFC 6455 section 1.3
+        let mut response = handshake_response("dGhlIHNhbXBsZSBub25jZQ==");
+        let head = String::from_utf8(response.make_head_sendable()).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("sec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(head.contains("upgrade: websocket\r\n"));
+        assert!(head.contains("connection: upgrade\r\n"));
+        assert!(!head.contains("content-length"));
+        assert!(!head.contains("connection: close"));
+    }
+
+    #[test]
+    fn encode_base64_test() {
+        assert_eq!(encode_base64(b""), "");
+        assert_eq!(encode_base64(b"f"), "Zg==");
+        assert_eq!(encode_base64(b"fo"), "Zm8=");
+        assert_eq!(encode_base64(b"foo"), "Zm9v");
+        assert_eq!(encode_base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn take_frame_test() {
+        // the masked "Hello" of RFC 6455 section 5.7, followed by the start of the next frame
+        let mut received = vec![0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58, 0x89];
+        assert_eq!(take_frame(&mut received), Ok(Some(Frame { fin: true, opcode: OPCODE_TEXT, payload: b"Hello".to_vec() })));
+        assert_eq!(received, [0x89]);
+        assert_eq!(take_frame(&mut received), Ok(None));
+
+        let mut long = vec![0x82, 0xfe, 0x01, 0x00, 0, 0, 0, 0];
+        long.extend_from_slice(&[7; 255]);
+        assert_eq!(take_frame(&mut long), Ok(None));
+        long.push(7);
+        assert_eq!(take_frame(&mut long).unwrap().unwrap().payload, vec![7; 256]);
+
+        assert_eq!(take_frame(&mut vec![0x81, 0x05, b'H', b'e', b'l', b'l', b'o']), Err(CLOSE_PROTOCOL_ERROR));
+        assert_eq!(take_frame(&mut vec![0x09, 0x80, 0, 0, 0, 0]), Err(CLOSE_PROTOCOL_ERROR));
+        let too_big: [u8; 8] = (MAX_MESSAGE_SIZE as u64 + 1).to_be_bytes();
+        let mut frame = vec![0x82, 0xff];
+        frame.extend_from_slice(&too_big);
+        assert_eq!(take_frame(&mut frame), Err(CLOSE_TOO_BIG));
+    }
+
+    #[test]
+    fn write_frame_test() {
+        let mut out = Vec::new();
+        write_frame(&mut out, OPCODE_TEXT, b"Hello");
+        assert_eq!(out, [0x81, 0x05, b'H', b'e', b'l', b'l', b'o']);
+
+        for (length, header_length) in [(125, 2), (126, 4), (65535, 4), (65536, 10)] {
+            let mut out = Vec::new();
+            write_frame(&mut out, OPCODE_BINARY, &vec![0; length]);
+            assert_eq!(out.len(), header_length + length);
+        }
+        let mut out = Vec::new();
+        write_frame(&mut out, OPCODE_BINARY, &[0; 65536]);
+        assert_eq!(u64::from_be_bytes(out[2..10].try_into().unwrap()), 65536);
+    }
+}
@@ -0,0 +1,217 @@
+//! Module decoding the parts of the request target, as the path and the query string
+
+use std::collections::HashMap;
+
+/// The parameters of a query string, percent-decoded
+///
+/// A key may occur multiple times, e.g. ?path=/a.html&path=/b.html
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct QueryParams {
+    params: HashMap<String, Vec<String>>,
+}
+
+impl QueryParams {
+    /// Parses the raw query string, without the leading question mark
+    ///
+    /// Pairs are separated by & or ;, a key without a value gets an empty value.
+    /// A plus stands for a space. Malformed percent-encodings are kept as they are.
+    pub fn parse(raw: &str) -> Self {
+        let mut params: HashMap<String, Vec<String>> = HashMap::new();
+        raw.split(['&', ';'])
+            .filter(|pair| !pair.is_empty())
+            .for_each(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                params.entry(decode_component(key)).or_default().push(decode_component(value));
+            });
+        Self { params }
+    }
+
+    /// The first value of the key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.params.get(key).and_then(|values| values.first()).map(String::as_str)
+    }
+
+    /// All values of the key, in the order they were sent
+    pub fn get_all(&self, key: &str) -> &[String] {
+        self.params.get(key).map(Vec::as_slice).unwrap_or(&[])
+    }
+}
+
+/// Decodes a key or value of the query string
+fn decode_component(component: &str) -> String {
+    let component = component.replace('+', " ");
+    match percent_decode(&component) {
+        Some(decoded) => String::from_utf8_lossy(&decoded).into_owned(),
+        None => component
+    }
+}
+
+/// Decodes the %XX escapes of the input into the bytes they stand for
+///
+/// Returns None if a percent sign is not followed by two hex digits.
+pub fn percent_decode(input: &str) -> Option<Vec<u8>> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = bytes.get(index + 1..index + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    Some(decoded)
+}
+
+/// Percent-encodes the path, so it can be used as link or location
+///
+/// Everything except the unreserved characters and the slashes is encoded.
+pub fn percent_encode(path: &str) -> String {
+    path.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte)
+    }).collect()
+}
+
+/// Normalises the path of the request target into the form the files are stored with
+///
+/// Percent-decodes every segment, collapses duplicate slashes and removes dot segments,
+/// keeping a trailing slash. Absolute targets are reduced to their path, the asterisk of
+/// OPTIONS requests is kept. Encoded NULs and slashes as well as paths leaving the root are rejected.
+pub fn normalize_path(target: &str) -> Result<String, String> {
+    if target == "*" {
+        return Ok(target.to_string());
+    }
+    let path = strip_authority(target);
+    if !path.starts_with('/') {
+        return Err(format!("Path {} does not start with a slash.", target));
+    }
+
+    let mut segments: Vec<String> = Vec::new();
+    let mut trailing_slash = false;
+    for raw_segment in path[1..].split('/') {
+        let segment = percent_decode(raw_segment)
+            .ok_or_else(|| format!("Path {} contains an invalid percent-encoding.", target))?;
+        let segment = String::from_utf8(segment)
+            .map_err(|_| format!("Path {} is not valid utf-8.", target))?;
+        if segment.contains('\0') || segment.contains('/') {
+            return Err(format!("Path {} contains an encoded NUL or slash.", target));
+        }
+
+        trailing_slash = true;
+        match &segment[..] {
+            "" | "." => {}
+            ".." => {
+                segments.pop().ok_or_else(|| format!("Path {} leaves the root directory.", target))?;
+            }
+            _ => {
+                segments.push(segment);
+                trailing_slash = false;
+            }
+        }
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    if trailing_slash && !segments.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Reduces an absolute target as http://localhost:9000/hello.html to its path
+fn strip_authority(target: &str) -> &str {
+    let lowercase = target.to_ascii_lowercase();
+    let scheme_length = if lowercase.starts_with("http://") {
+        "http://".len()
+    } else if lowercase.starts_with("https://") {
+        "https://".len()
+    } else {
+        return target;
+    };
+    let rest = &target[scheme_length..];
+    match rest.find('/') {
+        Some(path_start) => &rest[path_start..],
+        None => "/"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decode_test() {
+        assert_eq!(percent_decode("/hello.html").unwrap(), b"/hello.html");
+        assert_eq!(percent_decode("/a%20b%2Fc").unwrap(), b"/a b/c");
+        assert_eq!(percent_decode("%e2%82%ac").unwrap(), "€".as_bytes());
+        assert_eq!(percent_decode("%00").unwrap(), b"\0");
+        assert_eq!(percent_decode("%"), None);
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%+1"), None);
+    }
+
+    #[test]
+    fn percent_encode_test() {
+        assert_eq!(percent_encode("/docs/"), "/docs/");
+        assert_eq!(percent_encode("/my file&<€>.html"), "/my%20file%26%3C%E2%82%AC%3E.html");
+        assert_eq!(percent_decode(&percent_encode("/a b/ü")).unwrap(), "/a b/ü".as_bytes());
+    }
+
+    #[test]
+    fn normalize_path_test() {
+        assert_eq!(normalize_path("/hello.html").unwrap(), "/hello.html");
+        assert_eq!(normalize_path("/").unwrap(), "/");
+        assert_eq!(normalize_path("/my%20file.html").unwrap(), "/my file.html");
+        assert_eq!(normalize_path("//hello.html").unwrap(), "/hello.html");
+        assert_eq!(normalize_path("/a//b///c.html").unwrap(), "/a/b/c.html");
+        assert_eq!(normalize_path("/a/../hello.html").unwrap(), "/hello.html");
+        assert_eq!(normalize_path("/a/./b/../c.html").unwrap(), "/a/c.html");
+        assert_eq!(normalize_path("/a/%2e%2E/hello.html").unwrap(), "/hello.html");
+        assert_eq!(normalize_path("/a/b/").unwrap(), "/a/b/");
+        assert_eq!(normalize_path("/a/b/.").unwrap(), "/a/b/");
+        assert_eq!(normalize_path("/a/..").unwrap(), "/");
+        assert_eq!(normalize_path("http://localhost:9000/a/../hello.html").unwrap(), "/hello.html");
+        assert_eq!(normalize_path("HTTPS://localhost").unwrap(), "/");
+        assert_eq!(normalize_path("*").unwrap(), "*");
+    }
+
+    #[test]
+    fn normalize_path_rejected_test() {
+        assert!(normalize_path("/..").is_err());
+        assert!(normalize_path("/a/../../hello.html").is_err());
+        assert!(normalize_path("/%2e%2e/hello.html").is_err());
+        assert!(normalize_path("/..%2Fhello.html").is_err());
+        assert!(normalize_path("/hello%00.html").is_err());
+        assert!(normalize_path("/hello%2").is_err());
+        assert!(normalize_path("/%ff").is_err());
+        assert!(normalize_path("hello.html").is_err());
+    }
+
+    #[test]
+    fn query_params_test() {
+        let params = QueryParams::parse("path=/hello.html&limit=50&path=%2Fhuge.html&flag&q=a+b%21");
+        assert_eq!(params.get("path"), Some("/hello.html"));
+        assert_eq!(params.get_all("path"), &["/hello.html".to_string(), "/huge.html".to_string()]);
+        assert_eq!(params.get("limit"), Some("50"));
+        assert_eq!(params.get("flag"), Some(""));
+        assert_eq!(params.get("q"), Some("a b!"));
+        assert_eq!(params.get("missing"), None);
+        assert!(params.get_all("missing").is_empty());
+    }
+
+    #[test]
+    fn query_params_malformed_test() {
+        let params = QueryParams::parse("a=100%&&=x;b=%zz");
+        assert_eq!(params.get("a"), Some("100%"));
+        assert_eq!(params.get(""), Some("x"));
+        assert_eq!(params.get("b"), Some("%zz"));
+        assert_eq!(QueryParams::parse(""), QueryParams::default());
+    }
+}
@@ -0,0 +1,88 @@
+//! Module watching the served directory and reloading the static files when it changes
+
+use std::fs;
+use std::io::Error as IoError;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use crate::file::{load_static_files, ServerFiles};
+
+/// The state of a file that changes whenever the file is written, moved or removed
+type FileState = (PathBuf, u64, Option<SystemTime>);
+
+/// Starts a thread checking the directory for changes in the interval
+///
+/// After a change all files are read again and swapped in at once, so requests never see a half reloaded directory.
+/// If the files can not be read, e.g. because one is still being written, the old ones are kept and reading is retried.
+pub fn start_watching(dir: PathBuf, interval: Duration, files: ServerFiles) {
+    thread::spawn(move || {
+        let mut known_state = directory_state(&dir).ok();
+        loop {
+            thread::sleep(interval);
+            let state = match directory_state(&dir) {
+                Ok(state) => state,
+                Err(error) => {
+                    println!("Could not watch {}: {}", dir.display(), error);
+                    continue;
+                }
+            };
+            if known_state.as_ref() == Some(&state) {
+                continue;
+            }
+            match load_static_files(&dir, files.compression) {
+                Ok(static_files) => {
+                    println!("Reloaded {} files from {}", static_files.len(), dir.display());
+                    files.replace_static_files(static_files);
+                    known_state = Some(state);
+                }
+                Err(error) => println!("Could not reload {}: {}", dir.display(), error)
+            }
+        }
+    });
+}
+
+/// The states of all files below the path, sorted by their path
+fn directory_state(path: &Path) -> Result<Vec<FileState>, IoError> {
+    let mut state = Vec::new();
+    collect_state(path, &mut state)?;
+    state.sort();
+    Ok(state)
+}
+
+/// Adds the states of the file or of all files in the directory recursively
+fn collect_state(path: &Path, state: &mut Vec<FileState>) -> Result<(), IoError> {
+    let metadata = fs::metadata(path)?;
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            collect_state(&entry?.path(), state)?;
+        }
+    } else {
+        state.push((path.to_path_buf(), metadata.len(), metadata.modified().ok()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directory_state_test() {
+        let dir = std::env::temp_dir().join(format!("webserver_watch_test_{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.html"), "a").unwrap();
+        fs::write(dir.join("sub/b.html"), "b").unwrap();
+
+        let state = directory_state(&dir).unwrap();
+        assert_eq!(state.iter().map(|(path, _, _)| path.clone()).collect::<Vec<_>>(), vec![dir.join("a.html"), dir.join("sub/b.html")]);
+        assert_eq!(directory_state(&dir).unwrap(), state);
+
+        fs::write(dir.join("sub/b.html"), "bb").unwrap();
+        assert_ne!(directory_state(&dir).unwrap(), state);
+        fs::remove_file(dir.join("a.html")).unwrap();
+        assert_eq!(directory_state(&dir).unwrap().len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(directory_state(&dir).is_err());
+    }
+}